@@ -2,11 +2,33 @@
 
 MAELSTROM="./maelstrom/maelstrom"
 BINARY="./target/debug/cas-paxos"
+BENCH_BINARY="./target/release/cas-paxos"
 
-if [ "$1" = "lin-kv" ]; then
+# maelstrom runs the binary without arguments, so wrap it in one passing --workload.
+with_workload() {
+  printf '#!/bin/sh\nexec %s --workload %s\n' "$(realpath $BINARY)" "$1" > "./target/$1-node"
+  chmod +x "./target/$1-node"
+  echo "./target/$1-node"
+}
+
+if [ "$1" = "echo" ]; then
+  cargo build && $MAELSTROM test -w echo --bin $BINARY --time-limit 10 --log-stderr --node-count 1
+elif [ "$1" = "unique-ids" ]; then
+  cargo build && $MAELSTROM test -w unique-ids --bin $BINARY --time-limit 30 --log-stderr --node-count 3 --rate 1000 --availability total --nemesis partition
+elif [ "$1" = "broadcast" ]; then
+  cargo build && $MAELSTROM test -w broadcast --bin "$(with_workload broadcast)" --time-limit 20 --log-stderr --node-count 5 --rate 10 --nemesis partition
+elif [ "$1" = "g-counter" ] || [ "$1" = "pn-counter" ]; then
+  cargo build && $MAELSTROM test -w $1 --bin "$(with_workload $1)" --time-limit 20 --log-stderr --node-count 3 --rate 100 --nemesis partition
+elif [ "$1" = "lin-kv" ]; then
   cargo build && $MAELSTROM test -w lin-kv --bin $BINARY --time-limit 35 --log-stderr --node-count 3 --concurrency 2n --rate 10 # --latency 120
+elif [ "$1" = "lin-kv-proxy" ]; then
+  cargo build && $MAELSTROM test -w lin-kv --bin "$(with_workload lin-kv-proxy)" --time-limit 35 --log-stderr --node-count 3 --concurrency 2n --rate 10
 elif [ "$1" = "lin-kv-nemesis" ]; then
   cargo build && $MAELSTROM test -w lin-kv --bin $BINARY --time-limit 15 --log-stderr --node-count 3 --concurrency 4n --rate 100 --nemesis partition --nemesis-interval 4 # --latency 120
+elif [ "$1" = "lin-kv-bench" ]; then
+  cargo build --profile bench --features bench && $MAELSTROM test -w lin-kv --bin $BENCH_BINARY --time-limit 35 --log-stderr --node-count 3 --concurrency 2n --rate 100
+elif [ "$1" = "simulate" ]; then
+  cargo build && $BINARY simulate "${@:2}"
 else
   echo "unknown command"
 fi
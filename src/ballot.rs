@@ -0,0 +1,37 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::node::NodeIndex;
+
+/// A ballot: the proposer's round, along with the proposer's NodeIndex to break ties.
+/// Ballots compare by round first and then by node, so ballots from different
+/// proposers never compare equal. They go over the wire as `[round, node]`.
+#[derive(
+    Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash,
+)]
+pub struct BallotNumber(pub u64, pub NodeIndex);
+
+impl BallotNumber {
+    /// Lower than every ballot a proposer claims, standing in for no ballot at all.
+    pub const ZERO: Self = Self(0, 0);
+
+    pub fn round(&self) -> u64 {
+        self.0
+    }
+
+    /// The ballot the proposer at `node` claims to outbid this one.
+    pub fn next(&self, node: NodeIndex) -> Self {
+        Self(self.round() + 1, node)
+    }
+
+    pub fn is_zero(&self) -> bool {
+        *self == Self::ZERO
+    }
+}
+
+impl fmt::Display for BallotNumber {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.0, self.1)
+    }
+}
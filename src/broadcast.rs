@@ -0,0 +1,38 @@
+//! Maelstrom's broadcast workload: every message broadcast to any node should end
+//! up read from every node. Messages spread by gossip along the topology Maelstrom
+//! hands out, with no consensus involved, see `CASPaxos::gossip`.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::Mutex,
+};
+
+#[derive(Debug, Default)]
+pub struct Broadcast {
+    messages: Mutex<BTreeSet<u64>>,
+    neighbors: Mutex<Vec<String>>,
+}
+
+impl Broadcast {
+    /// Keeps our neighbors in `topology`, which maps every node to its neighbors.
+    pub fn set_topology(&self, my_id: &str, topology: &HashMap<String, Vec<String>>) {
+        *self.neighbors.lock().unwrap() = topology.get(my_id).cloned().unwrap_or_default();
+    }
+
+    pub fn neighbors(&self) -> Vec<String> {
+        self.neighbors.lock().unwrap().clone()
+    }
+
+    /// Adds `messages`, returning the ones we didn't have, which are left to gossip.
+    pub fn add(&self, messages: impl IntoIterator<Item = u64>) -> Vec<u64> {
+        let mut ours = self.messages.lock().unwrap();
+        messages
+            .into_iter()
+            .filter(|message| ours.insert(*message))
+            .collect()
+    }
+
+    pub fn messages(&self) -> Vec<u64> {
+        self.messages.lock().unwrap().iter().copied().collect()
+    }
+}
@@ -1,474 +1,4496 @@
 use std::{
-    collections::HashSet,
+    collections::{BTreeMap, BTreeSet, HashMap, VecDeque},
+    fmt,
+    future::Future,
+    hash::{DefaultHasher, Hash, Hasher},
+    ops::{Deref, DerefMut},
     sync::{
         atomic::{AtomicUsize, Ordering},
-        Arc, Mutex,
+        Arc, OnceLock,
     },
+    time::Duration,
 };
 
+use futures::{future::BoxFuture, stream::FuturesUnordered, StreamExt};
+use rand::Rng;
+use serde_json::Value;
+use tokio::{sync::mpsc::error::SendError, time::Instant};
+
 use crate::{
+    ballot::BallotNumber,
+    broadcast::Broadcast,
+    changelog::Changelog,
+    config::{Config, DivergenceCheck, ReadMode, Workload},
+    counter::{self, COUNTER_KEY},
+    crdt::LwwMap,
+    expiry::{self, LocalClock},
+    key::Key,
     kv_store::KeyValueStore,
-    message::{Body, ErrorCode, Message},
-    node::Node,
+    logging, membership,
+    message::{Body, BodyWithMsgId, ErrorCode, Message, PauseMode},
+    node::{Node, NodeIndex, QuorumSizes},
+    profiling::{self, Stage},
+    rate_limit::ClientRateLimiter,
+    rng,
+    snapshot::Snapshot,
+    state_machine::{Register, StateMachine as _, REGISTER_KEY},
+    stats::{Health, MemoryUsage, Stats},
+    transport::Transport,
+    txn::{self, TxnOp, TxnOpKind},
 };
 
-type BallotNumber = usize;
-type NodeId = String;
-type StateMachine = KeyValueStore<usize, usize>;
-type PromisesInbox = Vec<(NodeId, BallotNumber, StateMachine)>;
-type AcceptanceInbox = HashSet<(NodeId, BallotNumber)>;
+// Rounds carry the state of an instance as a KV store of its entries, whatever the
+// workload: the txn, expiry and membership entries of a key are kept along with it.
+// A `StateMachine` only decides what client ops do to it, see `apply_to_state_machine`.
+type InstanceState = KeyValueStore<Key, Value>;
+
+// New client ops are shed with error 11 once either limit is exceeded, since they'd
+// otherwise wait past the point where the client gave up on them.
+const MAX_IN_FLIGHT_PROPOSALS: usize = 16;
+const MAX_INBOUND_QUEUE_DEPTH: usize = 24;
+// Right after a partition heals, every node has ops to retry, so the in-flight limit
+// starts at 1 and ramps up to MAX_IN_FLIGHT_PROPOSALS over this window instead of
+// letting all of them race for ballots at once.
+const SLOW_START_WINDOW: Duration = Duration::from_secs(2);
+
+// After winning a ballot, the node skips the Propose phase of its next rounds on the
+// key for this long. Acceptors enforce the ballots either way, so the lease only
+// bounds how long a node keeps trying the fast path after another proposer took over.
+// With lease reads, acceptors also promise no other proposer for this long after
+// an accept, which is what lets the lease holder read its own state.
+const LEASE_DURATION: Duration = Duration::from_millis(500);
+
+// How many msgs from a single peer can wait for that peer's dispatch loop.
+const PEER_QUEUE_CAPACITY: usize = 32;
+
+// Client ops are proposed in arrival order per key by running all the ops whose keys
+// share a lane through one loop. Keys are spread over a fixed number of lanes so the
+// number of loops stays bounded however many keys there are.
+const KEY_LANES: usize = 16;
+
+fn key_lane(key: &Key) -> usize {
+    key.number() % KEY_LANES
+}
+
+/// The key whose CASPaxos instance runs the round for `op`. Txn ops on several keys
+/// are split into one op per key before they're proposed.
+fn instance_key(op: &Body) -> Key {
+    let key = match op {
+        Body::TxnPrepare { txn, .. } => txn[0].key().clone(),
+        Body::TxnDecide { txn_id, .. } | Body::TxnForget { txn_id } => txn::decision_key(*txn_id),
+        Body::TxnFinish { keys, .. } => keys[0].clone(),
+        Body::AddNode { .. } | Body::RemoveNode { .. } => membership::MEMBERSHIP_KEY,
+        op => op.key().expect("only ops on keys are proposed").clone(),
+    };
+    txn::base_key(&key).clone()
+}
+
+/// Why client ops can't name `key`, if it holds a txn's entries or the members register.
+fn reserved_key(key: &Key) -> Option<&'static str> {
+    if key.is_tagged() {
+        Some("key is reserved for txns")
+    } else if *key == membership::MEMBERSHIP_KEY {
+        Some("key is reserved for the cluster's members")
+    } else {
+        None
+    }
+}
+
+// Client ops past either limit are rejected with error 11 right away, rather than
+// queued until the client has long stopped waiting for them.
+const MAX_QUEUED_PER_KEY_LANE: usize = 32;
+const MAX_QUEUED_CLIENT_OPS: usize = 128;
+
+/// The loop proposing the client ops on one lane's keys, and how many ops wait for it.
+struct KeyLane {
+    tx: tokio::sync::mpsc::Sender<Message>,
+    queued: Arc<AtomicUsize>,
+}
+
+// How often peers are sent a heartbeat, well within the silence after which a peer
+// is considered unreachable.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(250);
+
+// How often a node compares its accepted ballots with a random peer's, and pulls the
+// state of the keys the peer accepted at greater ballots -- e.g. after missing
+// Accept msgs during a partition.
+const SYNC_INTERVAL: Duration = Duration::from_secs(1);
+
+// How often a node looks for txns that hold locks in its group, and how long a txn
+// may hold them before it's considered in doubt and gets finished by recovery.
+const TXN_RECOVERY_INTERVAL: Duration = Duration::from_secs(1);
+const TXN_IN_DOUBT_AFTER: Duration = Duration::from_secs(3);
+
+// How often a node looks for tombstones older than --compact-after-ms to drop.
+const COMPACTION_INTERVAL: Duration = Duration::from_secs(1);
+
+// Maelstrom's linearizable KV service, which client ops go to with --workload lin-kv-proxy.
+const LIN_KV_SERVICE: &str = "lin-kv";
+
+// A node paused in buffering mode drops whatever comes in past this many msgs.
+const MAX_BUFFERED_WHILE_PAUSED: usize = 10_000;
+
+// One bit per node, so the cluster can't be larger than the bitmap.
+const MAX_NODES: usize = u64::BITS as usize;
+
+/// Nodes that accepted the latest ballot we've heard of, as a bitmap indexed
+/// by each node's position in the cluster's node list.
+#[derive(Clone, Copy, Debug, Default)]
+struct AcceptanceInbox {
+    ballot_number: BallotNumber,
+    accepted_by: u64,
+}
+
+impl AcceptanceInbox {
+    /// Counts `node_index` as having accepted `ballot_number`. Returns false when
+    /// that changed nothing, i.e. for duplicates and for ballots older than ours.
+    fn insert(&mut self, node_index: NodeIndex, ballot_number: BallotNumber) -> bool {
+        debug_assert!((node_index as usize) < MAX_NODES);
+        let before = (self.ballot_number, self.accepted_by);
+        // a newer ballot invalidates every acceptance of the older one,
+        // while an older ballot contributes nothing.
+        let is_newer = (ballot_number > self.ballot_number) as u64;
+        let is_current = (ballot_number >= self.ballot_number) as u64;
+        self.accepted_by &= is_newer.wrapping_sub(1);
+        self.accepted_by |= is_current << node_index;
+        self.ballot_number = self.ballot_number.max(ballot_number);
+        (self.ballot_number, self.accepted_by) != before
+    }
+
+    fn len(&self) -> usize {
+        self.accepted_by.count_ones() as usize
+    }
+
+    fn members(&self) -> impl Iterator<Item = NodeIndex> + '_ {
+        (0..MAX_NODES as NodeIndex).filter(|index| self.accepted_by & (1 << index) != 0)
+    }
+}
+
+// Txn ids pack the coordinator's counter in the high bits and its NodeIndex in the low bits.
+const NODE_INDEX_BITS: u32 = NodeIndex::BITS;
+
+/// Promises of the current round. Only the promise the round builds on is kept,
+/// i.e. the one whose value was accepted at the highest ballot_number (node index
+/// breaking ties). Senders are kept as a bitmap like in `AcceptanceInbox`, so that
+/// a retransmitted promise doesn't count twice.
+#[derive(Clone, Debug, Default)]
+struct PromisesInbox {
+    promised_by: u64,
+    highest: Option<Box<(NodeIndex, BallotNumber, InstanceState)>>,
+    agreeing: usize, // promises whose value was accepted at the highest ballot_number
+}
+
+impl PromisesInbox {
+    fn insert(
+        &mut self,
+        node_index: NodeIndex,
+        accepted_ballot_number: BallotNumber,
+        state: InstanceState,
+    ) {
+        debug_assert!((node_index as usize) < MAX_NODES);
+        let is_duplicate = self.promised_by & (1 << node_index) != 0;
+        if is_duplicate {
+            return;
+        }
+        self.promised_by |= 1 << node_index;
+        let is_highest = self
+            .highest()
+            .is_none_or(|(highest_index, highest_ballot_number, _)| {
+                (accepted_ballot_number, node_index) > (*highest_ballot_number, *highest_index)
+            });
+        let agrees = self.highest().is_some_and(|(_, highest_ballot_number, _)| {
+            accepted_ballot_number == *highest_ballot_number
+        });
+        if agrees {
+            self.agreeing += 1;
+        } else if is_highest {
+            self.agreeing = 1;
+        }
+        if is_highest {
+            self.highest = Some(Box::new((node_index, accepted_ballot_number, state)));
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.promised_by.count_ones() as usize
+    }
+
+    fn highest(&self) -> Option<&(NodeIndex, BallotNumber, InstanceState)> {
+        self.highest.as_deref()
+    }
+
+    /// Whether the highest value was accepted by an accept quorum, i.e. was chosen.
+    /// Every later round then builds on it, so reading it needs no Accept phase.
+    fn highest_is_chosen(&self, accept_quorum: usize) -> bool {
+        self.agreeing >= accept_quorum
+    }
+}
+
+/// Where the final reply to a proposed op goes. It's taken from the client's msg when
+/// the proposal starts and travels with it, rather than being rebuilt from whatever
+/// msg happens to be in hand once the op is decided.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct ClientEnvelope {
+    src: String,
+    msg_id: usize,
+}
+
+impl ClientEnvelope {
+    fn of(msg: &Message) -> Self {
+        Self {
+            src: msg.src.clone(),
+            msg_id: msg.body.msg_id,
+        }
+    }
+}
+
+/// A client request awaiting its reply, in the table of those keyed by their client.
+#[derive(Debug, Default)]
+struct InFlightProposal {
+    response: Option<Body>, // set along with our Accept msgs, sent once they're accepted
+}
+
+/// What a rejected round needs to propose its ops again.
+#[derive(Debug)]
+struct Retry {
+    op: Box<Message>,
+    client: ClientEnvelope,
+    batched: Vec<(Message, ClientEnvelope)>,
+    attempt: u32, // how many rounds were rejected before this one
+}
+
+// Ops queued on an instance while a round runs are proposed together in its next
+// round, up to this many. Each is applied in turn, and answered once the round is.
+// The batch shrinks with the inbound queue, see `batch_limit`.
+const MAX_BATCH_SIZE: usize = 64;
+
+/// How many queued ops the next round of an instance takes along besides its own,
+/// given the inbound queue's load: as many as MAX_BATCH_SIZE allows when it's full,
+/// fewer as it drains, and none when it's empty, where a round per op keeps up and a
+/// rejected round only sets back its own op.
+fn batch_limit(inbound_load: f64) -> usize {
+    ((MAX_BATCH_SIZE - 1) as f64 * inbound_load.clamp(0.0, 1.0)).ceil() as usize
+}
 
 #[derive(Clone, Debug)]
 enum Role {
     Proposer {
-        op: Message,
+        op: Box<Message>,
+        client: ClientEnvelope,
+        batched: Vec<(Message, ClientEnvelope)>, // ops applied after `op`, in the same round
+        ballot_number: BallotNumber, // ballot_number of the round we're currently running
         last_accept_broadcast: BallotNumber, // ballot_number of last broadcast of Accept msgs
         last_client_confirmation: BallotNumber, // ballot_number at last msg we confirmed to client
         promises_inbox: PromisesInbox,
         acceptance_inbox: AcceptanceInbox,
-        pending_client_repsonse_body: Option<Body>,
     },
     Acceptor,
 }
 
 impl Role {
-    fn add_promise_to_inbox(
-        &mut self,
-        node_id: &str,
-        ballot_number: BallotNumber,
-        state_machine: StateMachine,
-    ) {
+    fn name(&self) -> &'static str {
         match self {
-            Role::Acceptor => panic!("got called on an Acceptor instead of a Proposer"),
-            Role::Proposer {
-                ref mut promises_inbox,
-                ..
-            } => {
-                promises_inbox.push((node_id.to_string(), ballot_number, state_machine));
-            }
+            Role::Proposer { .. } => "proposer",
+            Role::Acceptor => "acceptor",
         }
     }
 
-    fn promises_inbox(&self) -> PromisesInbox {
+    fn ballot_number(&self) -> Option<BallotNumber> {
         match self {
-            Role::Acceptor => panic!("got called on an Acceptor instead of a Proposer"),
-            Role::Proposer {
-                ref promises_inbox, ..
-            } => promises_inbox.clone(),
+            Role::Proposer { ballot_number, .. } => Some(*ballot_number),
+            Role::Acceptor => None,
         }
     }
 
-    fn add_acceptance_to_inbox(&mut self, node_id: &str, ballot_number: BallotNumber) {
+    /// Whether a promise for `ballot_number` comes after our round had its majority
+    /// of promises and broadcast Accept, leaving the promise nothing to contribute.
+    fn is_late_promise(&self, ballot_number: BallotNumber) -> bool {
         match self {
-            Role::Acceptor => panic!("got called on an Acceptor instead of a Proposer"),
             Role::Proposer {
-                ref mut acceptance_inbox,
+                ballot_number: active_ballot_number,
+                last_accept_broadcast,
                 ..
-            } => {
-                acceptance_inbox.insert((node_id.to_string(), ballot_number));
-            }
+            } => ballot_number == *active_ballot_number && *last_accept_broadcast >= ballot_number,
+            Role::Acceptor => false,
         }
     }
 
-    fn acceptance_inbox(&self) -> AcceptanceInbox {
-        match self {
-            Role::Acceptor => panic!("got called on an Acceptor instead of a Proposer"),
-            Role::Proposer {
-                ref acceptance_inbox,
-                ..
-            } => acceptance_inbox.clone(),
+    /// Counts the promise for the current round, if it's for that round. Returns how
+    /// many promises the round has so far.
+    fn add_promise_to_inbox(
+        &mut self,
+        node_index: NodeIndex,
+        ballot_number: BallotNumber,
+        accepted_ballot_number: BallotNumber,
+        state_machine: InstanceState,
+    ) -> Result<usize, NotProposing> {
+        let Role::Proposer {
+            ballot_number: active_ballot_number,
+            ref mut promises_inbox,
+            ..
+        } = self
+        else {
+            return Err(NotProposing);
+        };
+        // promises for other rounds can't count towards the current one.
+        if ballot_number == *active_ballot_number {
+            promises_inbox.insert(node_index, accepted_ballot_number, state_machine);
         }
+        Ok(promises_inbox.len())
     }
 
-    fn set_last_accept_broadcast(&mut self, ballot_number: usize) {
+    fn promises_inbox(&self) -> Option<&PromisesInbox> {
         match self {
-            Role::Acceptor => panic!("got called on an Acceptor instead of a Proposer"),
             Role::Proposer {
-                ref mut last_accept_broadcast,
-                ..
-            } => *last_accept_broadcast = ballot_number,
+                ref promises_inbox, ..
+            } => Some(promises_inbox),
+            Role::Acceptor => None,
         }
     }
 
-    fn set_last_client_confirmation(&mut self, ballot_number: usize) {
-        match self {
-            Role::Acceptor => panic!("got called on an Acceptor instead of a Proposer"),
-            Role::Proposer {
-                ref mut last_client_confirmation,
-                ..
-            } => *last_client_confirmation = ballot_number,
+    /// Returns false when the acceptance was already counted or isn't for the current round.
+    fn add_acceptance_to_inbox(
+        &mut self,
+        node_index: NodeIndex,
+        ballot_number: BallotNumber,
+    ) -> Result<bool, NotProposing> {
+        let Role::Proposer {
+            ballot_number: active_ballot_number,
+            ref mut acceptance_inbox,
+            ..
+        } = self
+        else {
+            return Err(NotProposing);
+        };
+        // acceptances of other rounds say nothing about the current one.
+        if ballot_number != *active_ballot_number {
+            return Ok(false);
         }
+        Ok(acceptance_inbox.insert(node_index, ballot_number))
     }
 
-    fn set_pending_client_response_body(&mut self, body: Body) {
+    fn acceptance_inbox(&self) -> Option<AcceptanceInbox> {
         match self {
-            Role::Acceptor => panic!("got called on an Acceptor instead of a Proposer"),
             Role::Proposer {
-                ref mut pending_client_repsonse_body,
-                ..
-            } => *pending_client_repsonse_body = Some(body),
+                acceptance_inbox, ..
+            } => Some(*acceptance_inbox),
+            Role::Acceptor => None,
         }
     }
+
+    fn set_last_accept_broadcast(
+        &mut self,
+        ballot_number: BallotNumber,
+    ) -> Result<(), NotProposing> {
+        let Role::Proposer {
+            ref mut last_accept_broadcast,
+            ..
+        } = self
+        else {
+            return Err(NotProposing);
+        };
+        *last_accept_broadcast = ballot_number;
+        Ok(())
+    }
+
+    fn set_last_client_confirmation(
+        &mut self,
+        ballot_number: BallotNumber,
+    ) -> Result<(), NotProposing> {
+        let Role::Proposer {
+            ref mut last_client_confirmation,
+            ..
+        } = self
+        else {
+            return Err(NotProposing);
+        };
+        *last_client_confirmation = ballot_number;
+        Ok(())
+    }
 }
 
-// NOTE Here, we store the entire key-value store in a single CASPaxos instance.
-//      A non-toy implementatation would instead store the kv store as a set of
-//      independent, labelled CASPaxos instances (where each instance label
-//      corresponds to a key in the kv store. See section '2.3.3 Optimization'
-//      in the CASPaxos paper.
-// TODO Implement the optimization above.
-pub struct CASPaxos {
-    node: Arc<Node>,
-    state_machine: Mutex<KeyValueStore<usize, usize>>,
-    role: Mutex<Role>,
-    highest_known_ballot_number: AtomicUsize,
+/// Where a round stands after a promise was counted towards it.
+enum AfterPromise {
+    Waiting,
+    // a prepare quorum promised, and we accepted the state the round sends in its
+    // Accept msgs, along with the replies to its clients if that already decided it.
+    Accepted(InstanceState, Vec<(Body, ClientEnvelope)>),
+    // a read found its value chosen, and this is its client's reply.
+    Read(ClientEnvelope, Body),
 }
 
-impl CASPaxos {
-    pub fn new() -> Self {
+/// The error of the Role methods that only make sense while we run a round, once
+/// the round is over: whatever msg led to the call came in too late for it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct NotProposing;
+
+/// The CASPaxos instance of one key: the ballots it has seen, and our role in it.
+#[derive(Debug)]
+struct Instance {
+    promised: BallotNumber,                 // highest ballot seen for the key
+    accepted: BallotNumber, // ballot the key's state was accepted at, ZERO if it never was
+    value_digest: u64,      // of the key's state as of the accepted ballot
+    sent_accept: BallotNumber, // ballot of our last broadcast of Accept msgs for the key
+    sent_accept_at: Option<Instant>, // when that broadcast went out
+    lease: Option<(BallotNumber, Instant)>, // last ballot we won, and until when we build on it
+    // proposer whose Accept we last took, and until when no one else gets our promise
+    // (lease reads only)
+    granted_lease: Option<(NodeIndex, Instant)>,
+    role: Role,
+    // the client whose op our rounds are for, until it gets its reply. Ops arriving
+    // meanwhile wait in `queued`, so that each gets complete rounds of its own.
+    running_for: Option<ClientEnvelope>,
+    queued: VecDeque<(Message, ClientEnvelope)>,
+}
+
+impl Default for Instance {
+    fn default() -> Self {
         Self {
-            node: Arc::new(Node::new()),
-            state_machine: Mutex::new(KeyValueStore::default()),
-            role: Mutex::new(Role::Acceptor),
-            highest_known_ballot_number: AtomicUsize::new(0),
+            promised: BallotNumber::ZERO,
+            accepted: BallotNumber::ZERO,
+            value_digest: 0,
+            sent_accept: BallotNumber::ZERO,
+            sent_accept_at: None,
+            lease: None,
+            granted_lease: None,
+            role: Role::Acceptor,
+            running_for: None,
+            queued: VecDeque::new(),
         }
     }
+}
 
-    pub async fn run(self: Arc<Self>) {
-        let mut rx = self.node.clone().run().await;
+impl Instance {
+    /// Raises the highest ballot seen to `ballot_number`, unless a greater one was
+    /// seen already -- in which case that greater ballot is returned as the error.
+    /// An equal ballot passes: ballots carry the index of their proposer, so an equal
+    /// one can only be a msg of the same round, from the proposer we promised to.
+    fn observe(&mut self, ballot_number: BallotNumber) -> Result<(), BallotNumber> {
+        if ballot_number < self.promised {
+            return Err(self.promised);
+        }
+        self.promised = ballot_number;
+        Ok(())
+    }
 
-        loop {
-            tokio::select! {
-                Some(msg) = rx.recv() => {
-                    tokio::spawn({
-                        let cas_paxos = self.clone();
-                        async move { cas_paxos.handle(msg).await }
-                    });
-                }
-            };
+    /// Whether the next round can skip its Propose phase: no greater ballot was seen
+    /// since the one we won, so acceptors still hold the promise they made with it.
+    fn holds_lease(&self) -> bool {
+        self.lease
+            .is_some_and(|(won, expires_at)| won == self.promised && Instant::now() < expires_at)
+    }
+
+    /// The proposer we granted a lease to that's still running, if any.
+    fn leased_to(&self) -> Option<NodeIndex> {
+        self.granted_lease
+            .filter(|(_, expires_at)| Instant::now() < *expires_at)
+            .map(|(holder, _)| holder)
+    }
+
+    /// Claims, for the proposer at `node_index`, a ballot greater than any seen one.
+    fn next_ballot(&mut self, node_index: NodeIndex) -> BallotNumber {
+        self.promised = self.promised.next(node_index);
+        self.promised
+    }
+}
+
+/// Everything rounds decide on or wait for: every key's CASPaxos instance, the state
+/// machine holding their values, and the client ops they run. Only the consensus task
+/// touches it, see `ConsensusTask`. Along with them, it keeps digests of the ballots
+/// the keys were accepted at and of their accepted states, updated by the same
+/// command as the accept, so that they always describe the same accepts. It also
+/// keeps the changelog of the values our rounds chose.
+struct Consensus {
+    instances: HashMap<Key, Instance>,
+    ballots_digest: u64,
+    state_digest: u64,
+    state_machine: KeyValueStore<Key, Value>,
+    last_ballot_winner: Option<NodeIndex>, // whose Accept we last took, client ops are proxied to it
+    in_flight_proposals: HashMap<ClientEnvelope, InFlightProposal>,
+    // rounds we run for ourselves, with whoever waits for their result
+    local_rounds: HashMap<ClientEnvelope, tokio::sync::oneshot::Sender<Body>>,
+    // writes made in CRDT fallback mode that aren't in the linearizable store yet
+    lww_overlay: LwwMap,
+    changelog: Changelog,
+}
+
+impl Consensus {
+    fn new(changelog: Changelog) -> Self {
+        Self {
+            instances: HashMap::new(),
+            ballots_digest: 0,
+            state_digest: 0,
+            state_machine: KeyValueStore::default(),
+            last_ballot_winner: None,
+            in_flight_proposals: HashMap::new(),
+            local_rounds: HashMap::new(),
+            lww_overlay: LwwMap::default(),
+            changelog,
         }
     }
 
-    async fn handle(self: Arc<Self>, msg: Message) {
-        match msg.body.inner.clone() {
-            Body::Init { .. } => {
-                // NOTE: By the time we receive this Init, its content was already used by
-                //       self.node to store the node ids provided by the msg.
-                //       So all we have to do here is to respond with InitOk.
-                let _ = self
-                    .node
-                    .clone()
-                    .send(
-                        &msg.src,
-                        Body::InitOk {
-                            in_reply_to: msg.body.msg_id,
-                        },
-                        None,
-                    )
-                    .await;
-            }
-            Body::Read { .. } | Body::Write { .. } | Body::Cas { .. } => {
-                self.clone().propose(msg).await;
-            }
-            Body::Proxy { proxied_msg } => todo!(),
-            Body::Propose { ballot_number } => {
-                self.promise(&msg.src, msg.body.msg_id, ballot_number).await;
-            }
-            Body::Promise {
-                ballot_number,
-                value,
-            } => {
-                self.handle_promise_msg(&msg.src, msg.body.msg_id, ballot_number, value)
-                    .await;
-            }
-            Body::Accept {
-                ballot_number,
-                value,
-            } => {
-                self.accept(&msg.src, msg.body.msg_id, ballot_number, value)
-                    .await;
-            }
-            Body::Accepted { ballot_number } => {
-                self.handle_accepted_msg(&msg.src, msg.body.msg_id, ballot_number)
-                    .await;
-            }
-            Body::Error {
-                in_reply_to,
-                code,
-                text,
-            } => eprintln!("GOT AN ERROR - TODO"),
-            Body::InitOk { .. }
-            | Body::ReadOk { .. }
-            | Body::WriteOk { .. }
-            | Body::CasOk { .. } => panic!("i shouldn't receive this ack msg"),
+    /// The instance of `key`, creating it if it doesn't exist yet.
+    fn instance(&mut self, key: &Key) -> InstanceEntry<'_> {
+        self.instances.entry(key.clone()).or_default();
+        InstanceEntry {
+            consensus: self,
+            key: key.clone(),
         }
     }
 
-    async fn handle_accepted_msg(
-        self: Arc<Self>,
-        src: &str,
-        src_msg_id: usize,
-        ballot_number: usize,
-    ) {
-        tracing::debug!("called handle_accepted_msg on ballot_number {ballot_number}");
-        let mut ballot_number_was_rejected = false;
-        let mut should_reply_to_client = false;
-        let mut client = String::new();
-        let mut body: Option<Body> = None;
-        {
-            let mut role_guard = self.role.lock().unwrap();
-            match &*role_guard {
-                Role::Acceptor => tracing::debug!("RECEVED ACCEPT WHILE I WAS AN ACCEPTOR"),
-                Role::Proposer {
-                    op,
-                    last_client_confirmation,
-                    pending_client_repsonse_body,
-                    ..
-                } => {
-                    // we only want to confirm msgs accepted during the current CASPaxos round.
-                    if self.highest_known_ballot_number.load(Ordering::SeqCst) > ballot_number {
-                        tracing::debug!("recv accept: decided to reject ballot number");
-                        ballot_number_was_rejected = true;
-                    } else {
-                        tracing::debug!("ROLE GUARD IN ACCEPT 231: {:?}", *role_guard);
-                        let last_client_confirmation = *last_client_confirmation;
-                        let pending_body = pending_client_repsonse_body.clone();
-                        client = op.src.clone();
-                        role_guard.add_acceptance_to_inbox(src, ballot_number);
-
-                        let majority_is_reached_for_the_first_time =
-                            role_guard.acceptance_inbox().len() >= self.majority_count()
-                                && last_client_confirmation < ballot_number;
-                        if majority_is_reached_for_the_first_time {
-                            role_guard.set_last_client_confirmation(ballot_number);
-                            should_reply_to_client = true;
-
-                            body = pending_body;
-                        }
-                    }
-                }
-            };
-        } // role_guard dropped
+    /// The entries of the state machine that make up `key`'s instance.
+    fn instance_state(&self, key: &Key) -> InstanceState {
+        txn::instance_keys(key)
+            .into_iter()
+            .filter_map(|key| {
+                let entry = self.state_machine.entry(&key).cloned()?;
+                Some((key, entry))
+            })
+            .collect()
+    }
 
-        if ballot_number_was_rejected {
-            self.send_reject_ballot_number(src, src_msg_id).await;
-            return;
+    /// Replaces the entries of `key`'s instance with those of `state`.
+    fn replace_instance_state(&mut self, key: &Key, state: &InstanceState) {
+        for key in txn::instance_keys(key) {
+            match state.entry(&key) {
+                Some(entry) => self.state_machine.put(key, entry.clone()),
+                None => {
+                    self.state_machine.remove(&key);
+                }
+            }
         }
+    }
+
+    /// What `key` reads as from the overlay, or else from the state machine.
+    fn current(&self, key: &Key) -> Option<Value> {
+        self.lww_overlay
+            .get(key)
+            .or_else(|| self.state_machine.read(key))
+            .cloned()
+    }
 
-        if should_reply_to_client {
-            self.node.clone().send(&client, body.unwrap(), None).await;
+    /// Writes the overlay's register for `key` on top of the key's state, ahead of an
+    /// Accept broadcast.
+    fn fold_overlay(&self, key: &Key, state: &mut InstanceState) {
+        if let Some(value) = self.lww_overlay.get(key) {
+            state.write(key.clone(), value.clone());
         }
     }
 
-    async fn promise(self: Arc<Self>, src: &str, src_msg_id: usize, ballot_number: usize) {
-        tracing::debug!("called promise() on ballot_number {ballot_number}");
-        *self.role.lock().unwrap() = Role::Acceptor;
+    /// Drops the overlay's registers that the state machine now holds.
+    fn settle_overlay(&mut self) {
+        let state_machine = &self.state_machine;
+        self.lww_overlay
+            .retain_unsettled(|key, value| state_machine.read(key) == Some(value));
+    }
 
-       if self.highest_known_ballot_number.load(Ordering::SeqCst) > ballot_number {
-            self.clone()
-                .send_reject_ballot_number(src, src_msg_id)
-                .await;
-            return;
+    /// The reply of `client`'s op, once a round computed it.
+    fn take_response(&mut self, client: &ClientEnvelope) -> Option<Body> {
+        self.in_flight_proposals.get_mut(client)?.response.take()
+    }
+}
+
+fn accepted_ballot_digest(key: &Key, ballot_number: BallotNumber) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    (key, ballot_number).hash(&mut hasher);
+    hasher.finish()
+}
+
+/// An instance, as a command sees it, along with the rest of the consensus state.
+struct InstanceEntry<'a> {
+    consensus: &'a mut Consensus,
+    key: Key,
+}
+
+impl InstanceEntry<'_> {
+    /// Records that the key's state, digested as `value_digest`, was accepted at `ballot_number`.
+    fn set_accepted(&mut self, ballot_number: BallotNumber, value_digest: u64) {
+        let key = &self.key;
+        let consensus = &mut *self.consensus;
+        let instance = consensus.instances.get_mut(key).unwrap();
+        if !instance.accepted.is_zero() {
+            consensus.ballots_digest = consensus
+                .ballots_digest
+                .wrapping_sub(accepted_ballot_digest(key, instance.accepted));
+            consensus.state_digest = consensus.state_digest.wrapping_sub(instance.value_digest);
         }
+        instance.accepted = ballot_number;
+        instance.value_digest = value_digest;
+        consensus.ballots_digest = consensus
+            .ballots_digest
+            .wrapping_add(accepted_ballot_digest(key, ballot_number));
+        consensus.state_digest = consensus.state_digest.wrapping_add(value_digest);
+    }
+}
 
-        self.highest_known_ballot_number
-            .store(ballot_number, Ordering::SeqCst);
+impl Deref for InstanceEntry<'_> {
+    type Target = Instance;
 
-        let body = Body::Promise {
-            ballot_number,
-            value: self.state_machine.lock().unwrap().clone(),
-        };
+    fn deref(&self) -> &Instance {
+        &self.consensus.instances[&self.key]
+    }
+}
 
-        self.node.clone().send(src, body, None).await;
+impl DerefMut for InstanceEntry<'_> {
+    fn deref_mut(&mut self) -> &mut Instance {
+        self.consensus.instances.get_mut(&self.key).unwrap()
     }
+}
 
-    async fn handle_promise_msg(
-        self: Arc<Self>,
-        src: &str,
-        src_msg_id: usize,
-        ballot_number: usize,
-        value: KeyValueStore<usize, usize>,
-    ) {
-        tracing::debug!("called handle_promise_msg() on ballot_number {ballot_number}");
-        let mut ballot_number_was_rejected = false;
-        let mut should_broadcast_accept = false;
-        {
-            let mut role_guard = self.role.lock().unwrap();
-            match &*role_guard {
-                Role::Acceptor => (),
-                Role::Proposer {
-                    last_accept_broadcast,
-                    op,
-                    ..
-                } => {
-                    if self.highest_known_ballot_number.load(Ordering::SeqCst) > ballot_number {
-                        ballot_number_was_rejected = true;
-                    } else {
-                        let last_accept_broadcast = *last_accept_broadcast;
-                        let op = op.clone();
-                        role_guard.add_promise_to_inbox(src, ballot_number, value);
-
-                        let majority_is_reached_for_the_first_time =
-                            role_guard.promises_inbox().len() >= self.majority_count()
-                                && last_accept_broadcast < ballot_number;
-                        if majority_is_reached_for_the_first_time {
-                            role_guard.set_last_accept_broadcast(ballot_number);
-                            should_broadcast_accept = true;
-
-                            let mut promises = role_guard.promises_inbox();
-                            // desc. sort by ballot_number, then node id as a tie breaker.
-                            promises.sort_by(|b, a| a.1.cmp(&b.1).then_with(|| a.0.cmp(&b.0)));
-
-                            let (_, _, mut state) = promises.first().unwrap().clone();
-                            let body = self.clone().apply_to_state_machine(&op, &mut state);
-
-                            *self.state_machine.lock().unwrap() = state;
-                            role_guard.set_pending_client_response_body(body);
-                        }
-                    }
-                }
-            };
-        } // role_guard dropped
+/// What the consensus task runs against the consensus state, one command at a time.
+type Command = Box<dyn FnOnce(&mut Consensus) + Send>;
 
-        if ballot_number_was_rejected {
-            self.send_reject_ballot_number(src, src_msg_id).await;
-            return;
-        }
+// How many commands can wait for the consensus task.
+const COMMAND_QUEUE_CAPACITY: usize = 1024;
 
-        if should_broadcast_accept {
-            let body = Body::Accept {
-                ballot_number,
-                value: self.state_machine.lock().unwrap().clone(),
-            };
-            self.node.clone().broadcast(body, None).await;
-        }
+/// The consensus task ended, so there's no consensus state left to run commands
+/// against. It only does if a command panicked in it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConsensusGone;
+
+impl fmt::Display for ConsensusGone {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("the consensus task is gone")
     }
+}
 
-    async fn accept(
-        self: Arc<Self>,
-        src: &str,
-        src_msg_id: usize,
-        ballot_number: usize,
-        value: KeyValueStore<usize, usize>,
-    ) {
-        tracing::debug!("called accept() on ballot_number {ballot_number}");
-        let role = self.role.lock().unwrap().clone();
-        match role {
-            Role::Proposer { .. } => (),
-            Role::Acceptor => {
-                if self.highest_known_ballot_number.load(Ordering::SeqCst) > ballot_number {
-                    self.clone()
-                        .send_reject_ballot_number(src, src_msg_id)
-                        .await;
-                    return;
-                }
+impl std::error::Error for ConsensusGone {}
 
-                *self.state_machine.lock().unwrap() = value;
+/// The task owning the `Consensus` state. It runs the commands sent to it in order,
+/// so that they never overlap, and no lock guards the state.
+struct ConsensusTask {
+    commands: tokio::sync::mpsc::Sender<Command>,
+}
 
-                self.node
-                    .clone()
-                    .send(src, Body::Accepted { ballot_number }, None)
-                    .await;
+impl ConsensusTask {
+    fn spawn(mut consensus: Consensus) -> Self {
+        let (commands, mut rx) = tokio::sync::mpsc::channel::<Command>(COMMAND_QUEUE_CAPACITY);
+        tokio::spawn(async move {
+            while let Some(command) = rx.recv().await {
+                command(&mut consensus);
             }
-        }
+        });
+        Self { commands }
     }
 
-    async fn propose(self: Arc<Self>, op: Message) {
-        {
-            let mut role_guard = self.role.lock().unwrap();
-            let (last_accept_broadcast, last_client_confirmation) = match *role_guard {
-                Role::Proposer {
-                    last_accept_broadcast,
-                    last_client_confirmation,
-                    ..
-                } => (last_accept_broadcast, last_client_confirmation),
-                Role::Acceptor => (0, 0),
-            };
+    /// Runs `f` on the consensus state, in the consensus task, and returns what it returns.
+    async fn run<R: Send + 'static>(
+        &self,
+        f: impl FnOnce(&mut Consensus) -> R + Send + 'static,
+    ) -> Result<R, ConsensusGone> {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let sent_at = std::time::Instant::now();
+        let command: Command = Box::new(move |consensus| {
+            profiling::record_since(Stage::Lock, sent_at);
+            let _ = tx.send(f(consensus));
+        });
+        self.commands
+            .send(command)
+            .await
+            .map_err(|_| ConsensusGone)?;
+        // the reply is only dropped unsent if `f` panicked, which ends the task.
+        rx.await.map_err(|_| ConsensusGone)
+    }
 
-            *role_guard = Role::Proposer {
-                op,
-                last_accept_broadcast,
-                promises_inbox: Vec::new(),
-                pending_client_repsonse_body: None,
-                acceptance_inbox: HashSet::new(),
-                last_client_confirmation,
-            };
-        }
+    /// Runs `f` on the instance of `key`, creating it if it doesn't exist yet.
+    async fn with<R: Send + 'static>(
+        &self,
+        key: &Key,
+        f: impl FnOnce(&mut InstanceEntry) -> R + Send + 'static,
+    ) -> Result<R, ConsensusGone> {
+        let key = key.clone();
+        self.run(move |consensus| f(&mut consensus.instance(&key)))
+            .await
+    }
 
-        self.highest_known_ballot_number
-            .fetch_add(1, Ordering::SeqCst);
-        let ballot_number = self.highest_known_ballot_number.load(Ordering::SeqCst);
-        let body = Body::Propose { ballot_number };
+    /// The digests of the ballots every key was accepted at, and of their states.
+    async fn digests(&self) -> Result<(u64, u64), ConsensusGone> {
+        self.run(|consensus| (consensus.ballots_digest, consensus.state_digest))
+            .await
+    }
 
-        self.node.clone().broadcast(body, None).await;
+    /// What `f` makes of each instance it makes something of.
+    async fn filter_map<T: Send + 'static>(
+        &self,
+        f: impl Fn(&Key, &Instance) -> Option<T> + Send + 'static,
+    ) -> Result<Vec<T>, ConsensusGone> {
+        self.run(move |consensus| {
+            consensus
+                .instances
+                .iter()
+                .filter_map(|(key, instance)| f(key, instance))
+                .collect()
+        })
+        .await
     }
+}
 
-    // TODO we should track the source of the highest known ballot number, since we might need to use
-    //      node ids for tie breakers in case the incoming ballot number matches the number we've seen before.
-    async fn send_reject_ballot_number(self: Arc<Self>, dest: &str, in_reply_to: usize) {
-        let body = Body::Error {
-            in_reply_to,
-            code: ErrorCode::PreconditionFailed,
-            text: String::from("exepcted a greater ballot number"),
-        };
+/// Spawns `task`, logging it if it ends as the consensus task is gone.
+fn spawn_logged(task: impl Future<Output = Result<(), ConsensusGone>> + Send + 'static) {
+    tokio::spawn(async move {
+        if let Err(gone) = task.await {
+            tracing::error!("{gone}, a task depending on it stopped");
+        }
+    });
+}
 
-        self.node.clone().send(dest, body, None).await;
+/// Client msgs waiting for their turn, served round-robin across clients so that
+/// one client sending a lot can't hold back the others. Each client's own msgs stay in order.
+#[derive(Default)]
+struct FairQueue {
+    by_client: HashMap<String, VecDeque<(Message, Instant)>>,
+    turns: VecDeque<String>, // clients with queued msgs, next one first
+}
+
+impl FairQueue {
+    fn push(&mut self, msg: Message) {
+        let queue = self.by_client.entry(msg.src.clone()).or_default();
+        if queue.is_empty() {
+            self.turns.push_back(msg.src.clone());
+        }
+        queue.push_back((msg, Instant::now()));
     }
 
-    fn majority_count(&self) -> usize {
-        let all_nodes_count = self.node.other_node_ids.get().unwrap().len() + 1;
-        (all_nodes_count / 2) + 1
+    /// The next client's oldest msg, along with when it was queued.
+    fn pop(&mut self) -> Option<(Message, Instant)> {
+        let client = self.turns.pop_front()?;
+        let queue = self.by_client.get_mut(&client).unwrap();
+        let next = queue.pop_front();
+        if queue.is_empty() {
+            self.by_client.remove(&client);
+        } else {
+            self.turns.push_back(client);
+        }
+        next
     }
 
-    fn apply_to_state_machine(
-        self: Arc<Self>,
-        msg: &Message,
-        state_machine: &mut KeyValueStore<usize, usize>,
-    ) -> Body {
+    fn is_empty(&self) -> bool {
+        self.turns.is_empty()
+    }
+}
+
+/// Where received msgs go: to their handlers, or nowhere yet while the node is paused.
+#[derive(Default)]
+struct Router {
+    peer_loops: HashMap<String, tokio::sync::mpsc::Sender<Message>>,
+    key_lanes: HashMap<usize, KeyLane>, // keyed by key_lane()
+    paused: Option<PauseMode>,
+    buffered_while_paused: VecDeque<Message>,
+}
+
+// NOTE Each key of the kv store is an independent CASPaxos instance, with its own
+//      ballots and rounds (see section '2.3.3 Optimization' in the CASPaxos paper).
+//      The entries txns keep about a key are part of that key's instance, see
+//      txn::instance_keys. The instances only hold the consensus state, while the
+//      values themselves live in the state machine.
+//      The consensus task is the one serialization point for the state transitions
+//      of every instance: every handler sends it a command that decides and updates
+//      the key's state, and sends msgs only once the command returned. Whatever else
+//      a round reads or updates, such as the in-flight client ops or the LWW overlay,
+//      is owned by the same task, so no lock guards any of it.
+pub struct CASPaxos {
+    config: Config,
+    node: Arc<Node>,
+    consensus: ConsensusTask,
+    queued_client_ops: AtomicUsize, // waiting in key lanes, on every lane
+    client_rate_limiter: Option<ClientRateLimiter>,
+    next_txn_id: AtomicUsize, // of the txns we coordinate, combined with our node index
+    next_generated_id: AtomicUsize, // see generate_id
+    rejected_init: OnceLock<String>, // why, if the cluster Init told us about can't run rounds
+    clock: LocalClock,        // what our rounds move their instances' clocks to
+    broadcast: Broadcast,
+    stats: Stats,
+}
+
+impl CASPaxos {
+    pub fn new(config: Config, transport: Arc<dyn Transport>) -> Self {
+        Self {
+            node: Arc::new(Node::new(
+                config.broadcast_concurrency,
+                config.group_size,
+                QuorumSizes {
+                    prepare: config.prepare_quorum,
+                    accept: config.accept_quorum,
+                },
+                config.witnesses.clone(),
+                config.reliable_broadcast.then_some(config.client_deadline),
+                config.chaos.clone(),
+                transport,
+            )),
+            stats: Stats::new(!config.quiet_bench),
+            client_rate_limiter: config.client_ops_per_sec.map(ClientRateLimiter::new),
+            next_txn_id: AtomicUsize::new(0),
+            next_generated_id: AtomicUsize::new(0),
+            rejected_init: OnceLock::new(),
+            clock: LocalClock::new(),
+            broadcast: Broadcast::default(),
+            consensus: ConsensusTask::spawn(Consensus::new(Changelog::new(
+                config.changelog_max_entries,
+                config.changelog_max_age,
+            ))),
+            config,
+            queued_client_ops: AtomicUsize::new(0),
+        }
+    }
+
+    /// Starts from `snapshot`'s state instead of an empty one. Must be called before `run`.
+    pub async fn restore(&self, snapshot: Snapshot) -> Result<(), ConsensusGone> {
+        self.clock
+            .fast_forward(expiry::latest(&snapshot.state_machine));
+        self.consensus
+            .run(move |consensus| {
+                consensus.state_machine = snapshot.state_machine;
+                for (key, ballot_number) in snapshot.ballot_numbers {
+                    let value_digest = consensus.instance_state(&key).digest();
+                    let mut instance = consensus.instance(&key);
+                    let _ = instance.observe(ballot_number);
+                    instance.set_accepted(ballot_number, value_digest);
+                }
+            })
+            .await
+    }
+
+    async fn snapshot(&self) -> Result<Snapshot, ConsensusGone> {
+        self.consensus
+            .run(|consensus| {
+                let mut ballot_numbers: Vec<(Key, BallotNumber)> = consensus
+                    .instances
+                    .iter()
+                    .filter(|(_, instance)| !instance.accepted.is_zero())
+                    .map(|(key, instance)| (key.clone(), instance.accepted))
+                    .collect();
+                ballot_numbers.sort_unstable();
+                Snapshot {
+                    state_machine: consensus.state_machine.clone(),
+                    ballot_numbers,
+                }
+            })
+            .await
+    }
+
+    pub async fn run(self: Arc<Self>) {
+        let mut rx = self.node.clone().run().await;
+        let mut router = Router::default();
+
+        loop {
+            tokio::select! {
+                Some(msg) = rx.recv() => {
+                    self.clone().route(&mut router, msg).await;
+                }
+            };
+        }
+    }
+
+    async fn route(self: Arc<Self>, router: &mut Router, msg: Message) {
+        // a node whose Init was rejected takes part in nothing, see `cluster_error`.
+        if let Some(text) = self.rejected_init.get() {
+            if msg.body.inner.in_reply_to().is_none() {
+                let body = Body::error(msg.body.msg_id, ErrorCode::MalformedRequest, text);
+                self.node.clone().reply(&msg, body).await;
+            }
+            return;
+        }
         match msg.body.inner {
-            Body::Read { key } => {
-                let result = state_machine.read(&key);
+            Body::Pause { mode } => {
+                tracing::info!("pausing, {mode:?} msgs until resumed");
+                router.paused = Some(mode);
+                let body = Body::PauseOk {
+                    in_reply_to: msg.body.msg_id,
+                };
+                self.node.clone().reply(&msg, body).await;
+            }
+            Body::Resume {} => {
+                tracing::info!(
+                    "resuming, {} msgs were buffered",
+                    router.buffered_while_paused.len()
+                );
+                router.paused = None;
+                let body = Body::ResumeOk {
+                    in_reply_to: msg.body.msg_id,
+                };
+                self.node.clone().reply(&msg, body).await;
+                while let Some(buffered) = router.buffered_while_paused.pop_front() {
+                    self.clone().dispatch(router, buffered).await;
+                }
+            }
+            _ => match router.paused {
+                None => self.dispatch(router, msg).await,
+                Some(PauseMode::Buffer)
+                    if router.buffered_while_paused.len() < MAX_BUFFERED_WHILE_PAUSED =>
+                {
+                    router.buffered_while_paused.push_back(msg);
+                }
+                Some(_) => tracing::debug!("paused, dropping {msg:?}"),
+            },
+        }
+    }
+
+    async fn dispatch(self: Arc<Self>, router: &mut Router, mut msg: Message) {
+        if self.config.workload == Workload::Register {
+            if let Body::Read { key }
+            | Body::Write { key, .. }
+            | Body::Cas { key, .. }
+            | Body::Delete { key }
+            | Body::CasDelete { key, .. }
+            | Body::ReadVersion { key }
+            | Body::CasVersion { key, .. } = &mut msg.body.inner
+            {
+                *key = REGISTER_KEY;
+            }
+        }
+        // msgs from peers are handled in order, one peer at a time, and so are
+        // client ops on keys of the same lane. Other client msgs get their own task.
+        if self.node.node_index(&msg.src).is_some() {
+            let peer_loop = router
+                .peer_loops
+                .entry(msg.src.clone())
+                .or_insert_with(|| self.clone().spawn_handler_loop());
+            // a loop only ends if a handler panicked in it, so the msg is dropped and the
+            // peer's next msgs go to a new loop.
+            if let Err(SendError(msg)) = peer_loop.send(msg).await {
+                tracing::error!("{}'s loop is gone, dropping {msg:?}", msg.src);
+                *peer_loop = self.clone().spawn_handler_loop();
+            }
+        } else if let Some(key) = msg.body.inner.key() {
+            let key_lane = router
+                .key_lanes
+                .entry(key_lane(key))
+                .or_insert_with(|| self.clone().spawn_key_lane());
+            let is_full = key_lane.queued.load(Ordering::SeqCst) >= MAX_QUEUED_PER_KEY_LANE
+                || self.queued_client_ops.load(Ordering::SeqCst) >= MAX_QUEUED_CLIENT_OPS;
+            if is_full {
+                self.stats.record_shed_client_op();
+                let body = Body::error(
+                    msg.body.msg_id,
+                    ErrorCode::TemporarilyUnavailable,
+                    "too many queued client ops",
+                );
+                self.node.clone().reply(&msg, body).await;
+                return;
+            }
+
+            key_lane.queued.fetch_add(1, Ordering::SeqCst);
+            self.queued_client_ops.fetch_add(1, Ordering::SeqCst);
+            // likewise for a lane, whose queued ops are dropped along with it.
+            if let Err(SendError(msg)) = key_lane.tx.send(msg).await {
+                tracing::error!("a key lane is gone, dropping {msg:?}");
+                let dropped = key_lane.queued.load(Ordering::SeqCst);
+                self.queued_client_ops.fetch_sub(dropped, Ordering::SeqCst);
+                *key_lane = self.clone().spawn_key_lane();
+            }
+        } else {
+            tokio::spawn(async move { self.handle(msg).await });
+        }
+    }
+
+    /// Spawns a task handling the client ops sent to it one at a time, taking turns
+    /// between clients.
+    fn spawn_key_lane(self: Arc<Self>) -> KeyLane {
+        let (tx, mut rx) = tokio::sync::mpsc::channel::<Message>(MAX_QUEUED_PER_KEY_LANE);
+        let queued = Arc::new(AtomicUsize::new(0));
+        let lane = KeyLane {
+            tx,
+            queued: queued.clone(),
+        };
+        tokio::spawn(async move {
+            let mut queue = FairQueue::default();
+            loop {
+                if queue.is_empty() {
+                    match rx.recv().await {
+                        Some(msg) => queue.push(msg),
+                        None => break,
+                    }
+                }
+                while let Ok(msg) = rx.try_recv() {
+                    queue.push(msg);
+                }
+
+                let (msg, queued_at) = queue.pop().unwrap();
+                queued.fetch_sub(1, Ordering::SeqCst);
+                self.queued_client_ops.fetch_sub(1, Ordering::SeqCst);
+                self.stats.record_client_wait(&msg.src, queued_at.elapsed());
+                self.clone().handle(msg).await;
+            }
+        });
+        lane
+    }
+
+    /// Spawns a task handling the msgs sent to it one at a time, in order.
+    fn spawn_handler_loop(self: Arc<Self>) -> tokio::sync::mpsc::Sender<Message> {
+        let (tx, mut rx) = tokio::sync::mpsc::channel::<Message>(PEER_QUEUE_CAPACITY);
+        tokio::spawn(async move {
+            while let Some(msg) = rx.recv().await {
+                self.clone().handle(msg).await;
+            }
+        });
+        tx
+    }
+
+    /// Handles `msg`, answering it with error 13 if that takes the consensus task,
+    /// which is gone.
+    async fn handle(self: Arc<Self>, msg: Message) {
+        let (src, msg_id) = (msg.src.clone(), msg.body.msg_id);
+        let is_request = msg.body.inner.in_reply_to().is_none();
+        if let Err(gone) = self.clone().handle_msg(msg).await {
+            tracing::error!("{gone}, can't handle msg {msg_id} from {src}");
+            if is_request {
+                let body = Body::error(msg_id, ErrorCode::Crash, gone.to_string());
+                self.node.clone().send(&src, body, None).await;
+            }
+        }
+    }
+
+    async fn handle_msg(self: Arc<Self>, msg: Message) -> Result<(), ConsensusGone> {
+        // nodes removed from the members are still in the node list, so that their
+        // NodeIndexes stay valid, but whatever they send has no say in our rounds.
+        let src = self
+            .node
+            .node_index(&msg.src)
+            .filter(|_| self.node.is_peer(&msg.src));
+        let peer = || src.expect("consensus msgs from non-members are dropped first");
+
+        match msg.body.inner.clone() {
+            Body::Propose { .. }
+            | Body::Promise { .. }
+            | Body::Accept { .. }
+            | Body::Accepted { .. }
+            | Body::Heartbeat { .. }
+                if src.is_none() =>
+            {
+                tracing::warn!("dropping consensus msg from non-member {msg:?}");
+            }
+            Body::Init { .. } => {
+                // NOTE: By the time we receive this Init, its content was already used by
+                //       self.node to store the node ids provided by the msg.
+                //       So all we have to do here is to respond with InitOk, unless
+                //       the cluster can't run our rounds.
+                if let Some(text) = self.cluster_error() {
+                    tracing::error!("rejecting Init: {text}");
+                    let body = Body::error(msg.body.msg_id, ErrorCode::MalformedRequest, &text);
+                    let _ = self.rejected_init.set(text);
+                    self.node.clone().reply(&msg, body).await;
+                    return Ok(());
+                }
+                spawn_logged(self.clone().heartbeat_loop());
+                if !self.is_witness() {
+                    spawn_logged(self.clone().sync_loop());
+                }
+                spawn_logged(self.clone().txn_recovery_loop());
+                if let Some(compact_after) = self.config.compact_after {
+                    if !self.is_witness() {
+                        spawn_logged(self.clone().compaction_loop(compact_after));
+                    }
+                }
+                self.node
+                    .clone()
+                    .reply(
+                        &msg,
+                        Body::InitOk {
+                            in_reply_to: msg.body.msg_id,
+                        },
+                    )
+                    .await;
+            }
+            Body::Read { .. }
+                if self.config.workload == Workload::Broadcast
+                    && self.node.node_index(&msg.src).is_none() =>
+            {
+                let body = Body::BroadcastReadOk {
+                    in_reply_to: msg.body.msg_id,
+                    messages: self.broadcast.messages(),
+                };
+                self.node.clone().reply(&msg, body).await;
+            }
+            Body::Read { .. }
+                if self.config.workload == Workload::Counter
+                    && self.node.node_index(&msg.src).is_none() =>
+            {
+                let in_reply_to = msg.body.msg_id;
+                let read = self
+                    .read_keys(vec![COUNTER_KEY])
+                    .await
+                    .and_then(|values| counter::value(values.first().map(|(_, value)| value)));
+                let body = match read {
+                    Ok(value) => Body::ReadOk {
+                        in_reply_to,
+                        value: Value::from(value),
+                        ballot_number: None,
+                    },
+                    Err(code) => Body::error(in_reply_to, code, code.to_string()),
+                };
+                self.node.clone().reply(&msg, body).await;
+            }
+            Body::Read { .. } | Body::Write { .. } | Body::Cas { .. }
+                if self.config.workload == Workload::LinKvProxy
+                    && self.node.node_index(&msg.src).is_none() =>
+            {
+                let body = match self.call(LIN_KV_SERVICE, msg.body.inner.clone()).await {
+                    Some(reply) => reply,
+                    None => Body::error(
+                        msg.body.msg_id,
+                        ErrorCode::Timeout,
+                        "the lin-kv service didn't answer in time",
+                    ),
+                };
+                self.node.clone().reply(&msg, body).await;
+            }
+            Body::Delete { .. }
+            | Body::CasDelete { .. }
+            | Body::ReadVersion { .. }
+            | Body::CasVersion { .. }
+            | Body::Txn { .. }
+            | Body::Scan { .. }
+            | Body::MultiRead { .. }
+                if self.config.workload == Workload::LinKvProxy =>
+            {
+                let body = Body::error(
+                    msg.body.msg_id,
+                    ErrorCode::NotSupported,
+                    "the lin-kv service only has read, write and cas",
+                );
+                self.node.clone().reply(&msg, body).await;
+            }
+            // witnesses hold no state to run ops on, a full replica runs them instead.
+            Body::Read { .. }
+            | Body::Write { .. }
+            | Body::Cas { .. }
+            | Body::Delete { .. }
+            | Body::CasDelete { .. }
+            | Body::ReadVersion { .. }
+            | Body::CasVersion { .. }
+            | Body::Txn { .. }
+            | Body::ForcePropose { .. }
+            | Body::AddNode { .. }
+            | Body::RemoveNode { .. }
+                if self.is_witness() =>
+            {
+                let my_group = self.node.cluster().my_group();
+                self.forward_to_group(msg, my_group).await;
+            }
+            Body::Read { key }
+            | Body::Write { key, .. }
+            | Body::Cas { key, .. }
+            | Body::Delete { key }
+            | Body::CasDelete { key, .. }
+            | Body::ReadVersion { key }
+            | Body::CasVersion { key, .. }
+                if reserved_key(&key).is_some() =>
+            {
+                let text = reserved_key(&key).unwrap();
+                let body = Body::error(msg.body.msg_id, ErrorCode::MalformedRequest, text);
+                self.node.clone().reply(&msg, body).await;
+            }
+            Body::MultiRead { keys } if keys.iter().any(|key| reserved_key(key).is_some()) => {
+                let text = keys.iter().find_map(reserved_key).unwrap();
+                let body = Body::error(msg.body.msg_id, ErrorCode::MalformedRequest, text);
+                self.node.clone().reply(&msg, body).await;
+            }
+            Body::Read { key }
+            | Body::Write { key, .. }
+            | Body::Cas { key, .. }
+            | Body::Delete { key }
+            | Body::CasDelete { key, .. }
+            | Body::ReadVersion { key }
+            | Body::CasVersion { key, .. }
+                if self.node.cluster().group_of_key(&key) != self.node.cluster().my_group() =>
+            {
+                self.forward_to_group(msg, self.node.cluster().group_of_key(&key))
+                    .await;
+            }
+            // an LWW register can't be unset, so there's no overlay path to delete by.
+            Body::Delete { key } | Body::CasDelete { key, .. } if self.config.is_lww_key(&key) => {
+                let body = Body::error(
+                    msg.body.msg_id,
+                    ErrorCode::NotSupported,
+                    "LWW keys can't be deleted",
+                );
+                self.node.clone().reply(&msg, body).await;
+            }
+            // nor can it expire, as replicas merge their registers with no clock to
+            // agree on.
+            Body::Write {
+                key,
+                expiry_ms: Some(_),
+                ..
+            } if self.config.is_lww_key(&key) => {
+                let body = Body::error(
+                    msg.body.msg_id,
+                    ErrorCode::NotSupported,
+                    "LWW keys can't expire",
+                );
+                self.node.clone().reply(&msg, body).await;
+            }
+            Body::Read { .. }
+            | Body::Write { .. }
+            | Body::Cas { .. }
+            | Body::Delete { .. }
+            | Body::CasDelete { .. }
+            | Body::ReadVersion { .. }
+            | Body::CasVersion { .. } => {
+                let rate_limited = self
+                    .client_rate_limiter
+                    .as_ref()
+                    .and_then(|limiter| limiter.try_acquire(&msg.src).err());
+                if let Some(retry_after) = rate_limited {
+                    self.stats.record_shed_client_op();
+                    let body = Body::Error {
+                        in_reply_to: msg.body.msg_id,
+                        code: ErrorCode::TemporarilyUnavailable,
+                        text: String::from("client is over its rate limit"),
+                        retry_after_ms: Some(retry_after.as_millis() as u64 + 1),
+                        ballot_hint: None,
+                    };
+                    self.node.clone().reply(&msg, body).await;
+                } else if self.serves_from_overlay(&msg) {
+                    self.serve_from_overlay(msg).await?;
+                } else if let Some(body) = self.read_under_lease(&msg).await? {
+                    self.node.clone().reply(&msg, body).await;
+                } else if let Some(winner) = self.proxy_target(&msg).await? {
+                    self.clone().proxy(msg, winner).await;
+                } else {
+                    let client = ClientEnvelope::of(&msg);
+                    self.clone().propose_unless_saturated(msg, client).await?;
+                }
+            }
+            Body::Txn { .. } if self.config.workload == Workload::Register => {
+                let body = Body::error(
+                    msg.body.msg_id,
+                    ErrorCode::NotSupported,
+                    "txns need the KV store, not the register",
+                );
+                self.node.clone().reply(&msg, body).await;
+            }
+            Body::Txn { txn } => {
+                let malformed = txn.iter().find_map(|TxnOp(kind, key, value)| {
+                    reserved_key(key).or_else(|| {
+                        (*kind == TxnOpKind::Write && value.is_none())
+                            .then_some("txn writes need a value")
+                    })
+                });
+                if let Some(text) = malformed {
+                    let body = Body::error(msg.body.msg_id, ErrorCode::MalformedRequest, text);
+                    self.node.clone().reply(&msg, body).await;
+                } else {
+                    self.clone().coordinate_txn(msg, txn).await;
+                }
+            }
+            Body::Scan { from, to } => self.clone().scan(msg, from, to).await,
+            Body::MultiRead { keys } => {
+                let in_reply_to = msg.body.msg_id;
+                let body = match self.read_keys(keys).await {
+                    Ok(values) => Body::MultiReadOk {
+                        in_reply_to,
+                        values,
+                    },
+                    Err(code) => Body::error(in_reply_to, code, code.to_string()),
+                };
+                self.node.clone().reply(&msg, body).await;
+            }
+            // the rounds these take are waited for in their own task, so that the peer
+            // loop they come in on keeps handling the peer's part in those rounds.
+            Body::TxnPrepare { txn_id, txn } => {
+                spawn_logged(self.clone().prepare_txn(msg, txn_id, txn));
+            }
+            Body::TxnFinish {
+                txn_id,
+                commit,
+                keys,
+            } => {
+                spawn_logged(self.clone().finish_txn(msg, txn_id, commit, keys));
+            }
+            Body::TxnDecide { .. } | Body::TxnForget { .. } => {
+                self.clone().propose(msg).await?;
+            }
+            Body::ForcePropose { .. } => {
+                // skips load shedding, since it's meant to run right away
+                self.clone().propose(msg).await?;
+            }
+            Body::AddNode { node_id } | Body::RemoveNode { node_id } => {
+                let cluster = self.node.cluster();
+                let mut changed_members = self.members();
+                changed_members.retain(|id| *id != node_id);
+                if let Body::AddNode { .. } = msg.body.inner {
+                    changed_members.push(node_id.clone());
+                }
+                let rejection = if self.config.group_size.is_some() {
+                    Some((
+                        ErrorCode::NotSupported,
+                        "members can't change with several groups",
+                    ))
+                } else if membership::encode(&self.members()).is_none() {
+                    Some((
+                        ErrorCode::NotSupported,
+                        "members can't change unless named n<k>",
+                    ))
+                } else if membership::node_number(&node_id).is_none() {
+                    Some((ErrorCode::MalformedRequest, "node id should look like n<k>"))
+                } else if matches!(msg.body.inner, Body::RemoveNode { .. })
+                    && !self.members().contains(&node_id)
+                {
+                    Some((ErrorCode::NodeNotFound, "no such member to remove"))
+                } else if cluster.node_index(&node_id).is_none() && cluster.size() >= MAX_NODES {
+                    Some((
+                        ErrorCode::NotSupported,
+                        "acceptance bitmaps can't track more nodes",
+                    ))
+                } else if !cluster.with_members(&changed_members).quorums_intersect() {
+                    Some((
+                        ErrorCode::NotSupported,
+                        "quorums wouldn't intersect among the new members",
+                    ))
+                } else {
+                    None
+                };
+
+                match rejection {
+                    Some((code, text)) => {
+                        let body = Body::error(msg.body.msg_id, code, text);
+                        self.node.clone().reply(&msg, body).await;
+                    }
+                    None => self.clone().propose(msg).await?,
+                }
+            }
+            Body::InjectLatency { peer, ms, duration } => {
+                self.node.inject_latency(
+                    &peer,
+                    Duration::from_millis(ms),
+                    Duration::from_millis(duration),
+                );
+                let body = Body::InjectLatencyOk {
+                    in_reply_to: msg.body.msg_id,
+                };
+                self.node.clone().reply(&msg, body).await;
+            }
+            Body::ChangesSince { cursor } => {
+                let (changes, cursor, truncated) = self
+                    .consensus
+                    .run(move |consensus| consensus.changelog.since(cursor))
+                    .await?;
+                let body = Body::ChangesSinceOk {
+                    in_reply_to: msg.body.msg_id,
+                    changes,
+                    cursor,
+                    truncated,
+                };
+                self.node.clone().reply(&msg, body).await;
+            }
+            Body::WriteSnapshot { path } => {
+                let in_reply_to = msg.body.msg_id;
+                let body = match self.snapshot().await?.write_to(&path) {
+                    Ok(()) => Body::WriteSnapshotOk { in_reply_to },
+                    Err(e) => Body::error(in_reply_to, ErrorCode::Crash, format!("{e:#}")),
+                };
+                self.node.clone().reply(&msg, body).await;
+            }
+            Body::Health {} => {
+                let body = Body::HealthOk {
+                    in_reply_to: msg.body.msg_id,
+                    health: self.health().await?,
+                };
+                self.node.clone().reply(&msg, body).await;
+            }
+            Body::Heartbeat {
+                ballots_digest,
+                state_digest,
+            } => {
+                self.clone()
+                    .check_divergence(peer(), ballots_digest, state_digest)
+                    .await?;
+            }
+            Body::InstanceDigests {} => {
+                let body = Body::InstanceDigestsOk {
+                    in_reply_to: msg.body.msg_id,
+                    digests: self.instance_digests().await?,
+                };
+                self.node.clone().reply(&msg, body).await;
+            }
+            Body::SyncState { keys } => {
+                // each state is read along with the ballot it was accepted at.
+                let instances = self
+                    .consensus
+                    .run(move |consensus| {
+                        keys.into_iter()
+                            .filter_map(|key| {
+                                let accepted = consensus.instances.get(&key)?.accepted;
+                                let state = consensus.instance_state(&key);
+                                (!accepted.is_zero()).then_some((key, accepted, state))
+                            })
+                            .collect()
+                    })
+                    .await?;
+                let body = Body::SyncStateOk {
+                    in_reply_to: msg.body.msg_id,
+                    instances,
+                };
+                self.node.clone().reply(&msg, body).await;
+            }
+            Body::ScanKeys { from, to } => {
+                let keys = self
+                    .consensus
+                    .run(move |consensus| consensus.state_machine.keys_in(from..to))
+                    .await?;
+                let body = Body::ScanKeysOk {
+                    in_reply_to: msg.body.msg_id,
+                    keys: keys
+                        .into_iter()
+                        .filter(|key| reserved_key(key).is_none())
+                        .collect(),
+                };
+                self.node.clone().reply(&msg, body).await;
+            }
+            Body::LwwMerge { registers } => {
+                self.consensus
+                    .run(move |consensus| consensus.lww_overlay.merge(&registers))
+                    .await?;
+            }
+            Body::Echo { echo } => {
+                let body = Body::EchoOk {
+                    in_reply_to: msg.body.msg_id,
+                    echo,
+                };
+                self.node.clone().reply(&msg, body).await;
+            }
+            Body::Topology { topology } => {
+                self.broadcast
+                    .set_topology(&self.node.cluster().my_id, &topology);
+                let body = Body::TopologyOk {
+                    in_reply_to: msg.body.msg_id,
+                };
+                self.node.clone().reply(&msg, body).await;
+            }
+            Body::Broadcast { message } => {
+                let new = self.broadcast.add([message]);
+                let body = Body::BroadcastOk {
+                    in_reply_to: msg.body.msg_id,
+                };
+                self.node.clone().reply(&msg, body).await;
+                self.gossip(new, &msg.src);
+            }
+            Body::Gossip { messages } => {
+                let new = self.broadcast.add(messages);
+                let body = Body::GossipOk {
+                    in_reply_to: msg.body.msg_id,
+                };
+                self.node.clone().reply(&msg, body).await;
+                self.gossip(new, &msg.src);
+            }
+            Body::Add { delta } => {
+                let in_reply_to = msg.body.msg_id;
+                let added = self
+                    .update_with_cas(COUNTER_KEY, |current| counter::add(current, delta))
+                    .await;
+                let body = match added {
+                    Ok(_) => Body::AddOk { in_reply_to },
+                    Err(code) => Body::error(in_reply_to, code, code.to_string()),
+                };
+                self.node.clone().reply(&msg, body).await;
+            }
+            Body::Generate {} => {
+                let body = Body::GenerateOk {
+                    in_reply_to: msg.body.msg_id,
+                    id: self.generate_id(),
+                };
+                self.node.clone().reply(&msg, body).await;
+            }
+            Body::Stats {} => {
+                let (in_flight_proposals, state_digest) = self
+                    .consensus
+                    .run(|consensus| {
+                        let in_flight_proposals = consensus.in_flight_proposals.len();
+                        (in_flight_proposals, consensus.state_machine.digest())
+                    })
+                    .await?;
+                let body = Body::StatsOk {
+                    in_reply_to: msg.body.msg_id,
+                    stats: self.stats.snapshot(
+                        in_flight_proposals,
+                        self.memory_usage().await?,
+                        state_digest,
+                    ),
+                };
+                self.node.clone().reply(&msg, body).await;
+            }
+            Body::SetLogLevel { level } => {
+                let in_reply_to = msg.body.msg_id;
+                let body = match logging::set_level(&level) {
+                    Ok(()) => Body::SetLogLevelOk { in_reply_to },
+                    Err(e) => {
+                        Body::error(in_reply_to, ErrorCode::MalformedRequest, format!("{e:#}"))
+                    }
+                };
+                self.node.clone().reply(&msg, body).await;
+            }
+            Body::Proxy { proxied_msg } => {
+                // the reply goes back to the proxying node, which relays it to the client.
+                let client = ClientEnvelope::of(&msg);
+                self.clone()
+                    .propose_unless_saturated(*proxied_msg, client)
+                    .await?;
+            }
+            Body::Propose { key, ballot_number } => {
+                self.clone()
+                    .promise(peer(), msg.body.msg_id, key, ballot_number)
+                    .await?;
+            }
+            Body::Promise {
+                key,
+                ballot_number,
+                accepted_ballot_number,
+                value,
+                ..
+            } => {
+                self.clone()
+                    .handle_promise_msg(peer(), key, ballot_number, accepted_ballot_number, value)
+                    .await?;
+            }
+            Body::Accept {
+                key,
+                ballot_number,
+                value,
+            } => {
+                self.clone()
+                    .accept(peer(), msg.body.msg_id, key, ballot_number, value)
+                    .await?;
+            }
+            Body::Accepted {
+                key, ballot_number, ..
+            } => {
+                self.clone()
+                    .handle_accepted_msg(peer(), key, ballot_number)
+                    .await?;
+            }
+            // rejections of our rounds go to the round that was rejected, see follow_round.
+            Body::Error { .. } => tracing::debug!("nothing waits for error {msg:?}"),
+            Body::InitOk { .. }
+            | Body::ReadOk { .. }
+            | Body::WriteOk { .. }
+            | Body::CasOk { .. }
+            | Body::DeleteOk { .. }
+            | Body::CasDeleteOk { .. }
+            | Body::ReadVersionOk { .. }
+            | Body::CasVersionOk { .. }
+            | Body::ScanOk { .. }
+            | Body::MultiReadOk { .. }
+            | Body::EchoOk { .. }
+            | Body::GenerateOk { .. }
+            | Body::AddOk { .. }
+            | Body::TopologyOk { .. }
+            | Body::BroadcastOk { .. }
+            | Body::BroadcastReadOk { .. }
+            | Body::GossipOk { .. }
+            | Body::StatsOk { .. }
+            | Body::SetLogLevelOk { .. }
+            | Body::PauseOk { .. }
+            | Body::ResumeOk { .. }
+            | Body::ForceProposeOk { .. }
+            | Body::InjectLatencyOk { .. }
+            | Body::AddNodeOk { .. }
+            | Body::RemoveNodeOk { .. }
+            | Body::InstanceDigestsOk { .. }
+            | Body::SyncStateOk { .. }
+            | Body::ScanKeysOk { .. }
+            | Body::HealthOk { .. }
+            | Body::WriteSnapshotOk { .. }
+            | Body::ChangesSinceOk { .. }
+            | Body::TxnOk { .. }
+            | Body::TxnPrepareOk { .. }
+            | Body::TxnDecideOk { .. }
+            | Body::TxnFinishOk { .. }
+            | Body::TxnForgetOk { .. } => {
+                // most likely a duplicate, or the reply to a request that gave up on it.
+                // Replies don't get replies, so it's dropped without one.
+                tracing::warn!("dropping unexpected ack {msg:?}");
+            }
+            Body::Batch { .. } => unreachable!("batches are unpacked by the node"),
+            Body::Pause { .. } | Body::Resume { .. } => unreachable!("handled by the run loop"),
+        }
+        Ok(())
+    }
+
+    async fn handle_accepted_msg(
+        self: Arc<Self>,
+        src: NodeIndex,
+        key: Key,
+        ballot_number: BallotNumber,
+    ) -> Result<(), ConsensusGone> {
+        tracing::debug!("called handle_accepted_msg on key {key}, ballot_number {ballot_number}");
+        let this = self.clone();
+        let replies = self
+            .consensus
+            .with(&key, move |instance| {
+                if !matches!(instance.role, Role::Proposer { .. }) {
+                    tracing::debug!(
+                        "dropping accepted for ballot {ballot_number}: no round is running"
+                    );
+                    return Vec::new();
+                }
+
+                // we only want to confirm msgs accepted during the current CASPaxos round.
+                // A stale one is from a round we already gave up on, so the acceptor has
+                // nothing to learn from being told.
+                if instance.role.add_acceptance_to_inbox(src, ballot_number) != Ok(true) {
+                    tracing::debug!(
+                        "ignoring duplicate or stale accepted for ballot {ballot_number}"
+                    );
+                    return Vec::new();
+                }
+                let key = instance.key.clone();
+                this.decide_if_chosen(&key, instance, ballot_number)
+            })
+            .await?;
+
+        // without a response left in the table, the client already got its reply.
+        // The batched ops go first, so the next round only starts once they're answered.
+        for (body, client) in replies {
+            self.reply_to_client(&key, client, body).await?;
+        }
+        Ok(())
+    }
+
+    /// Ends the round at `ballot_number` once an accept quorum accepted it, returning
+    /// the replies to its ops' clients. Returns none while it isn't decided yet.
+    fn decide_if_chosen(
+        self: &Arc<Self>,
+        key: &Key,
+        instance: &mut InstanceEntry,
+        ballot_number: BallotNumber,
+    ) -> Vec<(Body, ClientEnvelope)> {
+        let promised = instance.promised;
+        let Role::Proposer {
+            op,
+            client,
+            batched,
+            last_client_confirmation,
+            ..
+        } = &instance.role
+        else {
+            return Vec::new();
+        };
+        let op_key = op.body.inner.key().cloned();
+        let clients: Vec<ClientEnvelope> = batched
+            .iter()
+            .map(|(_, client)| client.clone())
+            .chain([client.clone()])
+            .collect();
+        let last_client_confirmation = *last_client_confirmation;
+        let Some(quorum) = instance.role.acceptance_inbox().filter(|quorum| {
+            quorum.len() >= self.node.cluster().accept_quorum
+                && last_client_confirmation < ballot_number
+        }) else {
+            return Vec::new();
+        };
+
+        let _ = instance.role.set_last_client_confirmation(ballot_number);
+        let value_digest = instance.value_digest;
+        self.audit_decision(
+            op_key.as_ref(),
+            ballot_number,
+            quorum,
+            value_digest,
+            instance.consensus,
+        );
+        instance.consensus.settle_overlay();
+        self.adopt_members(key, &instance.consensus.instance_state(key));
+
+        let replies = clients
+            .into_iter()
+            .filter_map(|client| Some((instance.consensus.take_response(&client)?, client)))
+            .collect();
+        // acceptors start their side of the lease once our Accept got to them,
+        // so ours, which starts from sending it, runs out first.
+        let sent_accept_at = instance
+            .sent_accept_at
+            .filter(|_| promised == ballot_number);
+        if let Some(sent_accept_at) = sent_accept_at {
+            instance.lease = Some((ballot_number, sent_accept_at + LEASE_DURATION));
+        }
+        // the round is over, so go back to accepting other proposers' rounds.
+        self.transition(key, instance, Role::Acceptor, "decided");
+        replies
+    }
+
+    /// Sends the final reply to a proposed op, unless its client already got one, and
+    /// starts the next op queued on the instance at `key`.
+    async fn reply_to_client(
+        self: &Arc<Self>,
+        key: &Key,
+        client: ClientEnvelope,
+        mut body: Body,
+    ) -> Result<(), ConsensusGone> {
+        body.set_in_reply_to(client.msg_id);
+        let replied_to = client.clone();
+        let batch_limit = batch_limit(self.node.inbound_load());
+        let replied = self
+            .consensus
+            .with(key, move |instance| {
+                instance.consensus.in_flight_proposals.remove(&replied_to)?;
+                let waiter = instance.consensus.local_rounds.remove(&replied_to);
+                let next = if instance.running_for.as_ref() == Some(&replied_to) {
+                    instance.running_for = None;
+                    let next = instance.queued.pop_front();
+                    instance.running_for = next.as_ref().map(|(_, next)| next.clone());
+                    let batched = instance.queued.len().min(batch_limit);
+                    next.map(|next| (next, instance.queued.drain(..batched).collect::<Vec<_>>()))
+                } else {
+                    instance.queued.retain(|(_, queued)| *queued != replied_to);
+                    None
+                };
+                Some((next, waiter))
+            })
+            .await?;
+        let Some((next, waiter)) = replied else {
+            return Ok(());
+        };
+        if let Some(((op, next), batched)) = next {
+            spawn_logged(self.clone().start_round(op, next, batched, 0));
+        }
+
+        // rounds the node runs for itself have nobody to reply to, except
+        // whoever waits for their result here.
+        if client.src == self.node.cluster().my_id {
+            if let Some(waiter) = waiter {
+                let _ = waiter.send(body);
+            }
+        } else {
+            self.node.clone().send(&client.src, body, None).await;
+        }
+        Ok(())
+    }
+
+    async fn promise(
+        self: Arc<Self>,
+        src: NodeIndex,
+        src_msg_id: usize,
+        key: Key,
+        ballot_number: BallotNumber,
+    ) -> Result<(), ConsensusGone> {
+        tracing::debug!("called promise() on key {key}, ballot_number {ballot_number}");
+        // the accepted state is read by the same command as the ballot, so that it's
+        // the one accepted at the ballot we send along with it.
+        let this = self.clone();
+        let observed = self
+            .consensus
+            .with(&key, move |instance| {
+                if instance.leased_to().is_some_and(|holder| holder != src) {
+                    Err(instance.promised)
+                } else {
+                    // a stale ballot is rejected without touching a round of ours, which
+                    // only a greater ballot than it preempts.
+                    instance.observe(ballot_number).map(|()| {
+                        let key = instance.key.clone();
+                        this.transition(&key, instance, Role::Acceptor, "propose_received");
+                        (instance.accepted, instance.consensus.instance_state(&key))
+                    })
+                }
+            })
+            .await?;
+
+        let (accepted_ballot_number, value) = match observed {
+            Ok(accepted) => accepted,
+            Err(highest_known_ballot_number) => {
+                self.clone()
+                    .send_reject_ballot_number(src, src_msg_id, highest_known_ballot_number)
+                    .await;
+                return Ok(());
+            }
+        };
+
+        let body = Body::Promise {
+            in_reply_to: src_msg_id,
+            key,
+            ballot_number,
+            accepted_ballot_number,
+            value,
+        };
+
+        self.node
+            .clone()
+            .send(&self.node.node_id(src), body, None)
+            .await;
+        Ok(())
+    }
+
+    async fn handle_promise_msg(
+        self: Arc<Self>,
+        src: NodeIndex,
+        key: Key,
+        ballot_number: BallotNumber,
+        accepted_ballot_number: BallotNumber,
+        value: KeyValueStore<Key, Value>,
+    ) -> Result<(), ConsensusGone> {
+        tracing::debug!("called handle_promise_msg() on key {key}, ballot_number {ballot_number}");
+        let this = self.clone();
+        let after_promise = self
+            .consensus
+            .with(&key, move |instance| {
+                let promised = instance.promised;
+                let Role::Proposer {
+                    ballot_number: active_ballot_number,
+                    ..
+                } = &instance.role
+                else {
+                    tracing::debug!(
+                        "dropping promise for ballot {ballot_number}: no round is running"
+                    );
+                    return AfterPromise::Waiting;
+                };
+                // a promise for another round, or for one a greater ballot preempted, says
+                // nothing about the current one, and its acceptor has nothing to learn
+                // from being told.
+                if ballot_number != *active_ballot_number || promised > ballot_number {
+                    tracing::debug!("ignoring stale promise for ballot {ballot_number}");
+                    return AfterPromise::Waiting;
+                }
+                // the round already had its quorum of promises, so a late one
+                // has nothing left to contribute and must not touch the round.
+                if instance.role.is_late_promise(ballot_number) {
+                    tracing::debug!("ignoring late promise for ballot {ballot_number}");
+                    this.stats.record_late_promise();
+                    return AfterPromise::Waiting;
+                }
+
+                let key = instance.key.clone();
+                this.count_promise(
+                    &key,
+                    instance,
+                    src,
+                    ballot_number,
+                    accepted_ballot_number,
+                    value,
+                )
+            })
+            .await?;
+
+        self.finish_promise_phase(key, ballot_number, after_promise)
+            .await
+    }
+
+    /// Counts `src`'s promise towards the round at `ballot_number`, and once that
+    /// makes a prepare quorum, ends the round's Propose phase.
+    fn count_promise(
+        self: &Arc<Self>,
+        key: &Key,
+        instance: &mut InstanceEntry,
+        src: NodeIndex,
+        ballot_number: BallotNumber,
+        accepted_ballot_number: BallotNumber,
+        value: InstanceState,
+    ) -> AfterPromise {
+        let Role::Proposer {
+            last_accept_broadcast,
+            op,
+            batched,
+            ..
+        } = &instance.role
+        else {
+            return AfterPromise::Waiting;
+        };
+        let last_accept_broadcast = *last_accept_broadcast;
+        let op = op.clone();
+        let is_batch = !batched.is_empty();
+
+        let cluster = self.node.cluster();
+        let quorum_is_reached_for_the_first_time = instance
+            .role
+            .add_promise_to_inbox(src, ballot_number, accepted_ballot_number, value)
+            .is_ok_and(|promises| promises >= cluster.prepare_quorum)
+            && last_accept_broadcast < ballot_number;
+        let promises = instance
+            .role
+            .promises_inbox()
+            .filter(|_| quorum_is_reached_for_the_first_time);
+        let builds_on = promises
+            .and_then(PromisesInbox::highest)
+            .map(|(_, _, state)| state.clone());
+        // a read of a chosen value changes nothing, so it's answered right away,
+        // unless the overlay has a write to fold into it first, or the key was given
+        // a TTL, which every such write sets the instance's clock for: whether the
+        // key expired goes by that clock, which only an Accept phase moves.
+        let expires = builds_on
+            .as_ref()
+            .is_some_and(|state| state.read(&expiry::clock_key(key)).is_some());
+        let reads_chosen_value = matches!(op.body.inner, Body::Read { .. })
+            && !is_batch
+            && !expires
+            && promises.is_some_and(|promises| promises.highest_is_chosen(cluster.accept_quorum))
+            && instance.consensus.lww_overlay.get(key).is_none();
+        match builds_on {
+            Some(state) if reads_chosen_value => self
+                .read_from_promises(key, instance, &op, state)
+                .map_or(AfterPromise::Waiting, |(client, body)| {
+                    AfterPromise::Read(client, body)
+                }),
+            Some(state) => match self.accept_own_round(key, instance, &op, state) {
+                Some(state) => {
+                    let replies = self.decide_if_chosen(key, instance, ballot_number);
+                    AfterPromise::Accepted(state, replies)
+                }
+                None => AfterPromise::Waiting,
+            },
+            None => AfterPromise::Waiting,
+        }
+    }
+
+    /// Sends what the round at `ballot_number` needs sent once its Propose phase ended.
+    // boxed, since replying to clients leads back to starting rounds.
+    fn finish_promise_phase(
+        self: Arc<Self>,
+        key: Key,
+        ballot_number: BallotNumber,
+        after_promise: AfterPromise,
+    ) -> BoxFuture<'static, Result<(), ConsensusGone>> {
+        Box::pin(async move {
+            match after_promise {
+                AfterPromise::Waiting => (),
+                AfterPromise::Accepted(value, replies) => {
+                    self.clone()
+                        .broadcast_accept(key.clone(), ballot_number, value)
+                        .await;
+                    for (body, client) in replies {
+                        self.reply_to_client(&key, client, body).await?;
+                    }
+                }
+                AfterPromise::Read(client, body) => {
+                    self.reply_to_client(&key, client, body).await?
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// Ends the round of a read whose promises agree on a chosen `state`, with the
+    /// reply it gets from it. Returns None if no round is running anymore.
+    fn read_from_promises(
+        self: &Arc<Self>,
+        key: &Key,
+        instance: &mut InstanceEntry,
+        op: &Message,
+        mut state: InstanceState,
+    ) -> Option<(ClientEnvelope, Body)> {
+        let ballot_number = instance.role.ballot_number()?;
+        let Role::Proposer { client, .. } = &instance.role else {
+            return None;
+        };
+        let client = client.clone();
+        let body = self
+            .clone()
+            .apply_to_state_machine(op, ballot_number, &mut state);
+        self.stats.record_promise_only_read();
+        self.transition(key, instance, Role::Acceptor, "read_from_promises");
+        Some((client, body))
+    }
+
+    /// Applies `op`, then the ops batched with it, to `state`, the state the round of
+    /// the proposer at `instance` builds on, and accepts the result ourselves. Returns it, to be sent along with Accept msgs,
+    /// or None if no round is running anymore.
+    fn accept_own_round(
+        self: &Arc<Self>,
+        key: &Key,
+        instance: &mut InstanceEntry,
+        op: &Message,
+        mut state: InstanceState,
+    ) -> Option<InstanceState> {
+        let ballot_number = instance.role.ballot_number()?;
+        instance
+            .role
+            .set_last_accept_broadcast(ballot_number)
+            .ok()?;
+        instance.consensus.fold_overlay(key, &mut state);
+        let Role::Proposer {
+            client, batched, ..
+        } = &instance.role
+        else {
+            return None;
+        };
+        let responses: Vec<(ClientEnvelope, Body)> = [(op, client)]
+            .into_iter()
+            .chain(batched.iter().map(|(op, client)| (op, client)))
+            .map(|(op, client)| {
+                let body = profiling::time(Stage::Apply, || {
+                    self.clone()
+                        .apply_to_state_machine(op, ballot_number, &mut state)
+                });
+                (client.clone(), body)
+            })
+            .collect();
+        for (client, body) in responses {
+            if let Some(in_flight) = instance.consensus.in_flight_proposals.get_mut(&client) {
+                in_flight.response = Some(body);
+            }
+        }
+        instance.consensus.replace_instance_state(key, &state);
+        instance.set_accepted(ballot_number, state.digest());
+        instance.sent_accept = ballot_number;
+        instance.sent_accept_at = Some(Instant::now());
+        let my_index = self.node.cluster().my_index;
+        // the caller sends our Accept msgs next, which is what makes us the last winner.
+        instance.consensus.last_ballot_winner = Some(my_index);
+        self.grant_lease(instance, my_index);
+        // we're one of the acceptors the round needs, unless we're a witness.
+        if !self.is_witness() {
+            let _ = instance
+                .role
+                .add_acceptance_to_inbox(my_index, ballot_number);
+        }
+        Some(state)
+    }
+
+    async fn broadcast_accept(
+        self: Arc<Self>,
+        key: Key,
+        ballot_number: BallotNumber,
+        value: InstanceState,
+    ) {
+        let body = Body::Accept {
+            key: key.clone(),
+            ballot_number,
+            value,
+        };
+        self.broadcast_for_round(key, ballot_number, body, None)
+            .await;
+    }
+
+    async fn accept(
+        self: Arc<Self>,
+        src: NodeIndex,
+        src_msg_id: usize,
+        key: Key,
+        ballot_number: BallotNumber,
+        value: KeyValueStore<Key, Value>,
+    ) -> Result<(), ConsensusGone> {
+        tracing::debug!("called accept() on key {key}, ballot_number {ballot_number}");
+        let this = self.clone();
+        let observed = self
+            .consensus
+            .with(&key, move |instance| match instance.role {
+                Role::Proposer { .. } => None,
+                Role::Acceptor => {
+                    let rejected_by = instance.observe(ballot_number).err();
+                    if rejected_by.is_none() {
+                        let key = instance.key.clone();
+                        instance.consensus.replace_instance_state(&key, &value);
+                        this.adopt_members(&key, &value);
+                        instance.set_accepted(ballot_number, value.digest());
+                        // accepting also promises the proposer's next ballot, which it
+                        // then uses without a Propose phase (see `holds_lease`).
+                        instance.promised = ballot_number.next(ballot_number.1);
+                        this.grant_lease(instance, src);
+                        instance.consensus.last_ballot_winner = Some(src);
+                        instance.consensus.settle_overlay();
+                    }
+                    Some(rejected_by)
+                }
+            })
+            .await?;
+        let Some(rejected_by) = observed else {
+            return Ok(());
+        };
+
+        if let Some(highest_known_ballot_number) = rejected_by {
+            self.clone()
+                .send_reject_ballot_number(src, src_msg_id, highest_known_ballot_number)
+                .await;
+            return Ok(());
+        }
+
+        self.node
+            .clone()
+            .send(
+                &self.node.node_id(src),
+                Body::Accepted {
+                    in_reply_to: src_msg_id,
+                    key,
+                    ballot_number,
+                },
+                None,
+            )
+            .await;
+        Ok(())
+    }
+
+    async fn propose(self: Arc<Self>, op: Message) -> Result<(), ConsensusGone> {
+        let client = ClientEnvelope::of(&op);
+        self.propose_for(op, client).await
+    }
+
+    /// Proposes `op`, replying to `client` once it's decided. While the instance is busy
+    /// with another client's op, `op` waits for it to get its reply, and then goes in
+    /// the next round along with the other ops that waited.
+    async fn propose_for(
+        self: Arc<Self>,
+        op: Message,
+        client: ClientEnvelope,
+    ) -> Result<(), ConsensusGone> {
+        let key = instance_key(&op.body.inner);
+        spawn_logged(self.clone().expire_proposal(key.clone(), client.clone()));
+
+        let to_start = self
+            .consensus
+            .with(&key, move |instance| {
+                instance
+                    .consensus
+                    .in_flight_proposals
+                    .insert(client.clone(), InFlightProposal::default());
+                if instance.running_for.is_some() {
+                    instance.queued.push_back((op, client));
+                    return None;
+                }
+                instance.running_for = Some(client.clone());
+                Some((op, client))
+            })
+            .await?;
+        if let Some((op, client)) = to_start {
+            self.start_round(op, client, Vec::new(), 0).await?;
+        }
+        Ok(())
+    }
+
+    /// Answers `client` with a timeout error if its op isn't decided within the client
+    /// deadline, and drops the round the instance at `key` may still run for it.
+    async fn expire_proposal(
+        self: Arc<Self>,
+        key: Key,
+        client: ClientEnvelope,
+    ) -> Result<(), ConsensusGone> {
+        tokio::time::sleep(self.config.client_deadline).await;
+        let this = self.clone();
+        let expired = client.clone();
+        let is_in_flight = self
+            .consensus
+            .with(&key, move |instance| {
+                if !instance
+                    .consensus
+                    .in_flight_proposals
+                    .contains_key(&expired)
+                {
+                    return false;
+                }
+                let runs_for_client = matches!(
+                    &instance.role,
+                    Role::Proposer { client: running_for, .. } if *running_for == expired
+                );
+                if runs_for_client {
+                    let key = instance.key.clone();
+                    this.transition(&key, instance, Role::Acceptor, "client_deadline");
+                }
+                true
+            })
+            .await?;
+        if !is_in_flight {
+            return Ok(());
+        }
+
+        let body = Body::error(
+            client.msg_id,
+            ErrorCode::Timeout,
+            "op wasn't decided within the client deadline",
+        );
+        self.reply_to_client(&key, client, body).await
+    }
+
+    /// Starts a round proposing `op` and then the `batched` ops, after `attempt` rounds
+    /// for them were rejected. While we hold the lease on the key, the round goes
+    /// straight to its Accept phase.
+    async fn start_round(
+        self: Arc<Self>,
+        op: Message,
+        client: ClientEnvelope,
+        batched: Vec<(Message, ClientEnvelope)>,
+        attempt: u32,
+    ) -> Result<(), ConsensusGone> {
+        let key = instance_key(&op.body.inner);
+        let my_index = self.node.cluster().my_index;
+        let this = self.clone();
+        let (round_op, round_client, round_batched) = (op.clone(), client.clone(), batched.clone());
+        let (ballot_number, holds_lease, after_promise) = self
+            .consensus
+            .with(&key, move |instance| {
+                let (op, client, batched) = (round_op, round_client, round_batched);
+                let key = instance.key.clone();
+                let holds_lease = attempt == 0 && instance.holds_lease();
+                let ballot_number = instance.next_ballot(my_index);
+                let (last_accept_broadcast, last_client_confirmation) = match instance.role {
+                    Role::Proposer {
+                        last_accept_broadcast,
+                        last_client_confirmation,
+                        ..
+                    } => (last_accept_broadcast, last_client_confirmation),
+                    Role::Acceptor => (BallotNumber::ZERO, BallotNumber::ZERO),
+                };
+
+                let proposer = Role::Proposer {
+                    op: Box::new(op.clone()),
+                    client,
+                    batched,
+                    ballot_number,
+                    last_accept_broadcast,
+                    promises_inbox: PromisesInbox::default(),
+                    acceptance_inbox: AcceptanceInbox::default(),
+                    last_client_confirmation,
+                };
+                let trigger = if attempt == 0 { "client_op" } else { "retry" };
+                this.transition(&key, instance, proposer, trigger);
+                // our state is the one we won the leased ballot with, as any later accept
+                // would have raised the ballot seen past it.
+                let after_promise = if holds_lease {
+                    let state = instance.consensus.instance_state(&key);
+                    match this.accept_own_round(&key, instance, &op, state) {
+                        Some(state) => {
+                            let replies = this.decide_if_chosen(&key, instance, ballot_number);
+                            AfterPromise::Accepted(state, replies)
+                        }
+                        None => AfterPromise::Waiting,
+                    }
+                // we promise our own round like any acceptor would, unless we leased the
+                // key to another proposer.
+                } else if instance.leased_to().is_none_or(|holder| holder == my_index) {
+                    let accepted = instance.accepted;
+                    let state = instance.consensus.instance_state(&key);
+                    this.count_promise(&key, instance, my_index, ballot_number, accepted, state)
+                } else {
+                    AfterPromise::Waiting
+                };
+                (ballot_number, holds_lease, after_promise)
+            })
+            .await?;
+
+        if !matches!(after_promise, AfterPromise::Waiting) {
+            if holds_lease {
+                self.stats.record_fast_round();
+            }
+            return self
+                .finish_promise_phase(key, ballot_number, after_promise)
+                .await;
+        }
+
+        let body = Body::Propose {
+            key: key.clone(),
+            ballot_number,
+        };
+        let retry = Retry {
+            op: Box::new(op),
+            client,
+            batched,
+            attempt,
+        };
+        self.broadcast_for_round(key, ballot_number, body, Some(retry))
+            .await;
+        Ok(())
+    }
+
+    /// Broadcasts one of the round's requests, and hands the replies to `follow_round`.
+    async fn broadcast_for_round(
+        self: Arc<Self>,
+        key: Key,
+        ballot_number: BallotNumber,
+        body: Body,
+        retry: Option<Retry>,
+    ) {
+        // witnesses only take part in Propose phases.
+        let cluster = self.node.cluster();
+        let peers = match body {
+            Body::Accept { .. } => cluster.other_replica_ids(),
+            _ => cluster.other_node_ids.clone(),
+        };
+        let (tx, rx) = tokio::sync::mpsc::channel(peers.len().max(1));
+        self.node.clone().broadcast_to(&peers, body, Some(tx)).await;
+        spawn_logged(self.follow_round(key, ballot_number, rx, retry));
+    }
+
+    /// Handles the replies to a round's requests like any other msg, except for the
+    /// ballot rejections, which get the round retried if it came with a `retry`. So
+    /// does the round getting preempted.
+    // boxed, since retries and the handlers it runs lead back to spawning it.
+    fn follow_round(
+        self: Arc<Self>,
+        key: Key,
+        ballot_number: BallotNumber,
+        mut replies: tokio::sync::mpsc::Receiver<Message>,
+        mut retry: Option<Retry>,
+    ) -> BoxFuture<'static, Result<(), ConsensusGone>> {
+        Box::pin(async move {
+            // past the client's deadline, whatever replies are left don't matter anymore.
+            let deadline = Instant::now() + self.config.client_deadline;
+            while let Ok(Some(reply)) = tokio::time::timeout_at(deadline, replies.recv()).await {
+                match reply.body.inner {
+                    Body::Error {
+                        code: ErrorCode::PreconditionFailed,
+                        ballot_hint,
+                        ..
+                    } => {
+                        // so that the retry's ballot goes past the one we lost to right away.
+                        self.consensus
+                            .with(&key, move |instance| {
+                                if let Some(hint) = ballot_hint {
+                                    let _ = instance.observe(hint);
+                                }
+                                // a greater ballot is around, so the next rounds take
+                                // both phases.
+                                instance.lease = None;
+                            })
+                            .await?;
+                        // the round can still reach a quorum, so it keeps going until the
+                        // retry is due. One rejection is enough to get it retried.
+                        if let Some(retry) = retry.take() {
+                            spawn_logged(self.clone().retry_round(
+                                key.clone(),
+                                ballot_number,
+                                retry,
+                            ));
+                        }
+                    }
+                    _ => {
+                        self.clone().handle(reply).await;
+                        // a round preempted by a greater ballot before its Accept phase
+                        // only gets ignored promises from then on, so it's retried like
+                        // a rejected one.
+                        let is_preempted = self
+                            .consensus
+                            .with(&key, move |instance| {
+                                instance.role.ballot_number() != Some(ballot_number)
+                                    && instance.sent_accept < ballot_number
+                            })
+                            .await?;
+                        if let Some(retry) = retry.take_if(|_| is_preempted) {
+                            spawn_logged(self.clone().retry_round(
+                                key.clone(),
+                                ballot_number,
+                                retry,
+                            ));
+                        }
+                    }
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// How long to wait before retrying after `attempt` tries lost to other proposers.
+    fn retry_backoff(&self, attempt: u32) -> Duration {
+        self.config
+            .retry_backoff_base
+            .saturating_mul(1 << attempt.min(16))
+            .min(self.config.retry_backoff_cap)
+            .mul_f64(rng::with_rng(|rng| rng.random_range(0.5..=1.0)))
+    }
+
+    /// Waits out a randomized exponential backoff, then proposes the ops of the rejected
+    /// round at `ballot_number` again, unless their clients got an answer meanwhile.
+    /// Past max_proposal_retries, the clients get a timeout error instead.
+    async fn retry_round(
+        self: Arc<Self>,
+        key: Key,
+        ballot_number: BallotNumber,
+        retry: Retry,
+    ) -> Result<(), ConsensusGone> {
+        let Retry {
+            op,
+            client,
+            mut batched,
+            attempt,
+        } = retry;
+        tokio::time::sleep(self.retry_backoff(attempt)).await;
+
+        let this = self.clone();
+        let rejected = client.clone();
+        let still_in_flight = self
+            .consensus
+            .with(&key, move |instance| {
+                let in_flight_proposals = &instance.consensus.in_flight_proposals;
+                if !in_flight_proposals.contains_key(&rejected) {
+                    return None;
+                }
+                batched.retain(|(_, client)| in_flight_proposals.contains_key(client));
+
+                // once we sent Accept msgs at or past the rejected ballot, they may carry
+                // the op's value to a later round, which picks it up. Proposing the op
+                // again could apply it twice, so the client is left to time out instead.
+                // Rounds on the instance run one at a time, so those msgs can only be for
+                // these ops.
+                if instance.sent_accept >= ballot_number {
+                    return None;
+                }
+                if attempt >= this.config.max_proposal_retries
+                    && instance.role.ballot_number() == Some(ballot_number)
+                {
+                    let key = instance.key.clone();
+                    this.transition(&key, instance, Role::Acceptor, "retries_exhausted");
+                }
+                Some(batched)
+            })
+            .await?;
+        let Some(batched) = still_in_flight else {
+            return Ok(());
+        };
+
+        if attempt >= self.config.max_proposal_retries {
+            tracing::debug!("giving up on {op:?} after {attempt} retries");
+            for (_, client) in batched.into_iter().chain([(*op, client)]) {
+                let body = Body::error(
+                    client.msg_id,
+                    ErrorCode::Timeout,
+                    format!("ballot rejected {} times", attempt + 1),
+                );
+                self.reply_to_client(&key, client, body).await?;
+            }
+            Ok(())
+        } else {
+            self.start_round(*op, client, batched, attempt + 1).await
+        }
+    }
+
+    /// Runs a round of our own on `inner` and waits for its result, up to the client deadline.
+    async fn propose_locally(self: Arc<Self>, inner: Body) -> Result<Option<Body>, ConsensusGone> {
+        let my_id = self.node.cluster().my_id.clone();
+        let op = Message {
+            src: my_id.clone(),
+            dest: my_id,
+            body: BodyWithMsgId {
+                msg_id: self.node.reserve_next_msg_id(),
+                resent: false,
+                inner,
+            },
+        };
+        let client = ClientEnvelope::of(&op);
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let waiting = client.clone();
+        self.consensus
+            .run(move |consensus| consensus.local_rounds.insert(waiting, tx))
+            .await?;
+
+        self.clone().propose(op).await?;
+        let result = tokio::time::timeout(self.config.client_deadline, rx).await;
+        self.consensus
+            .run(move |consensus| consensus.local_rounds.remove(&client))
+            .await?;
+        Ok(result.ok().and_then(Result::ok))
+    }
+
+    /// Proposes `op` for `client`, or sheds it with error 11 when too much is in flight.
+    async fn propose_unless_saturated(
+        self: Arc<Self>,
+        op: Message,
+        client: ClientEnvelope,
+    ) -> Result<(), ConsensusGone> {
+        if self.is_saturated().await? {
+            self.stats.record_shed_client_op();
+            let body = Body::error(
+                client.msg_id,
+                ErrorCode::TemporarilyUnavailable,
+                "proposer is saturated",
+            );
+            self.node.clone().send(&client.src, body, None).await;
+            Ok(())
+        } else {
+            self.propose_for(op, client).await
+        }
+    }
+
+    /// Lets `proposer` build on our accepts without anyone else getting our promise,
+    /// for the lease's duration. Only done for lease reads, which rely on it.
+    fn grant_lease(&self, instance: &mut Instance, proposer: NodeIndex) {
+        if self.config.reads == ReadMode::Lease {
+            instance.granted_lease = Some((proposer, Instant::now() + LEASE_DURATION));
+        }
+    }
+
+    /// The reply to `msg`, if it's a read we can answer from our own state: with lease
+    /// reads, while we hold the lease of the key and have no round of ours running on
+    /// it, our state is the chosen one, and no one else can choose another meanwhile.
+    async fn read_under_lease(
+        self: &Arc<Self>,
+        msg: &Message,
+    ) -> Result<Option<Body>, ConsensusGone> {
+        let Body::Read { key } = &msg.body.inner else {
+            return Ok(None);
+        };
+        // the register's key holds the register as a whole, for `Register` to read.
+        if self.config.reads != ReadMode::Lease || self.config.workload == Workload::Register {
+            return Ok(None);
+        }
+        let key = key.clone();
+        let leased = self
+            .consensus
+            .with(&instance_key(&msg.body.inner), move |instance| {
+                let is_idle =
+                    instance.running_for.is_none() && matches!(instance.role, Role::Acceptor);
+                let (won, _) = instance
+                    .lease
+                    .filter(|_| is_idle && instance.holds_lease())?;
+                if instance.consensus.lww_overlay.get(&key).is_some() {
+                    return None;
+                }
+                // a txn's lock fails the read, which a round then tells the client.
+                let state = instance.consensus.instance_state(&key);
+                if txn::is_locked(&state, &key) {
+                    return None;
+                }
+                let entry = state.entry(&key);
+                // whether the key expired goes by the clock of its instance, which only
+                // rounds move.
+                if entry.is_some_and(|entry| entry.expires_at.is_some()) {
+                    return None;
+                }
+                Some(
+                    entry
+                        .and_then(|entry| entry.value.clone())
+                        .map(|value| (value, won)),
+                )
+            })
+            .await?;
+        let Some(value) = leased else {
+            return Ok(None);
+        };
+
+        self.stats.record_lease_read();
+        let in_reply_to = msg.body.msg_id;
+        Ok(Some(match value {
+            Some((value, won)) => Body::ReadOk {
+                in_reply_to,
+                value,
+                ballot_number: self.config.debug_read_ballots.then_some(won),
+            },
+            None => Body::error(
+                in_reply_to,
+                ErrorCode::KeyDoesNotExist,
+                ErrorCode::KeyDoesNotExist.to_string(),
+            ),
+        }))
+    }
+
+    /// The node client ops get proxied to: the last one to win a ballot, unless
+    /// that's us or we're running a round of our own on the op's key. Heartbeats
+    /// probe the winner: once it goes silent, it's forgotten and ops get proposed
+    /// here, rather than waiting on a node that may be down.
+    async fn proxy_target(
+        self: &Arc<Self>,
+        op: &Message,
+    ) -> Result<Option<NodeIndex>, ConsensusGone> {
+        let this = self.clone();
+        self.consensus
+            .with(&instance_key(&op.body.inner), move |instance| {
+                let is_proposer = matches!(instance.role, Role::Proposer { .. });
+                let winner = instance.consensus.last_ballot_winner?;
+                if is_proposer || winner == this.node.cluster().my_index {
+                    return None;
+                }
+                if this.node.is_suspected(&this.node.node_id(winner)) {
+                    tracing::debug!(
+                        "{} went silent, not proxying to it",
+                        this.node.node_id(winner)
+                    );
+                    instance.consensus.last_ballot_winner = None;
+                    return None;
+                }
+                Some(winner)
+            })
+            .await
+    }
+
+    /// Hands a client op to `winner` to propose, and relays its reply to the client.
+    /// A winner that doesn't reply in time is forgotten, so the next ops get proposed here,
+    /// and the client gets a timeout error.
+    async fn proxy(self: Arc<Self>, msg: Message, winner: NodeIndex) {
+        let body = Body::Proxy {
+            proxied_msg: Box::new(msg.clone()),
+        };
+
+        // the lane moves on to its next op while this one is out at the winner.
+        spawn_logged(async move {
+            match self.call(&self.node.node_id(winner), body).await {
+                Some(body) => {
+                    self.node.clone().reply(&msg, body).await;
+                }
+                None => {
+                    tracing::debug!(
+                        "{} didn't reply to proxied {msg:?}",
+                        self.node.node_id(winner)
+                    );
+                    let body = Body::error(
+                        msg.body.msg_id,
+                        ErrorCode::Timeout,
+                        "proxied op wasn't decided within the client deadline",
+                    );
+                    self.node.clone().reply(&msg, body).await;
+                    self.consensus
+                        .run(move |consensus| {
+                            if consensus.last_ballot_winner == Some(winner) {
+                                consensus.last_ballot_winner = None;
+                            }
+                        })
+                        .await?;
+                }
+            }
+            Ok(())
+        });
+    }
+
+    /// Rejects the ballot of `dest`'s msg, naming the greater one it lost to.
+    async fn send_reject_ballot_number(
+        self: Arc<Self>,
+        dest: NodeIndex,
+        in_reply_to: usize,
+        highest_known_ballot_number: BallotNumber,
+    ) {
+        let body = Body::Error {
+            in_reply_to,
+            code: ErrorCode::PreconditionFailed,
+            text: format!("ballot number is behind {highest_known_ballot_number}"),
+            retry_after_ms: None,
+            ballot_hint: Some(highest_known_ballot_number),
+        };
+
+        self.node
+            .clone()
+            .send(&self.node.node_id(dest), body, None)
+            .await;
+    }
+
+    /// Hands a client op to a member of the group storing its key, and relays the reply.
+    async fn forward_to_group(&self, msg: Message, group: usize) {
+        match self.call_group(group, msg.body.inner.clone()).await {
+            Some(body) => self.node.clone().reply(&msg, body).await,
+            None => tracing::debug!("group {group} didn't reply to forwarded {msg:?}"),
+        }
+    }
+
+    /// Sends `body` to a member of `group` -- another node than us if there's one,
+    /// and never a witness -- and waits for its reply, up to the client deadline.
+    async fn call_group(&self, group: usize, body: Body) -> Option<Body> {
+        let cluster = self.node.cluster();
+        let members: Vec<&String> = cluster
+            .group_members(group)
+            .iter()
+            .filter(|member| **member != cluster.my_id && !cluster.is_witness(member))
+            .collect();
+        let member = match members.len() {
+            0 => cluster.my_id.clone(),
+            len => members[rng::with_rng(|rng| rng.random_range(0..len))].clone(),
+        };
+
+        self.call(&member, body).await
+    }
+
+    /// Runs a client txn as its coordinator, with two-phase commit across the
+    /// groups storing its keys. See the txn module for the steps.
+    async fn coordinate_txn(self: Arc<Self>, msg: Message, txn: Vec<TxnOp>) {
+        let in_reply_to = msg.body.msg_id;
+        let cluster = self.node.cluster();
+        let txn_id = (self.next_txn_id.fetch_add(1, Ordering::SeqCst) << NODE_INDEX_BITS)
+            | cluster.my_index as usize;
+
+        // positions in the txn of each group's ops, so their results can be put back in order.
+        let mut positions_by_group: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+        for (position, op) in txn.iter().enumerate() {
+            positions_by_group
+                .entry(cluster.group_of_key(op.key()))
+                .or_default()
+                .push(position);
+        }
+
+        let prepares = positions_by_group.iter().map(|(group, positions)| {
+            let body = Body::TxnPrepare {
+                txn_id,
+                txn: positions
+                    .iter()
+                    .map(|position| txn[*position].clone())
+                    .collect(),
+            };
+            self.call_group(*group, body)
+        });
+        let prepared = futures::future::join_all(prepares).await;
+        let mut completed = txn.clone();
+        let mut all_prepared = true;
+        for (positions, reply) in positions_by_group.values().zip(prepared) {
+            match reply {
+                Some(Body::TxnPrepareOk { txn, .. }) => {
+                    for (position, op) in positions.iter().zip(txn) {
+                        completed[*position] = op;
+                    }
+                }
+                _ => all_prepared = false,
+            }
+        }
+
+        let decision_group = cluster.group_of_key(&txn::decision_key(txn_id));
+        let decision = Body::TxnDecide {
+            txn_id,
+            commit: all_prepared,
+        };
+        let commit = match self.call_group(decision_group, decision).await {
+            Some(Body::TxnDecideOk { commit, .. }) => commit,
+            // the txn is in doubt until recovery decides it.
+            _ => {
+                let body = Body::error(
+                    in_reply_to,
+                    ErrorCode::Timeout,
+                    "couldn't record the txn's decision",
+                );
+                self.node.clone().reply(&msg, body).await;
+                return;
+            }
+        };
+
+        // a committed txn is only reported once every group applied its writes, as
+        // until then ops on its keys fail, and the client could read around them.
+        // Locks that still aren't released by the deadline are left to recovery.
+        let deadline = Instant::now() + self.config.client_deadline;
+        let mut unfinished: Vec<(&usize, &Vec<usize>)> = positions_by_group.iter().collect();
+        let mut attempt = 0;
+        while !unfinished.is_empty() && Instant::now() < deadline {
+            if attempt > 0 {
+                tokio::time::sleep(self.retry_backoff(attempt)).await;
+            }
+            attempt += 1;
+            let finishes = unfinished.iter().map(|(group, positions)| {
+                let body = Body::TxnFinish {
+                    txn_id,
+                    commit,
+                    keys: positions
+                        .iter()
+                        .map(|position| txn[*position].key().clone())
+                        .collect(),
+                };
+                self.call_group(**group, body)
+            });
+            let finished = futures::future::join_all(finishes).await;
+            unfinished = unfinished
+                .into_iter()
+                .zip(finished)
+                .filter(|(_, reply)| !matches!(reply, Some(Body::TxnFinishOk { .. })))
+                .map(|(group, _)| group)
+                .collect();
+        }
+
+        let body = if !commit {
+            Body::error(in_reply_to, ErrorCode::TxnConflict, "txn aborted")
+        } else if !unfinished.is_empty() {
+            // committed, but whether its writes show yet depends on recovery.
+            Body::error(
+                in_reply_to,
+                ErrorCode::Timeout,
+                "couldn't apply the txn's writes in every group",
+            )
+        } else {
+            Body::TxnOk {
+                in_reply_to,
+                txn: completed,
+            }
+        };
+        self.node.clone().reply(&msg, body).await;
+
+        // no group holds a lock of the txn anymore, so no one looks its decision up.
+        if self.config.compact_after.is_some() && unfinished.is_empty() {
+            let forget = Body::TxnForget { txn_id };
+            if self.call_group(decision_group, forget).await.is_none() {
+                tracing::debug!("group {decision_group} didn't forget txn {txn_id}");
+            }
+        }
+    }
+
+    /// Reads every client key in [from, to). The keys to read come from enough of each
+    /// group's replicas that every key written before the scan started is among them.
+    async fn scan(self: Arc<Self>, msg: Message, from: Key, to: Key) {
+        let in_reply_to = msg.body.msg_id;
+        let values = match self.scan_keys(&from, &to).await {
+            Some(keys) => self.read_keys(keys).await,
+            None => Err(ErrorCode::Timeout),
+        };
+        let body = match values {
+            Ok(values) => Body::ScanOk {
+                in_reply_to,
+                values,
+            },
+            Err(code) => Body::error(in_reply_to, code, code.to_string()),
+        };
+        self.node.clone().reply(&msg, body).await;
+    }
+
+    /// The client keys in [from, to) stored by `ClusterInfo::key_holders_needed`
+    /// replicas of each group, sorted, or None if some group doesn't have that many
+    /// answer by the client deadline.
+    async fn scan_keys(&self, from: &Key, to: &Key) -> Option<Vec<Key>> {
+        let cluster = self.node.cluster();
+        let mut needed: Vec<usize> = (0..cluster.group_count())
+            .map(|group| cluster.key_holders_needed(group))
+            .collect();
+        let mut calls: FuturesUnordered<_> = (0..cluster.group_count())
+            .flat_map(|group| {
+                cluster
+                    .group_members(group)
+                    .iter()
+                    .filter(|member| !cluster.is_witness(member))
+                    .map(move |member| (group, member))
+            })
+            .map(|(group, member)| async move {
+                let body = Body::ScanKeys {
+                    from: from.clone(),
+                    to: to.clone(),
+                };
+                (group, self.call(member, body).await)
+            })
+            .collect();
+        let mut keys = BTreeSet::new();
+        while needed.iter().any(|needed| *needed > 0) {
+            let (group, reply) = calls.next().await?;
+            if let Some(Body::ScanKeysOk { keys: theirs, .. }) = reply {
+                keys.extend(theirs);
+                needed[group] = needed[group].saturating_sub(1);
+            }
+        }
+        Some(keys.into_iter().collect())
+    }
+
+    /// Reads each of `keys` from its group, leaving out the keys that don't exist.
+    async fn read_keys(&self, keys: Vec<Key>) -> Result<Vec<(Key, Value)>, ErrorCode> {
+        let cluster = self.node.cluster();
+        let reads = keys.iter().map(|key| {
+            let body = Body::Read { key: key.clone() };
+            self.call_group(cluster.group_of_key(key), body)
+        });
+        let replies = futures::future::join_all(reads).await;
+        let mut values = Vec::new();
+        for (key, reply) in keys.into_iter().zip(replies) {
+            match reply {
+                Some(Body::ReadOk { value, .. }) => values.push((key, value)),
+                Some(Body::Error {
+                    code: ErrorCode::KeyDoesNotExist,
+                    ..
+                }) => {}
+                Some(Body::Error { code, .. }) => return Err(code),
+                _ => return Err(ErrorCode::Timeout),
+            }
+        }
+        Ok(values)
+    }
+
+    /// Sets `key` to what `update` makes of its value, None if it doesn't exist, with
+    /// a CAS from the value read, reading and trying again while CASes lose to other
+    /// writes, until the client's deadline. Anything else failing the CAS,
+    /// including it timing out, fails the update, since a retry could apply it twice.
+    async fn update_with_cas(
+        &self,
+        key: Key,
+        update: impl Fn(Option<&Value>) -> Result<Value, ErrorCode>,
+    ) -> Result<Value, ErrorCode> {
+        let group = self.node.cluster().group_of_key(&key);
+        let deadline = Instant::now() + self.config.client_deadline;
+        let mut attempt = 0;
+        while Instant::now() < deadline {
+            if attempt > 0 {
+                tokio::time::sleep(self.retry_backoff(attempt)).await;
+            }
+            attempt += 1;
+            let current = self
+                .read_keys(vec![key.clone()])
+                .await?
+                .pop()
+                .map(|(_, value)| value);
+            let to = update(current.as_ref())?;
+            // with no value to CAS from, create the key, which fails if it exists by now.
+            let body = Body::Cas {
+                key: key.clone(),
+                from: current.unwrap_or(Value::Null),
+                to: to.clone(),
+                create_if_not_exists: true,
+            };
+            match self.call_group(group, body).await {
+                Some(Body::CasOk { .. }) => return Ok(to),
+                Some(Body::Error {
+                    code: ErrorCode::PreconditionFailed,
+                    ..
+                }) => tracing::debug!(%key, "CAS lost to another write, retrying"),
+                Some(Body::Error { code, .. }) => return Err(code),
+                _ => return Err(ErrorCode::Timeout),
+            }
+        }
+        Err(ErrorCode::Timeout)
+    }
+
+    /// Prepares our group's part of a txn, with a round on each of its keys. Keys
+    /// are prepared independently, so some may end up locked when others fail, which
+    /// the coordinator's abort then releases.
+    async fn prepare_txn(
+        self: Arc<Self>,
+        msg: Message,
+        txn_id: usize,
+        txn: Vec<TxnOp>,
+    ) -> Result<(), ConsensusGone> {
+        let mut positions_by_key: BTreeMap<&Key, Vec<usize>> = BTreeMap::new();
+        for (position, op) in txn.iter().enumerate() {
+            positions_by_key.entry(op.key()).or_default().push(position);
+        }
+
+        let rounds = positions_by_key.values().map(|positions| {
+            let body = Body::TxnPrepare {
+                txn_id,
+                txn: positions
+                    .iter()
+                    .map(|position| txn[*position].clone())
+                    .collect(),
+            };
+            self.clone().propose_locally(body)
+        });
+        let prepared = futures::future::join_all(rounds).await;
+        let mut completed = txn.clone();
+        let mut error = None;
+        for (positions, result) in positions_by_key.values().zip(prepared) {
+            match result? {
+                Some(Body::TxnPrepareOk { txn, .. }) => {
+                    for (position, op) in positions.iter().zip(txn) {
+                        completed[*position] = op;
+                    }
+                }
+                Some(body @ Body::Error { .. }) => error = Some(body),
+                // the coordinator takes no reply as a failure to prepare.
+                _ => return Ok(()),
+            }
+        }
+
+        let body = error.unwrap_or(Body::TxnPrepareOk {
+            in_reply_to: msg.body.msg_id,
+            txn: completed,
+        });
+        self.node.clone().reply(&msg, body).await;
+        Ok(())
+    }
+
+    /// Finishes our group's part of a txn, with a round on each of its keys.
+    async fn finish_txn(
+        self: Arc<Self>,
+        msg: Message,
+        txn_id: usize,
+        commit: bool,
+        keys: Vec<Key>,
+    ) -> Result<(), ConsensusGone> {
+        let rounds = keys.into_iter().map(|key| {
+            let body = Body::TxnFinish {
+                txn_id,
+                commit,
+                keys: vec![key],
+            };
+            self.clone().propose_locally(body)
+        });
+        let finished = futures::future::join_all(rounds)
+            .await
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()?;
+        // a round that timed out may not have released its key, so no reply is sent,
+        // which the coordinator takes as the finish failing.
+        if finished
+            .iter()
+            .all(|result| matches!(result, Some(Body::TxnFinishOk { .. })))
+        {
+            let body = Body::TxnFinishOk {
+                in_reply_to: msg.body.msg_id,
+            };
+            self.node.clone().reply(&msg, body).await;
+        }
+        Ok(())
+    }
+
+    /// Finishes the txns whose locks have been held in our group for too long,
+    /// presumably because their coordinator went away.
+    async fn txn_recovery_loop(self: Arc<Self>) -> Result<(), ConsensusGone> {
+        // when each (txn, key) lock was first seen
+        let mut first_seen: HashMap<(usize, Key), Instant> = HashMap::new();
+        // members of the group wait their turn, so that one node at a time recovers a txn.
+        let cluster = self.node.cluster();
+        let position_in_group = cluster
+            .group_members(cluster.my_group())
+            .iter()
+            .position(|member| *member == cluster.my_id)
+            .unwrap() as u32;
+        let in_doubt_after = TXN_IN_DOUBT_AFTER * (position_in_group + 1);
+        loop {
+            tokio::time::sleep(TXN_RECOVERY_INTERVAL).await;
+
+            let locks = self
+                .consensus
+                .run(|consensus| txn::locks(&consensus.state_machine))
+                .await?;
+            first_seen.retain(|lock, _| locks.contains(lock));
+            let mut in_doubt: BTreeMap<usize, Vec<Key>> = BTreeMap::new();
+            for lock in locks {
+                let seen_at = first_seen.entry(lock.clone()).or_insert_with(Instant::now);
+                if seen_at.elapsed() >= in_doubt_after {
+                    let (txn_id, key) = lock;
+                    in_doubt.entry(txn_id).or_default().push(key);
+                }
+            }
+
+            for (txn_id, keys) in in_doubt {
+                tokio::spawn(self.clone().recover_txn(txn_id, keys));
+            }
+        }
+    }
+
+    async fn recover_txn(self: Arc<Self>, txn_id: usize, keys: Vec<Key>) {
+        tracing::info!("recovering in-doubt txn {txn_id} holding keys {keys:?}");
+        let cluster = self.node.cluster();
+        // aborts the txn, unless its coordinator got to decide it first.
+        let decision = Body::TxnDecide {
+            txn_id,
+            commit: false,
+        };
+        let decision_group = cluster.group_of_key(&txn::decision_key(txn_id));
+        if let Some(Body::TxnDecideOk { commit, .. }) =
+            self.call_group(decision_group, decision).await
+        {
+            let body = Body::TxnFinish {
+                txn_id,
+                commit,
+                keys,
+            };
+            self.call_group(cluster.my_group(), body).await;
+        }
+    }
+
+    /// Every COMPACTION_INTERVAL, runs a round on each key whose tombstone is older
+    /// than `compact_after`, which drops it. Like any change to a key's state, that's
+    /// only done by a round, so that every replica drops it, and tombstones age by the
+    /// clock of their instance, as the round goes by that one too. Only the node whose
+    /// round the instance last accepted runs it, unless that node went silent, so that
+    /// replicas don't all start the same rounds and reject each other's ballots.
+    async fn compaction_loop(
+        self: Arc<Self>,
+        compact_after: Duration,
+    ) -> Result<(), ConsensusGone> {
+        let compact_after = compact_after.as_millis() as u64;
+        loop {
+            tokio::time::sleep(COMPACTION_INTERVAL).await;
+            let local_clock = self.clock.now();
+            let cluster = self.node.cluster();
+            let this = self.clone();
+            let keys = self
+                .consensus
+                .run(move |consensus| {
+                    let state_machine = &consensus.state_machine;
+                    let due: BTreeSet<Key> = state_machine
+                        .tombstones()
+                        .map(|(key, buried_at)| (txn::base_key(key), buried_at))
+                        .filter(|(base_key, buried_at)| {
+                            let now = expiry::now(state_machine, base_key, local_clock);
+                            *buried_at < now.saturating_sub(compact_after)
+                        })
+                        .map(|(base_key, _)| base_key.clone())
+                        .collect();
+                    let my_index = this.node.cluster().my_index;
+                    due.into_iter()
+                        .filter(|key| {
+                            let Some(winner) = consensus
+                                .instances
+                                .get(key)
+                                .filter(|instance| !instance.accepted.is_zero())
+                                .map(|instance| instance.accepted.1)
+                            else {
+                                return true;
+                            };
+                            winner == my_index || this.node.is_suspected(&this.node.node_id(winner))
+                        })
+                        .collect::<Vec<_>>()
+                })
+                .await?;
+            for key in keys {
+                let op = Message {
+                    src: cluster.my_id.clone(),
+                    dest: cluster.my_id.clone(),
+                    body: BodyWithMsgId {
+                        msg_id: self.node.reserve_next_msg_id(),
+                        resent: false,
+                        inner: Body::ForcePropose { key },
+                    },
+                };
+                self.clone().propose(op).await?;
+            }
+        }
+    }
+
+    /// Replicas whose keys were accepted at the same ballots must hold the same state.
+    /// When a peer's doesn't match ours, a new round on each key makes every replica
+    /// adopt a single state again.
+    async fn check_divergence(
+        self: Arc<Self>,
+        peer: NodeIndex,
+        peer_ballots_digest: u64,
+        peer_state_digest: u64,
+    ) -> Result<(), ConsensusGone> {
+        if self.config.divergence_check == DivergenceCheck::Off {
+            return Ok(());
+        }
+        let (ballots_digest, state_digest) = self.consensus.digests().await?;
+        let is_diverging =
+            ballots_digest == peer_ballots_digest && state_digest != peer_state_digest;
+        if !is_diverging {
+            return Ok(());
+        }
+
+        tracing::error!(
+            target: "divergence",
+            peer = self.node.node_id(peer),
+            ballots_digest,
+            state_digest,
+            peer_state_digest,
+        );
+        if self.config.divergence_check == DivergenceCheck::Diff {
+            // the peer's loop, which this runs on, keeps going meanwhile.
+            spawn_logged(self.clone().log_diverging_keys(peer));
+        }
+        // only one of the two replicas runs the rounds.
+        let cluster = self.node.cluster();
+        if cluster.my_index < peer {
+            let keys = self
+                .consensus
+                .filter_map(|key, instance| (!instance.accepted.is_zero()).then(|| key.clone()))
+                .await?;
+            for key in keys {
+                let op = Message {
+                    src: cluster.my_id.clone(),
+                    dest: cluster.my_id.clone(),
+                    body: BodyWithMsgId {
+                        msg_id: self.node.reserve_next_msg_id(),
+                        resent: false,
+                        inner: Body::ForcePropose { key },
+                    },
+                };
+                self.clone().propose(op).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Logs each key that was accepted at the same ballot by `peer` and us, but with
+    /// a different state.
+    async fn log_diverging_keys(self: Arc<Self>, peer: NodeIndex) -> Result<(), ConsensusGone> {
+        let peer_id = self.node.node_id(peer);
+        let Some(Body::InstanceDigestsOk { digests, .. }) =
+            self.call(&peer_id, Body::InstanceDigests {}).await
+        else {
+            tracing::debug!("{peer_id} didn't send its instance digests");
+            return Ok(());
+        };
+
+        // our digest and value of each key that diverges, read by a single command
+        let diverging = self
+            .consensus
+            .run(move |consensus| {
+                digests
+                    .into_iter()
+                    .filter_map(|(key, ballot_number, peer_digest)| {
+                        let instance = consensus.instances.get(&key)?;
+                        let digest = instance.value_digest;
+                        if instance.accepted != ballot_number || digest == peer_digest {
+                            return None;
+                        }
+                        let value = consensus.state_machine.read(&key).cloned();
+                        Some((key, ballot_number, digest, peer_digest, value))
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .await?;
+        for (key, ballot_number, digest, peer_digest, value) in diverging {
+            tracing::error!(
+                target: "divergence",
+                peer = peer_id.as_str(),
+                %key,
+                %ballot_number,
+                digest,
+                peer_digest,
+                ?value,
+            );
+        }
+        Ok(())
+    }
+
+    /// Every SYNC_INTERVAL, asks a random full replica for its accepted ballots, and
+    /// pulls the state of the keys it accepted at greater ballots than we did.
+    async fn sync_loop(self: Arc<Self>) -> Result<(), ConsensusGone> {
+        loop {
+            tokio::time::sleep(SYNC_INTERVAL).await;
+            let replicas = self.node.cluster().other_replica_ids();
+            if replicas.is_empty() {
+                continue;
+            }
+            let peer = &replicas[rng::with_rng(|rng| rng.random_range(0..replicas.len()))];
+
+            let Some(Body::InstanceDigestsOk { digests, .. }) =
+                self.call(peer, Body::InstanceDigests {}).await
+            else {
+                continue;
+            };
+            let behind: Vec<Key> = self
+                .consensus
+                .run(move |consensus| {
+                    digests
+                        .into_iter()
+                        .filter(|(key, ballot_number, _)| {
+                            consensus
+                                .instances
+                                .get(key)
+                                .is_none_or(|instance| instance.accepted < *ballot_number)
+                        })
+                        .map(|(key, _, _)| key)
+                        .collect()
+                })
+                .await?;
+            if behind.is_empty() {
+                continue;
+            }
+
+            let Some(Body::SyncStateOk { instances, .. }) =
+                self.call(peer, Body::SyncState { keys: behind }).await
+            else {
+                continue;
+            };
+            for (key, ballot_number, state) in instances {
+                self.catch_up(&key, ballot_number, state).await?;
+            }
+        }
+    }
+
+    /// Accepts `state` at `ballot_number`, pulled from a peer, as if its Accept msg
+    /// had reached us. Like that msg, it's only taken while our promises allow it.
+    async fn catch_up(
+        self: &Arc<Self>,
+        key: &Key,
+        ballot_number: BallotNumber,
+        state: InstanceState,
+    ) -> Result<(), ConsensusGone> {
+        let this = self.clone();
+        self.consensus
+            .with(key, move |instance| {
+                let is_newer =
+                    instance.accepted < ballot_number && instance.promised <= ballot_number;
+                if !is_newer || !matches!(instance.role, Role::Acceptor) {
+                    return;
+                }
+                let key = instance.key.clone();
+                tracing::info!("caught up on key {key}, accepted at {ballot_number} by a peer");
+                instance.consensus.replace_instance_state(&key, &state);
+                this.adopt_members(&key, &state);
+                instance.promised = ballot_number;
+                instance.set_accepted(ballot_number, state.digest());
+            })
+            .await
+    }
+
+    /// Sends `body` to `peer` and waits for its reply, up to the client deadline.
+    async fn call(&self, peer: &str, body: Body) -> Option<Body> {
+        self.node
+            .clone()
+            .rpc(peer, body, self.config.client_deadline)
+            .await
+    }
+
+    /// (key, accepted ballot, state digest) of each key accepted so far, by key.
+    async fn instance_digests(&self) -> Result<Vec<(Key, BallotNumber, u64)>, ConsensusGone> {
+        let mut digests = self
+            .consensus
+            .filter_map(|key, instance| {
+                (!instance.accepted.is_zero())
+                    .then(|| (key.clone(), instance.accepted, instance.value_digest))
+            })
+            .await?;
+        digests.sort_unstable();
+        Ok(digests)
+    }
 
-                match result {
+    async fn heartbeat_loop(self: Arc<Self>) -> Result<(), ConsensusGone> {
+        let mut had_quorum = true;
+        loop {
+            tokio::time::sleep(HEARTBEAT_INTERVAL).await;
+            let (ballots_digest, state_digest) = self.consensus.digests().await?;
+            let body = Body::Heartbeat {
+                ballots_digest,
+                state_digest,
+            };
+            self.node.clone().broadcast(body, None).await;
+
+            // once a quorum is back, share what was written without one,
+            // so that whoever proposes next folds all of it into the store.
+            let has_quorum = self.node.has_quorum();
+            if has_quorum && !had_quorum {
+                self.broadcast_overlay().await?;
+            }
+            had_quorum = has_quorum;
+        }
+    }
+
+    async fn broadcast_overlay(&self) -> Result<(), ConsensusGone> {
+        let registers = self
+            .consensus
+            .run(|consensus| consensus.lww_overlay.clone())
+            .await?;
+        if !registers.is_empty() {
+            let body = Body::LwwMerge { registers };
+            self.node.clone().broadcast(body, None).await;
+        }
+        Ok(())
+    }
+
+    /// Whether a client op takes the LWW path, either because of its key's policy
+    /// or because it can't reach a quorum in CRDT fallback mode. Deletes, expiring
+    /// writes and versioned ops, as LWW registers keep no versions, never do.
+    fn serves_from_overlay(&self, msg: &Message) -> bool {
+        if matches!(
+            msg.body.inner,
+            Body::Delete { .. }
+                | Body::CasDelete { .. }
+                | Body::ReadVersion { .. }
+                | Body::CasVersion { .. }
+                | Body::Write {
+                    expiry_ms: Some(_),
+                    ..
+                }
+        ) {
+            return false;
+        }
+        let is_lww_key = msg
+            .body
+            .inner
+            .key()
+            .is_some_and(|key| self.config.is_lww_key(key));
+        is_lww_key || (self.config.crdt_fallback && !self.node.has_quorum())
+    }
+
+    /// Handles a client op against the LWW overlay, without running a CASPaxos round.
+    async fn serve_from_overlay(&self, msg: Message) -> Result<(), ConsensusGone> {
+        let in_reply_to = msg.body.msg_id;
+        let error = move |code: ErrorCode| Body::error(in_reply_to, code, code.to_string());
+        let me = self.node.cluster().my_index;
+
+        let op = msg.body.inner.clone();
+        let body = self
+            .consensus
+            .run(move |consensus| match op {
+                Body::Read { key } => match consensus.current(&key) {
                     Some(value) => Body::ReadOk {
-                        in_reply_to: msg.body.msg_id,
-                        value: *value,
+                        in_reply_to,
+                        value,
+                        ballot_number: None,
                     },
-                    None => {
-                        let err = ErrorCode::KeyDoesNotExist;
-                        Body::Error {
-                            in_reply_to: msg.body.msg_id,
-                            code: err.clone(),
-                            text: err.to_string(),
-                        }
+                    None => error(ErrorCode::KeyDoesNotExist),
+                },
+                Body::Write {
+                    key,
+                    value,
+                    create_if_not_exists,
+                    ..
+                } => match consensus.current(&key) {
+                    Some(_) if create_if_not_exists => error(ErrorCode::KeyAlreadyExists),
+                    _ => {
+                        consensus.lww_overlay.set(key, value, me);
+                        Body::WriteOk { in_reply_to }
                     }
-                }
+                },
+                Body::Cas {
+                    key,
+                    from,
+                    to,
+                    create_if_not_exists,
+                } => match consensus.current(&key) {
+                    Some(value) if value == from => {
+                        consensus.lww_overlay.set(key, to, me);
+                        Body::CasOk { in_reply_to }
+                    }
+                    Some(_) => error(ErrorCode::PreconditionFailed),
+                    None if create_if_not_exists => {
+                        consensus.lww_overlay.set(key, to, me);
+                        Body::CasOk { in_reply_to }
+                    }
+                    None => error(ErrorCode::KeyDoesNotExist),
+                },
+                _ => unreachable!("only client ops are served from the overlay"),
+            })
+            .await?;
+
+        if matches!(body, Body::WriteOk { .. } | Body::CasOk { .. }) {
+            self.broadcast_overlay().await?;
+        }
+        self.node.clone().reply(&msg, body).await;
+        Ok(())
+    }
+
+    /// Emits a `decision` event for the value chosen at `ballot_number`, and adds
+    /// it to the changelog.
+    fn audit_decision(
+        &self,
+        key: Option<&Key>,
+        ballot_number: BallotNumber,
+        quorum: AcceptanceInbox,
+        value_digest: u64,
+        consensus: &mut Consensus,
+    ) {
+        let quorum: Vec<String> = quorum
+            .members()
+            .map(|index| self.node.node_id(index))
+            .collect();
+        tracing::info!(
+            target: "decision",
+            key = key.map(tracing::field::display),
+            %ballot_number,
+            proposer = self.node.cluster().my_id.as_str(),
+            quorum = ?quorum,
+            value_digest,
+        );
+
+        if let Some(key) = key {
+            let value = consensus.state_machine.read(key).cloned();
+            consensus
+                .changelog
+                .record(key.clone(), value, ballot_number);
+        }
+    }
+
+    /// Replaces our role in `key`'s instance, emitting a `role_transition` event whenever
+    /// its kind changes or a new round starts, so that the cluster's timeline can be
+    /// rebuilt from the logs.
+    fn transition(&self, key: &Key, instance: &mut Instance, new_role: Role, trigger: &str) {
+        let role = &instance.role;
+        if role.name() != new_role.name() || role.ballot_number() != new_role.ballot_number() {
+            tracing::info!(
+                target: "role_transition",
+                node = self.node.cluster().my_id.as_str(),
+                %key,
+                from = role.name(),
+                to = new_role.name(),
+                ballot_number = new_role.ballot_number().map(tracing::field::display),
+                highest_known_ballot_number = %instance.promised,
+                trigger,
+            );
+        }
+        instance.role = new_role;
+    }
+
+    /// Why the cluster Init told us about can't run our rounds, if it can't. The
+    /// flags only tell the group size with --group-size, see `Config::from_args`.
+    fn cluster_error(&self) -> Option<String> {
+        let cluster = self.node.cluster();
+        if cluster.size() > MAX_NODES {
+            Some(format!(
+                "acceptance bitmaps can't track more than {MAX_NODES} nodes"
+            ))
+        } else if !cluster.quorums_intersect() {
+            Some(format!(
+                "prepare and accept quorums of {} and {} nodes should add up to more than \
+                 the group size",
+                cluster.prepare_quorum, cluster.accept_quorum
+            ))
+        } else {
+            None
+        }
+    }
+
+    async fn is_saturated(&self) -> Result<bool, ConsensusGone> {
+        let in_flight_proposals = self
+            .consensus
+            .run(|consensus| consensus.in_flight_proposals.len())
+            .await?;
+        Ok(in_flight_proposals >= self.max_in_flight_proposals()
+            || self.node.inbound_depth() >= MAX_INBOUND_QUEUE_DEPTH
+            || self.memory_usage().await?.total() >= self.config.memory_limit_bytes)
+    }
+
+    fn max_in_flight_proposals(&self) -> usize {
+        match self.node.since_reconnect() {
+            Some(elapsed) if elapsed < SLOW_START_WINDOW => {
+                let ramp = elapsed.as_secs_f64() / SLOW_START_WINDOW.as_secs_f64();
+                1 + ((MAX_IN_FLIGHT_PROPOSALS - 1) as f64 * ramp) as usize
             }
-            Body::Write { key, value } => {
-                state_machine.write(key, value);
-                Body::WriteOk {
-                    in_reply_to: msg.body.msg_id,
+            _ => MAX_IN_FLIGHT_PROPOSALS,
+        }
+    }
+
+    /// Sends `messages` to each of our neighbors but `from`, who already has them,
+    /// again and again until the neighbor acks them.
+    fn gossip(self: &Arc<Self>, messages: Vec<u64>, from: &str) {
+        if messages.is_empty() {
+            return;
+        }
+        for neighbor in self.broadcast.neighbors() {
+            if neighbor == from {
+                continue;
+            }
+            let cas_paxos = self.clone();
+            let messages = messages.clone();
+            tokio::spawn(async move {
+                let body = Body::Gossip { messages };
+                while !matches!(
+                    cas_paxos.call(&neighbor, body.clone()).await,
+                    Some(Body::GossipOk { .. })
+                ) {
+                    tracing::debug!(neighbor, "retrying unacked gossip");
                 }
+            });
+        }
+    }
+
+    /// An id no other call on any node returns: our node id, which no other node has,
+    /// and a counter of ours. Needs no messages, so ids keep coming during partitions.
+    /// The counter isn't persisted, so a node restarted under the same id would repeat ids.
+    fn generate_id(&self) -> String {
+        let counter = self.next_generated_id.fetch_add(1, Ordering::Relaxed);
+        format!("{}-{counter}", self.node.cluster().my_id)
+    }
+
+    async fn health(&self) -> Result<Health, ConsensusGone> {
+        let (instances, store_size) = self
+            .consensus
+            .run(|consensus| {
+                let instances: Vec<(&'static str, BallotNumber)> = consensus
+                    .instances
+                    .values()
+                    .map(|instance| (instance.role.name(), instance.promised))
+                    .collect();
+                (instances, consensus.state_machine.len())
+            })
+            .await?;
+        // we're a proposer as long as we run a round on any key.
+        let mut role = Role::Acceptor.name();
+        let mut highest_known_ballot_number = BallotNumber::ZERO;
+        for (instance_role, promised) in instances {
+            if instance_role != Role::Acceptor.name() {
+                role = instance_role;
             }
-            Body::Cas { key, from, to } => {
-                let result = state_machine.cas(key, from, to);
+            highest_known_ballot_number = highest_known_ballot_number.max(promised);
+        }
+        Ok(Health {
+            role: role.to_string(),
+            highest_known_ballot_number,
+            peers_last_heard_ms: self
+                .node
+                .peers_last_heard()
+                .into_iter()
+                .map(|(peer, last_heard)| {
+                    (peer, last_heard.map(|elapsed| elapsed.as_millis() as u64))
+                })
+                .collect(),
+            inbound_queue_depth: self.node.inbound_depth(),
+            outbound_queue_depth: self.node.outbound_depth(),
+            queued_client_ops: self.queued_client_ops.load(Ordering::SeqCst),
+            store_size,
+        })
+    }
 
-                match result {
-                    Ok(()) => Body::CasOk {
-                        in_reply_to: msg.body.msg_id,
+    async fn memory_usage(&self) -> Result<MemoryUsage, ConsensusGone> {
+        let (state_machine, in_flight_proposals) = self
+            .consensus
+            .run(|consensus| {
+                let in_flight_requests =
+                    consensus
+                        .in_flight_proposals
+                        .keys()
+                        .fold(0, |size, client| {
+                            size + client.src.capacity()
+                                + size_of::<(ClientEnvelope, InFlightProposal)>()
+                        });
+                // the promise each round builds on holds a copy of the promiser's state for the key.
+                let promises: usize = consensus
+                    .instances
+                    .values()
+                    .filter_map(|instance| match &instance.role {
+                        Role::Proposer { promises_inbox, .. } => promises_inbox
+                            .highest()
+                            .map(|(_, _, state)| state.approximate_size_bytes()),
+                        Role::Acceptor => None,
+                    })
+                    .sum();
+                (
+                    consensus.state_machine.approximate_size_bytes(),
+                    in_flight_requests + promises,
+                )
+            })
+            .await?;
+        let queued_msgs = self.node.inbound_depth() + self.node.outbound_depth();
+
+        Ok(MemoryUsage {
+            state_machine,
+            in_flight_proposals,
+            queues: queued_msgs * size_of::<Message>(),
+        })
+    }
+
+    /// Runs consensus among the members in `state` from now on, if it's the state of
+    /// the members register's instance. Acceptors adopt a value as they accept it,
+    /// while a proposer waits for the round to be decided, so that the round changing
+    /// the members still runs among the old ones.
+    fn adopt_members(&self, key: &Key, state: &InstanceState) {
+        let Some(members) = state
+            .read(&membership::MEMBERSHIP_KEY)
+            .and_then(Value::as_u64)
+            .filter(|_| *key == membership::MEMBERSHIP_KEY)
+        else {
+            return;
+        };
+        let members = membership::decode(members as usize);
+        if self.node.set_members(&members) {
+            tracing::info!("cluster members are now {members:?}");
+        }
+    }
+
+    fn is_witness(&self) -> bool {
+        let cluster = self.node.cluster();
+        cluster.is_witness(&cluster.my_id)
+    }
+
+    /// The nodes taking part in consensus, ourselves included, ordered by node number
+    /// like the members register is.
+    fn members(&self) -> Vec<String> {
+        let cluster = self.node.cluster();
+        let mut members: Vec<String> = cluster
+            .other_node_ids
+            .iter()
+            .chain([&cluster.my_id])
+            .cloned()
+            .collect();
+        members.sort_by_key(|id| membership::node_number(id));
+        members
+    }
+
+    fn apply_to_state_machine(
+        self: Arc<Self>,
+        msg: &Message,
+        ballot_number: BallotNumber,
+        state_machine: &mut KeyValueStore<Key, Value>,
+    ) -> Body {
+        let base_key = instance_key(&msg.body.inner);
+        let now = expiry::now(state_machine, &base_key, self.clock.now());
+        let sets_expiry = matches!(
+            msg.body.inner,
+            Body::Write {
+                expiry_ms: Some(_),
+                ..
+            }
+        );
+        if state_machine.expire(now) || sets_expiry {
+            expiry::advance(state_machine, &base_key, now);
+        }
+        // tombstones go by the instance's clock too, so that every replica drops the
+        // same ones, along with the state they accept.
+        if let Some(compact_after) = self.config.compact_after {
+            state_machine.compact(now.saturating_sub(compact_after.as_millis() as u64));
+        }
+        match &msg.body.inner {
+            Body::Read { .. }
+            | Body::Write { .. }
+            | Body::Cas { .. }
+            | Body::Delete { .. }
+            | Body::CasDelete { .. }
+            | Body::ReadVersion { .. }
+            | Body::CasVersion { .. } => {
+                let in_reply_to = msg.body.msg_id;
+                if matches!(msg.body.inner, Body::Read { .. }) {
+                    self.stats.record_quorum_read();
+                }
+                let result = match self.config.workload {
+                    Workload::KeyValue
+                    | Workload::Broadcast
+                    | Workload::Counter
+                    | Workload::LinKvProxy => {
+                        state_machine.apply(&msg.body.inner, in_reply_to, now)
+                    }
+                    Workload::Register => match state_machine
+                        .read(&REGISTER_KEY)
+                        .map(Register::restore)
+                        .transpose()
+                    {
+                        Ok(register) => {
+                            let mut register = register.unwrap_or_default();
+                            let before = register.clone();
+                            let result = register.apply(&msg.body.inner, in_reply_to, now);
+                            if register != before {
+                                state_machine.write(REGISTER_KEY, register.snapshot());
+                            }
+                            result
+                        }
+                        // e.g. a snapshot of a KV store was restored, leaving the op
+                        // nothing it can run on.
+                        Err(error) => {
+                            tracing::error!("the register's key holds no register: {error}");
+                            Err(ErrorCode::Abort)
+                        }
                     },
-                    Err(e) => match e.downcast_ref::<ErrorCode>() {
-                        Some(e @ ErrorCode::PreconditionFailed)
-                        | Some(e @ ErrorCode::KeyDoesNotExist) => Body::Error {
-                            in_reply_to: msg.body.msg_id,
-                            code: e.clone(),
-                            text: e.to_string(),
-                        },
-                        _ => panic!("encountered an unexpected error while processing Cas request"),
+                };
+
+                match result {
+                    Ok(Body::ReadOk { value, .. }) => Body::ReadOk {
+                        in_reply_to,
+                        value,
+                        ballot_number: self.config.debug_read_ballots.then_some(ballot_number),
                     },
+                    Ok(body) => body,
+                    Err(code) => Body::error(in_reply_to, code, code.to_string()),
+                }
+            }
+            Body::ForcePropose { .. } => Body::ForceProposeOk {
+                in_reply_to: msg.body.msg_id,
+            },
+            Body::AddNode { ref node_id } | Body::RemoveNode { ref node_id } => {
+                // the register is only ever unset while every node still has Init's members
+                let members = state_machine
+                    .read(&membership::MEMBERSHIP_KEY)
+                    .and_then(Value::as_u64)
+                    .map(|members| members as usize)
+                    .or_else(|| membership::encode(&self.members()))
+                    .expect("handle only proposes membership changes for n<k> members");
+                let node = 1
+                    << membership::node_number(node_id)
+                        .expect("handle only proposes membership changes for n<k> ids");
+                let in_reply_to = msg.body.msg_id;
+                let (members, reply) = match msg.body.inner {
+                    Body::AddNode { .. } => (members | node, Body::AddNodeOk { in_reply_to }),
+                    _ => (members & !node, Body::RemoveNodeOk { in_reply_to }),
+                };
+                state_machine.write(membership::MEMBERSHIP_KEY, Value::from(members));
+                reply
+            }
+            Body::TxnPrepare { txn_id, txn } => match txn::prepare(state_machine, *txn_id, txn) {
+                Ok(txn) => Body::TxnPrepareOk {
+                    in_reply_to: msg.body.msg_id,
+                    txn,
+                },
+                Err(code) => Body::error(msg.body.msg_id, code, code.to_string()),
+            },
+            Body::TxnDecide { txn_id, commit } => Body::TxnDecideOk {
+                in_reply_to: msg.body.msg_id,
+                commit: txn::decide(state_machine, *txn_id, *commit),
+            },
+            Body::TxnFinish {
+                txn_id,
+                commit,
+                keys,
+            } => {
+                txn::finish(state_machine, *txn_id, *commit, keys);
+                Body::TxnFinishOk {
+                    in_reply_to: msg.body.msg_id,
+                }
+            }
+            Body::TxnForget { txn_id } => {
+                txn::forget(state_machine, *txn_id);
+                Body::TxnForgetOk {
+                    in_reply_to: msg.body.msg_id,
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{atomic::AtomicUsize, Mutex};
+
+    use serde_json::json;
+    use tokio::task::JoinHandle;
+
+    use super::*;
+    use crate::{
+        key::Tag,
+        sim::{self, Channel, Client, Faults, Network},
+        transport::Received,
+    };
+
+    /// A proposer running the round at `ballot_number`, before any promise came in.
+    fn proposer(ballot_number: BallotNumber) -> Role {
+        let op: Message = serde_json::from_str(
+            r#"{"src": "c1", "dest": "n1", "body": {"type": "read", "msg_id": 1, "key": 0}}"#,
+        )
+        .unwrap();
+        Role::Proposer {
+            client: ClientEnvelope::of(&op),
+            op: Box::new(op),
+            batched: Vec::new(),
+            ballot_number,
+            last_accept_broadcast: BallotNumber::ZERO,
+            last_client_confirmation: BallotNumber::ZERO,
+            promises_inbox: PromisesInbox::default(),
+            acceptance_inbox: AcceptanceInbox::default(),
+        }
+    }
+
+    fn no_state() -> KeyValueStore<Key, Value> {
+        KeyValueStore::new_with_inner(HashMap::new())
+    }
+
+    #[test]
+    fn promises_after_the_accept_broadcast_are_late() {
+        let ballot_number = BallotNumber(2, 0);
+        let mut role = proposer(ballot_number);
+        assert!(!role.is_late_promise(ballot_number));
+
+        role.set_last_accept_broadcast(ballot_number).unwrap();
+        assert!(role.is_late_promise(ballot_number));
+        // promises for other rounds aren't this round's to judge.
+        assert!(!role.is_late_promise(BallotNumber(2, 1)));
+        assert!(!Role::Acceptor.is_late_promise(ballot_number));
+    }
+
+    #[test]
+    fn accepteds_count_once_per_node_and_ballot() {
+        let ballot_number = BallotNumber(2, 0);
+        let mut inbox = AcceptanceInbox::default();
+        assert!(inbox.insert(1, ballot_number));
+        assert!(!inbox.insert(1, ballot_number));
+        // an older ballot's accepted says nothing about the current one.
+        assert!(!inbox.insert(2, BallotNumber(1, 2)));
+        assert_eq!(inbox.len(), 1);
+        assert!(inbox.insert(2, ballot_number));
+        assert_eq!(inbox.len(), 2);
+    }
+
+    #[test]
+    fn accepteds_of_other_rounds_are_ignored() {
+        let ballot_number = BallotNumber(2, 0);
+        let mut role = proposer(ballot_number);
+        assert!(!role.add_acceptance_to_inbox(1, BallotNumber(3, 0)).unwrap());
+        assert!(role.add_acceptance_to_inbox(1, ballot_number).unwrap());
+        assert!(!role.add_acceptance_to_inbox(1, ballot_number).unwrap());
+        assert_eq!(role.acceptance_inbox().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn equal_ballots_pass_and_lower_ones_dont() {
+        let mut instance = Instance::default();
+        let ballot_number = BallotNumber(2, 1);
+        assert_eq!(instance.observe(ballot_number), Ok(()));
+        // the same round's msgs carry the same ballot, from the proposer we promised.
+        assert_eq!(instance.observe(ballot_number), Ok(()));
+        assert_eq!(instance.observe(BallotNumber(2, 0)), Err(ballot_number));
+        assert_eq!(instance.promised, ballot_number);
+    }
+
+    #[test]
+    fn retransmitted_promises_count_once() {
+        let mut inbox = PromisesInbox::default();
+        inbox.insert(1, BallotNumber::ZERO, no_state());
+        inbox.insert(1, BallotNumber::ZERO, no_state());
+        assert_eq!(inbox.len(), 1);
+        inbox.insert(2, BallotNumber(1, 0), no_state());
+        assert_eq!(inbox.len(), 2);
+        assert_eq!(inbox.highest().unwrap().0, 2);
+    }
+
+    #[test]
+    fn promises_of_other_rounds_are_ignored() {
+        let ballot_number = BallotNumber(2, 0);
+        let mut role = proposer(ballot_number);
+        for other in [BallotNumber(1, 0), BallotNumber(2, 1), BallotNumber(3, 0)] {
+            let promises = role.add_promise_to_inbox(1, other, BallotNumber::ZERO, no_state());
+            assert_eq!(promises.unwrap(), 0);
+        }
+        let promises = role.add_promise_to_inbox(1, ballot_number, BallotNumber::ZERO, no_state());
+        assert_eq!(promises.unwrap(), 1);
+    }
+
+    const KEY: Key = Key::Int(1);
+    // How long a peer waits on n1 for a msg: long enough for n1 to get one out, and
+    // short of n1 suspecting the peers, which never heartbeat.
+    const WAIT: Duration = Duration::from_millis(50);
+
+    /// n1, a real node, among peers the test plays: they see what n1 sends them, and
+    /// send n1 whatever the test has them send, in whatever order.
+    struct Cluster {
+        client: Arc<Client>,
+        peers: Vec<Peer>,
+    }
+
+    impl Cluster {
+        async fn start(size: usize) -> Self {
+            Self::start_with(size, Config::default()).await
+        }
+
+        async fn start_with(size: usize, config: Config) -> Self {
+            let node_ids: Vec<String> = (1..=size).map(|i| format!("n{i}")).collect();
+            let network = Network::new(node_ids.clone(), Faults::default());
+            let client = Client::start(&network, config.client_deadline * 2);
+            let peers = node_ids[1..]
+                .iter()
+                .map(|node_id| Peer {
+                    node_id: node_id.clone(),
+                    channel: network.connect(node_id),
+                    next_msg_id: AtomicUsize::new(0),
+                    unread: Mutex::default(),
+                })
+                .collect();
+            let n1 = CASPaxos::new(config, Arc::new(network.connect("n1")));
+            tokio::spawn(Arc::new(n1).run());
+            let init = Body::Init {
+                node_id: "n1".into(),
+                node_ids,
+            };
+            let reply = client.call("n1", init).await;
+            assert!(matches!(reply, Some(Body::InitOk { .. })), "{reply:?}");
+            Self { client, peers }
+        }
+
+        fn peer(&self, node_id: &str) -> &Peer {
+            self.peers
+                .iter()
+                .find(|peer| peer.node_id == node_id)
+                .unwrap()
+        }
+
+        /// Sends `body` to n1 as a client op, for the test to await its reply when due.
+        fn call(&self, body: Body) -> JoinHandle<Option<Body>> {
+            let client = self.client.clone();
+            tokio::spawn(async move { client.call("n1", body).await })
+        }
+
+        fn write(&self, value: u64) -> JoinHandle<Option<Body>> {
+            self.call(Body::Write {
+                key: KEY,
+                value: json!(value),
+                create_if_not_exists: false,
+                expiry_ms: None,
+            })
+        }
+    }
+
+    struct Peer {
+        node_id: String,
+        channel: Channel,
+        next_msg_id: AtomicUsize,
+        // msgs of a batch n1 sent, not looked at yet.
+        unread: Mutex<VecDeque<Message>>,
+    }
+
+    impl Peer {
+        async fn next(&self, until: Instant) -> Option<Message> {
+            loop {
+                if let Some(msg) = self.unread.lock().unwrap().pop_front() {
+                    return Some(msg);
+                }
+                let Ok(Some(Received::Msg(msg))) =
+                    tokio::time::timeout_at(until, self.channel.recv()).await
+                else {
+                    return None;
+                };
+                match msg.body.inner {
+                    Body::Batch { msgs } => self.unread.lock().unwrap().extend(msgs),
+                    _ => return Some(msg),
+                }
+            }
+        }
+
+        /// The first msg n1 sends, that `matches` and isn't a resent copy. The ones
+        /// before it are dropped.
+        async fn expect(&self, what: &str, matches: impl Fn(&Body) -> bool) -> Message {
+            let until = Instant::now() + WAIT;
+            while let Some(msg) = self.next(until).await {
+                if !msg.body.resent && matches(&msg.body.inner) {
+                    return msg;
                 }
             }
+            panic!("{} got no {what}", self.node_id);
+        }
+
+        async fn expect_none(&self, what: &str, matches: impl Fn(&Body) -> bool) {
+            let until = Instant::now() + WAIT;
+            while let Some(msg) = self.next(until).await {
+                assert!(
+                    msg.body.resent || !matches(&msg.body.inner),
+                    "{} got {what}: {msg:?}",
+                    self.node_id
+                );
+            }
+        }
+
+        async fn send(&self, body: Body) {
+            let msg = Message {
+                src: self.node_id.clone(),
+                dest: "n1".into(),
+                body: BodyWithMsgId {
+                    msg_id: self.next_msg_id.fetch_add(1, Ordering::SeqCst),
+                    resent: false,
+                    inner: body,
+                },
+            };
+            self.channel.send(&msg).await;
+        }
+
+        /// Promises the ballot of `propose`, having accepted nothing yet.
+        async fn promise(&self, propose: &Message) {
+            self.send(Body::Promise {
+                in_reply_to: propose.body.msg_id,
+                key: KEY,
+                ballot_number: ballot_of(propose),
+                accepted_ballot_number: BallotNumber::ZERO,
+                value: KeyValueStore::new_with_inner(HashMap::new()),
+            })
+            .await;
+        }
+
+        async fn accepted(&self, accept: &Message, ballot_number: BallotNumber) {
+            self.send(Body::Accepted {
+                in_reply_to: accept.body.msg_id,
+                key: KEY,
+                ballot_number,
+            })
+            .await;
+        }
+    }
+
+    fn ballot_of(msg: &Message) -> BallotNumber {
+        match msg.body.inner {
+            Body::Propose { ballot_number, .. } | Body::Accept { ballot_number, .. } => {
+                ballot_number
+            }
+            _ => panic!("{msg:?} has no ballot"),
+        }
+    }
+
+    fn is_propose(body: &Body) -> bool {
+        matches!(body, Body::Propose { .. })
+    }
+
+    fn is_accept(body: &Body) -> bool {
+        matches!(body, Body::Accept { .. })
+    }
+
+    fn is_error(body: &Body) -> bool {
+        matches!(body, Body::Error { .. })
+    }
+
+    /// Has n2 and n3 promise n1's round on a write, which with n1's own promise makes
+    /// a quorum of five, returning the write, and the Propose and Accept each peer got.
+    async fn past_propose_phase(cluster: &Cluster) -> (JoinHandle<Option<Body>>, Round) {
+        let write = cluster.write(7);
+        let mut round = Round::default();
+        for peer in &cluster.peers {
+            round
+                .proposes
+                .push(peer.expect("propose", is_propose).await);
+        }
+        for (peer, propose) in cluster.peers.iter().zip(&round.proposes).take(2) {
+            peer.promise(propose).await;
+        }
+        for peer in &cluster.peers {
+            round.accepts.push(peer.expect("accept", is_accept).await);
+        }
+        (write, round)
+    }
+
+    #[derive(Default)]
+    struct Round {
+        proposes: Vec<Message>,
+        accepts: Vec<Message>,
+    }
+
+    #[test]
+    fn batches_grow_with_the_inbound_queue() {
+        assert_eq!(batch_limit(0.0), 0);
+        assert_eq!(batch_limit(0.01), 1);
+        assert_eq!(batch_limit(0.5), 32);
+        assert_eq!(batch_limit(1.0), MAX_BATCH_SIZE - 1);
+    }
+
+    #[tokio::test]
+    async fn commands_fail_once_the_consensus_task_is_gone() {
+        let consensus = ConsensusTask::spawn(Consensus::new(Changelog::new(1, None)));
+        let panicked = consensus.run(|_| -> u64 { panic!("a command panicked") });
+        assert_eq!(panicked.await, Err(ConsensusGone));
+        assert_eq!(consensus.digests().await, Err(ConsensusGone));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn duplicate_and_stale_accepteds_dont_decide() {
+        let cluster = Cluster::start(5).await;
+        let (write, Round { accepts, .. }) = past_propose_phase(&cluster).await;
+        let ballot_number = ballot_of(&accepts[0]);
+
+        // n1's acceptance and n2's, counted once, are two of the three needed.
+        let n2 = cluster.peer("n2");
+        n2.accepted(&accepts[0], ballot_number).await;
+        n2.accepted(&accepts[0], ballot_number).await;
+        // n3 accepted a round n1 had already given up on, which it isn't told about.
+        let n3 = cluster.peer("n3");
+        let stale = BallotNumber(ballot_number.0 - 1, 2);
+        n3.accepted(&accepts[1], stale).await;
+        n3.expect_none("rejection", is_error).await;
+        assert!(!write.is_finished());
+
+        cluster
+            .peer("n4")
+            .accepted(&accepts[2], ballot_number)
+            .await;
+        let reply = write.await.unwrap();
+        assert!(matches!(reply, Some(Body::WriteOk { .. })), "{reply:?}");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn duplicate_and_stale_promises_dont_reach_a_quorum() {
+        let cluster = Cluster::start(5).await;
+        let _write = cluster.write(7);
+        let n2 = cluster.peer("n2");
+        let propose = n2.expect("propose", is_propose).await;
+        let ballot_number = ballot_of(&propose);
+
+        // n1's promise and n2's, counted once, are two of the three needed.
+        n2.promise(&propose).await;
+        n2.promise(&propose).await;
+        // n3's is for an earlier ballot, overtaken on its way by the current one's.
+        let n3 = cluster.peer("n3");
+        let propose = n3.expect("propose", is_propose).await;
+        n3.send(Body::Promise {
+            in_reply_to: propose.body.msg_id,
+            key: KEY,
+            ballot_number: BallotNumber(ballot_number.0 - 1, 0),
+            accepted_ballot_number: BallotNumber::ZERO,
+            value: KeyValueStore::new_with_inner(HashMap::new()),
+        })
+        .await;
+        n3.expect_none("rejection", is_error).await;
+        n2.expect_none("accept", is_accept).await;
+
+        n3.promise(&propose).await;
+        n2.expect("accept", is_accept).await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn late_promises_leave_their_round_alone() {
+        let cluster = Cluster::start(5).await;
+        let (write, Round { proposes, accepts }) = past_propose_phase(&cluster).await;
+        let ballot_number = ballot_of(&accepts[0]);
+
+        // n4's promise comes in after the Accept went out, with a value it accepted
+        // that the round would have had to build on, had it come in time.
+        let n4 = cluster.peer("n4");
+        let mut value = KeyValueStore::new_with_inner(HashMap::new());
+        value.write(KEY, json!(99));
+        n4.send(Body::Promise {
+            in_reply_to: proposes[2].body.msg_id,
+            key: KEY,
+            ballot_number,
+            accepted_ballot_number: BallotNumber(ballot_number.0 - 1, 3),
+            value,
+        })
+        .await;
+        for peer in &cluster.peers {
+            peer.expect_none("second accept", is_accept).await;
+        }
+
+        cluster
+            .peer("n2")
+            .accepted(&accepts[0], ballot_number)
+            .await;
+        cluster
+            .peer("n3")
+            .accepted(&accepts[1], ballot_number)
+            .await;
+        let reply = write.await.unwrap();
+        assert!(matches!(reply, Some(Body::WriteOk { .. })), "{reply:?}");
+        let Some(Body::StatsOk { stats, .. }) = cluster.client.call("n1", Body::Stats {}).await
+        else {
+            panic!("n1 gave no stats");
+        };
+        assert_eq!(stats.late_promises, 1);
+    }
+
+    // n2's round left KEY's tombstone, so dropping it is up to n2 for as long as it's up.
+    #[tokio::test(start_paused = true)]
+    async fn only_the_last_ballot_winner_compacts_a_key() {
+        let config = Config {
+            compact_after: Some(Duration::from_secs(1)),
+            ..Config::default()
+        };
+        let cluster = Cluster::start_with(3, config).await;
+        let n2 = cluster.peer("n2");
+        let mut value = KeyValueStore::new_with_inner(HashMap::new());
+        value.write(KEY, json!(5));
+        value.delete(&KEY, 0).unwrap();
+        n2.send(Body::Accept {
+            key: KEY,
+            ballot_number: BallotNumber(2, 1),
+            value,
+        })
+        .await;
+        n2.expect("accepted", |body| matches!(body, Body::Accepted { .. }))
+            .await;
+
+        let n3 = cluster.peer("n3");
+        for _ in 0..6 {
+            n2.send(Body::Heartbeat {
+                ballots_digest: 0,
+                state_digest: 0,
+            })
+            .await;
+            n3.expect_none("propose", is_propose).await;
+            tokio::time::sleep(Duration::from_millis(400)).await;
+        }
+
+        // once n2 went silent, n1 drops the tombstone itself.
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        n3.expect("propose", is_propose).await;
+    }
+
+    // n1 as an acceptor, getting n2's Accept before the Propose it follows.
+    #[tokio::test(start_paused = true)]
+    async fn accepts_overtaking_their_propose_are_accepted() {
+        let cluster = Cluster::start(3).await;
+        let n2 = cluster.peer("n2");
+        let ballot_number = BallotNumber(2, 1);
+        let mut value = KeyValueStore::new_with_inner(HashMap::new());
+        value.write(KEY, json!(5));
+        n2.send(Body::Accept {
+            key: KEY,
+            ballot_number,
+            value,
+        })
+        .await;
+        n2.expect("accepted", |body| {
+            matches!(body, Body::Accepted { ballot_number: accepted, .. } if *accepted == ballot_number)
+        })
+        .await;
+
+        // accepting promised n2's next ballot, which the Propose is already behind.
+        n2.send(Body::Propose {
+            key: KEY,
+            ballot_number,
+        })
+        .await;
+        assert_eq!(rejection_hint(n2).await, Some(ballot_number.next(1)));
+    }
+
+    /// The ballot hint of the rejection `peer` is expected to get.
+    async fn rejection_hint(peer: &Peer) -> Option<BallotNumber> {
+        match peer.expect("rejection", is_error).await.body.inner {
+            Body::Error { ballot_hint, .. } => ballot_hint,
             _ => unreachable!(),
         }
     }
+
+    // n2 and n3 propose in the same round, which their node indexes tell apart, to n1
+    // as an acceptor: only the greater ballot can get its value accepted.
+    #[tokio::test(start_paused = true)]
+    async fn competing_proposers_dont_both_get_accepted() {
+        let cluster = Cluster::start(3).await;
+        let (n2, n3) = (cluster.peer("n2"), cluster.peer("n3"));
+        let (lower, greater) = (BallotNumber(5, 1), BallotNumber(5, 2));
+        let propose = |ballot_number| Body::Propose {
+            key: KEY,
+            ballot_number,
+        };
+        let accept = |ballot_number, value: u64| {
+            let mut state = KeyValueStore::new_with_inner(HashMap::new());
+            state.write(KEY, json!(value));
+            Body::Accept {
+                key: KEY,
+                ballot_number,
+                value: state,
+            }
+        };
+        let is_promise = |body: &Body| matches!(body, Body::Promise { .. });
+
+        n2.send(propose(lower)).await;
+        n2.expect("promise", is_promise).await;
+        n3.send(propose(greater)).await;
+        n3.expect("promise", is_promise).await;
+
+        // n2 takes its Propose phase again, and its Accept phase, after n3's promise.
+        n2.send(propose(lower)).await;
+        assert_eq!(rejection_hint(n2).await, Some(greater));
+        n2.send(accept(lower, 1)).await;
+        assert_eq!(rejection_hint(n2).await, Some(greater));
+
+        n3.send(accept(greater, 2)).await;
+        n3.expect("accepted", |body| matches!(body, Body::Accepted { .. }))
+            .await;
+    }
+
+    // a txn-rw-register txn over keys of two groups: its reads see its own writes,
+    // and once it's acknowledged, single-key reads in both groups see all of them.
+    #[tokio::test(start_paused = true)]
+    async fn txns_across_groups_are_applied_whole() {
+        let config = Config {
+            group_size: Some(3),
+            ..Config::default()
+        };
+        let cluster = sim::Cluster::start(6, &config, Faults::default())
+            .await
+            .unwrap();
+        let call = |node: &'static str, body| cluster.client.call(node, body);
+        let op = |kind, key, value: Option<u64>| TxnOp(kind, Key::Int(key), value.map(Value::from));
+        let (read, write) = (TxnOpKind::Read, TxnOpKind::Write);
+
+        let txn = vec![
+            op(write, 1, Some(10)),
+            op(write, 2, Some(20)),
+            op(read, 1, None),
+        ];
+        let reply = call("n1", Body::Txn { txn }).await;
+        let Some(Body::TxnOk { txn, .. }) = reply else {
+            panic!("txn wasn't committed: {reply:?}");
+        };
+        assert_eq!(txn[2], op(read, 1, Some(10)));
+
+        for (node, key, value) in [("n2", 1, 10), ("n5", 2, 20)] {
+            let reply = call(node, Body::Read { key: Key::Int(key) }).await;
+            assert!(
+                matches!(&reply, Some(Body::ReadOk { value: read, .. }) if *read == json!(value)),
+                "key {key}: {reply:?}"
+            );
+        }
+
+        let txn = vec![op(read, 2, None), op(write, 1, Some(11)), op(read, 1, None)];
+        let reply = call("n4", Body::Txn { txn }).await;
+        let Some(Body::TxnOk { txn, .. }) = reply else {
+            panic!("txn wasn't committed: {reply:?}");
+        };
+        assert_eq!(txn[0], op(read, 2, Some(20)));
+        assert_eq!(txn[2], op(read, 1, Some(11)));
+    }
+
+    // keys expire by the clock tokio pauses in tests, so the same run expires the same
+    // keys whenever it's replayed.
+    #[tokio::test(start_paused = true)]
+    async fn keys_expire_by_the_paused_clock() {
+        let cluster = sim::Cluster::start(3, &Config::default(), Faults::default())
+            .await
+            .unwrap();
+        let write = Body::Write {
+            key: KEY,
+            value: json!(1),
+            create_if_not_exists: false,
+            expiry_ms: Some(1000),
+        };
+        let reply = cluster.client.call("n1", write).await;
+        assert!(matches!(reply, Some(Body::WriteOk { .. })), "{reply:?}");
+
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        let reply = cluster.client.call("n2", Body::Read { key: KEY }).await;
+        assert!(matches!(reply, Some(Body::ReadOk { .. })), "{reply:?}");
+
+        tokio::time::sleep(Duration::from_millis(1000)).await;
+        let reply = cluster.client.call("n3", Body::Read { key: KEY }).await;
+        assert!(
+            matches!(
+                reply,
+                Some(Body::Error {
+                    code: ErrorCode::KeyDoesNotExist,
+                    ..
+                })
+            ),
+            "{reply:?}"
+        );
+    }
+
+    // the size of n1's Promise and Accept msgs once it holds a few hundred keys, next
+    // to that of an Accept carrying the whole store, as they all did before: theirs
+    // stays that of the one key's entries, whatever else the store holds.
+    #[tokio::test(start_paused = true)]
+    async fn promises_and_accepts_dont_grow_with_the_store() {
+        let cluster = Cluster::start(5).await;
+        let n5 = cluster.peer("n5");
+        let mut store = KeyValueStore::new_with_inner(HashMap::new());
+        for i in 100..400 {
+            let key = Key::Int(i);
+            let mut value = KeyValueStore::new_with_inner(HashMap::new());
+            value.write(key.clone(), json!(i));
+            store.write(key.clone(), json!(i));
+            n5.send(Body::Accept {
+                key,
+                ballot_number: BallotNumber(1, 4),
+                value,
+            })
+            .await;
+            n5.expect("accepted", |body| matches!(body, Body::Accepted { .. }))
+                .await;
+        }
+        // so that n1 forgets n5 won ballots, and proposes the write itself rather
+        // than proxying it to n5.
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        let (_write, Round { accepts, .. }) = past_propose_phase(&cluster).await;
+        let n2 = cluster.peer("n2");
+        n2.send(Body::Propose {
+            key: Key::Int(100),
+            ballot_number: BallotNumber(3, 1),
+        })
+        .await;
+        let promise = n2
+            .expect("promise", |body| matches!(body, Body::Promise { .. }))
+            .await;
+
+        store.write(KEY, json!(7));
+        let whole_store = Body::Accept {
+            key: KEY,
+            ballot_number: ballot_of(&accepts[0]),
+            value: store,
+        };
+        let size = |body: &Body| serde_json::to_vec(body).unwrap().len();
+        let (accept, promise, whole_store) = (
+            size(&accepts[0].body.inner),
+            size(&promise.body.inner),
+            size(&whole_store),
+        );
+        assert!(accept < 200, "accept takes {accept} bytes");
+        assert!(promise < 200, "promise takes {promise} bytes");
+        assert!(
+            whole_store > 20 * accept.max(promise),
+            "whole store takes {whole_store} bytes, next to {accept} and {promise}"
+        );
+    }
+
+    // the entries txns keep about a key, and the members register, aren't client keys.
+    #[tokio::test(start_paused = true)]
+    async fn reads_of_reserved_keys_fail() {
+        let cluster = Cluster::start(3).await;
+        for key in [
+            Key::Tagged(Tag::Lock, Box::new(KEY)),
+            membership::MEMBERSHIP_KEY,
+        ] {
+            for body in [
+                Body::Read { key: key.clone() },
+                Body::ReadVersion { key: key.clone() },
+                Body::MultiRead {
+                    keys: vec![KEY, key.clone()],
+                },
+            ] {
+                let reply = cluster.call(body).await.unwrap();
+                assert!(
+                    matches!(
+                        reply,
+                        Some(Body::Error {
+                            code: ErrorCode::MalformedRequest,
+                            ..
+                        })
+                    ),
+                    "{key}: {reply:?}"
+                );
+            }
+        }
+    }
+
+    // quorums of one node out of three could each decide a value the other never sees.
+    #[tokio::test(start_paused = true)]
+    async fn init_with_disjoint_quorums_is_rejected() {
+        let node_ids: Vec<String> = (1..=3).map(|i| format!("n{i}")).collect();
+        let network = Network::new(node_ids.clone(), Faults::default());
+        let config = Config {
+            prepare_quorum: Some(1),
+            accept_quorum: Some(1),
+            ..Config::default()
+        };
+        let client = Client::start(&network, config.client_deadline * 2);
+        let n1 = CASPaxos::new(config, Arc::new(network.connect("n1")));
+        tokio::spawn(Arc::new(n1).run());
+
+        let init = Body::Init {
+            node_id: "n1".into(),
+            node_ids,
+        };
+        for body in [init, Body::Read { key: KEY }] {
+            let reply = client.call("n1", body).await;
+            assert!(
+                matches!(
+                    reply,
+                    Some(Body::Error {
+                        code: ErrorCode::MalformedRequest,
+                        ..
+                    })
+                ),
+                "{reply:?}"
+            );
+        }
+    }
 }
@@ -0,0 +1,163 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::time::Instant;
+
+use crate::{ballot::BallotNumber, key::Key};
+
+/// One value chosen by a round this node proposed.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Change {
+    pub cursor: u64,
+    pub key: Key,
+    pub value: Option<Value>, // None if the key doesn't exist
+    pub ballot_number: BallotNumber,
+}
+
+/// The latest values chosen by this node's rounds, in the order they were chosen.
+/// Ballots order changes across nodes, so the cluster's history can be put back
+/// together from every node's changelog. Once there are more than `max_entries`
+/// changes, or they're older than `max_age`, the oldest ones are compacted: only the
+/// last of them for each key is kept, so that a reader that fell behind still gets
+/// every key's latest value, if not every value in between.
+#[derive(Debug)]
+pub struct Changelog {
+    max_entries: usize,
+    max_age: Option<Duration>,
+    changes: VecDeque<(Instant, Change)>, // with when they were recorded
+    compacted: HashMap<Key, Change>,      // the last compacted change of each key
+    compacted_before: u64,                // the cursor of the oldest change left whole
+    next_cursor: u64,
+}
+
+impl Changelog {
+    pub fn new(max_entries: usize, max_age: Option<Duration>) -> Self {
+        Self {
+            max_entries: max_entries.max(1),
+            max_age,
+            changes: VecDeque::new(),
+            compacted: HashMap::new(),
+            compacted_before: 0,
+            next_cursor: 0,
+        }
+    }
+
+    pub fn record(&mut self, key: Key, value: Option<Value>, ballot_number: BallotNumber) {
+        let cursor = self.next_cursor;
+        self.next_cursor += 1;
+        let change = Change {
+            cursor,
+            key,
+            value,
+            ballot_number,
+        };
+        self.changes.push_back((Instant::now(), change));
+        self.compact();
+    }
+
+    /// The changes from `cursor` on, along with the cursor to ask for next time and
+    /// whether changes after `cursor` were already compacted away. Compacted changes
+    /// come first, in the order they were chosen.
+    pub fn since(&mut self, cursor: u64) -> (Vec<Change>, u64, bool) {
+        self.compact();
+        let mut compacted: Vec<Change> = self
+            .compacted
+            .values()
+            .filter(|change| change.cursor >= cursor)
+            .cloned()
+            .collect();
+        compacted.sort_unstable_by_key(|change| change.cursor);
+        let changes = compacted
+            .into_iter()
+            .chain(
+                self.changes
+                    .iter()
+                    .map(|(_, change)| change)
+                    .filter(|change| change.cursor >= cursor)
+                    .cloned(),
+            )
+            .collect();
+        (changes, self.next_cursor, cursor < self.compacted_before)
+    }
+
+    /// Folds the changes past `max_entries` or `max_age` into `compacted`.
+    fn compact(&mut self) {
+        let now = Instant::now();
+        while let Some((recorded_at, _)) = self.changes.front() {
+            let is_due = self.changes.len() > self.max_entries
+                || self
+                    .max_age
+                    .is_some_and(|max_age| now.duration_since(*recorded_at) > max_age);
+            if !is_due {
+                break;
+            }
+            let (_, change) = self.changes.pop_front().unwrap();
+            self.compacted_before = change.cursor + 1;
+            self.compacted.insert(change.key.clone(), change);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn keys(changes: &[Change]) -> Vec<(u64, Key)> {
+        changes
+            .iter()
+            .map(|change| (change.cursor, change.key.clone()))
+            .collect()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn compacts_past_max_entries() {
+        let mut changelog = Changelog::new(2, None);
+        for (i, key) in [1, 2, 1, 3, 4].into_iter().enumerate() {
+            changelog.record(Key::Int(key), Some(json!(i)), BallotNumber(1, 0));
+        }
+
+        // 0 and 2 were both key 1's, so only 2 is left of them.
+        let (changes, cursor, truncated) = changelog.since(0);
+        assert_eq!(
+            keys(&changes),
+            [
+                (1, Key::Int(2)),
+                (2, Key::Int(1)),
+                (3, Key::Int(3)),
+                (4, Key::Int(4))
+            ]
+        );
+        assert_eq!(cursor, 5);
+        assert!(truncated);
+
+        let (changes, _, truncated) = changelog.since(3);
+        assert_eq!(keys(&changes), [(3, Key::Int(3)), (4, Key::Int(4))]);
+        assert!(!truncated);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn compacts_past_max_age() {
+        let mut changelog = Changelog::new(100, Some(Duration::from_secs(10)));
+        changelog.record(Key::Int(1), Some(json!(1)), BallotNumber(1, 0));
+        changelog.record(Key::Int(1), None, BallotNumber(2, 0));
+        tokio::time::advance(Duration::from_secs(6)).await;
+        changelog.record(Key::Int(2), Some(json!(2)), BallotNumber(1, 0));
+
+        let (changes, _, truncated) = changelog.since(0);
+        assert_eq!(changes.len(), 3);
+        assert!(!truncated);
+
+        // the first two are old enough, so only key 1's delete is left of them.
+        tokio::time::advance(Duration::from_secs(6)).await;
+        let (changes, _, truncated) = changelog.since(0);
+        assert_eq!(keys(&changes), [(1, Key::Int(1)), (2, Key::Int(2))]);
+        assert_eq!(changes[0].value, None);
+        assert!(truncated);
+    }
+}
@@ -0,0 +1,103 @@
+//! Faults a node deals its own msgs to peers, set with `--chaos` or the CHAOS
+//! environment variable, e.g. `drop=0.1,duplicate=0.05,delay=0.2,reorder=0.1`. They
+//! provoke resends, late promises, stale ballots and the like in a local run, with
+//! no Maelstrom nemesis.
+
+use std::{str::FromStr, time::Duration};
+
+use anyhow::{anyhow, Context};
+use rand::Rng;
+
+use crate::rng;
+
+// How long a reordered msg is held back, for the msgs sent after it to overtake it.
+const REORDER_HOLD: Duration = Duration::from_millis(10);
+
+/// The chance of each fault, per msg.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Chaos {
+    pub drop_rate: f64,
+    pub duplicate_rate: f64,
+    // delayed msgs are held back for up to max_delay.
+    pub delay_rate: f64,
+    pub max_delay: Duration,
+    pub reorder_rate: f64,
+}
+
+impl Default for Chaos {
+    fn default() -> Self {
+        Self {
+            drop_rate: 0.0,
+            duplicate_rate: 0.0,
+            delay_rate: 0.0,
+            max_delay: Duration::from_millis(50),
+            reorder_rate: 0.0,
+        }
+    }
+}
+
+/// What becomes of a msg.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fate {
+    Deliver,
+    Drop,
+    Duplicate,
+    Delay(Duration),
+}
+
+impl Chaos {
+    pub fn fate(&self) -> Fate {
+        rng::with_rng(|rng| {
+            if rng.random_bool(self.drop_rate) {
+                Fate::Drop
+            } else if rng.random_bool(self.duplicate_rate) {
+                Fate::Duplicate
+            } else if rng.random_bool(self.delay_rate) {
+                Fate::Delay(self.max_delay.mul_f64(rng.random()))
+            } else if rng.random_bool(self.reorder_rate) {
+                Fate::Delay(REORDER_HOLD)
+            } else {
+                Fate::Deliver
+            }
+        })
+    }
+}
+
+impl FromStr for Chaos {
+    type Err = anyhow::Error;
+
+    /// Comma-separated `fault=rate` pairs, with `max-delay-ms=N` for delays.
+    fn from_str(spec: &str) -> anyhow::Result<Self> {
+        let mut chaos = Chaos::default();
+        for pair in spec.split(',').filter(|pair| !pair.is_empty()) {
+            let (fault, value) = pair
+                .split_once('=')
+                .ok_or_else(|| anyhow!("{pair:?} should be fault=rate"))?;
+            let rate = || match value.parse() {
+                Ok(rate) if (0.0..=1.0).contains(&rate) => Ok(rate),
+                _ => Err(anyhow!(
+                    "the {fault} rate should be from 0 to 1, not {value}"
+                )),
+            };
+            match fault {
+                "drop" => chaos.drop_rate = rate()?,
+                "duplicate" => chaos.duplicate_rate = rate()?,
+                "delay" => chaos.delay_rate = rate()?,
+                "reorder" => chaos.reorder_rate = rate()?,
+                "max-delay-ms" => {
+                    chaos.max_delay = Duration::from_millis(
+                        value
+                            .parse()
+                            .context("max-delay-ms should be a number of milliseconds")?,
+                    );
+                }
+                other => {
+                    return Err(anyhow!(
+                        "unknown fault {other}, should be drop, duplicate, delay or reorder"
+                    ))
+                }
+            }
+        }
+        Ok(chaos)
+    }
+}
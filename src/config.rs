@@ -0,0 +1,310 @@
+use std::time::Duration;
+
+use clap::{
+    error::{ContextKind, ContextValue, ErrorKind},
+    CommandFactory, Parser, ValueEnum,
+};
+use tracing_subscriber::filter::LevelFilter;
+
+use crate::{chaos::Chaos, key::Key, node::QuorumSizes};
+
+/// Runtime knobs, set from the command line. The flags' help is the fields' docs.
+#[derive(Parser, Debug, Clone)]
+#[command(name = "cas-paxos", about = "A CASPaxos key-value store for Maelstrom")]
+pub struct Config {
+    /// Approximate memory the node may use before it starts shedding client ops.
+    #[arg(long, default_value_t = 256 * 1024 * 1024)]
+    pub memory_limit_bytes: usize,
+    /// How many peers a broadcast sends to concurrently.
+    #[arg(long, default_value_t = 8)]
+    pub broadcast_concurrency: usize,
+    /// Skip all logging and metric collection, to measure the bare protocol cost.
+    #[arg(long)]
+    pub quiet_bench: bool,
+    /// Per-client cap on client ops, past which they're rejected. No cap if unset.
+    #[arg(long)]
+    pub client_ops_per_sec: Option<u32>,
+    /// Add the ballot each read was decided at to read_ok, to match reads with decision
+    /// records. Off by default, since checkers don't expect the extra field.
+    #[arg(long)]
+    pub debug_read_ballots: bool,
+    /// Serve client ops from a local LWW map while no quorum is reachable, merging it
+    /// back once one is. Trades linearizability for availability.
+    #[arg(long)]
+    pub crdt_fallback: bool,
+    /// Keys starting with one of these always take the LWW path, while all the other
+    /// keys stay linearizable. Int keys are matched by their decimal form, so "1"
+    /// takes both 12 and "1a".
+    #[arg(long = "lww-key-prefix")]
+    pub lww_key_prefixes: Vec<String>,
+    /// Split the cluster into consensus groups of this many consecutive nodes, each
+    /// storing its share of the keys. Unset keeps the whole cluster as one group.
+    #[arg(long)]
+    pub group_size: Option<usize>,
+    /// Snapshot file (see `write_snapshot`) to start from instead of an empty store.
+    #[arg(long)]
+    pub restore: Option<String>,
+    /// How many times a rejected round is retried, with a new ballot, before the
+    /// client gets a timeout error.
+    #[arg(long, visible_alias = "retry-max", default_value_t = 5)]
+    pub max_proposal_retries: u32,
+    /// The backoff before the first retry, which doubles with each rejection.
+    #[arg(long = "retry-backoff-base-ms", value_name = "MS", value_parser = millis, default_value = "5")]
+    pub retry_backoff_base: Duration,
+    /// The most a backoff grows to, so that retries fit the client's deadline.
+    #[arg(long = "retry-backoff-cap-ms", value_name = "MS", value_parser = millis, default_value = "200")]
+    pub retry_backoff_cap: Duration,
+    /// How long a client request may take before it gets a timeout error, and the
+    /// round still running for it is dropped.
+    #[arg(
+        long = "client-deadline-ms",
+        visible_alias = "timeout-ms",
+        value_name = "MS",
+        value_parser = millis,
+        default_value = "1000"
+    )]
+    pub client_deadline: Duration,
+    /// How long deleted keys keep their tombstones, and with them their versions,
+    /// before compaction drops them. Compaction also drops the decisions of the txns
+    /// every group finished. Unset keeps both for good.
+    #[arg(long = "compact-after-ms", value_name = "MS", value_parser = millis)]
+    pub compact_after: Option<Duration>,
+    /// How many of the latest chosen values the changelog keeps whole. Older ones
+    /// are compacted down to each key's last one.
+    #[arg(long, default_value_t = 10_000)]
+    pub changelog_max_entries: usize,
+    /// How long the changelog keeps chosen values whole, however few there are.
+    #[arg(long = "changelog-max-age-ms", value_name = "MS", value_parser = millis)]
+    pub changelog_max_age: Option<Duration>,
+    /// Promises a round waits for, in place of a majority of the group. Together with
+    /// the accept quorum it has to exceed the group size (see FPaxos).
+    #[arg(long)]
+    pub prepare_quorum: Option<usize>,
+    /// Acceptances a round waits for, in place of a majority of the group, e.g. a
+    /// small one for cheaper writes, at the cost of a larger prepare quorum.
+    #[arg(long)]
+    pub accept_quorum: Option<usize>,
+    /// Nodes that only take part in Propose phases, storing promises but neither
+    /// state nor proposals of their own. Every node is told, so quorums account for them.
+    #[arg(long = "witness")]
+    pub witnesses: Vec<String>,
+    /// How reads are served, see `ReadMode`.
+    #[arg(long, value_enum, default_value_t)]
+    pub reads: ReadMode,
+    /// What to do when a peer's heartbeat digests say our states diverge.
+    #[arg(long, value_enum, default_value_t)]
+    pub divergence_check: DivergenceCheck,
+    /// What client ops run against, see `Workload`.
+    #[arg(long, value_enum, default_value_t)]
+    pub workload: Workload,
+    /// Send broadcast msgs again until each peer replies, or the client deadline
+    /// passes, rather than leaving a lost msg to the round's retry.
+    #[arg(long)]
+    pub reliable_broadcast: bool,
+    /// The max level of logged events, until a `set_log_level` message changes it.
+    #[arg(long, default_value = "debug")]
+    pub log_level: LevelFilter,
+    /// Faults dealt to our own msgs to peers, see `Chaos`. Taken from the CHAOS
+    /// environment variable when unset. Unset in both deals none.
+    #[arg(long)]
+    pub chaos: Option<Chaos>,
+}
+
+/// Replicas compare digests of their states on every heartbeat. Off ignores them, Log
+/// logs a `divergence` error when they disagree at the same ballots, and Diff also
+/// asks the peer for per-key digests, to log which keys diverge and our values of them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum DivergenceCheck {
+    Off,
+    #[default]
+    Log,
+    Diff,
+}
+
+/// Quorum reads run a CASPaxos round like any other op. Lease reads are answered
+/// from the local state while the node holds the key's lease, at the cost of other
+/// proposers waiting out leases they didn't take part in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum ReadMode {
+    #[default]
+    Quorum,
+    Lease,
+}
+
+/// The KV store runs each key as its own CASPaxos instance. The register runs every
+/// client op on a single instance, whatever key it names, as in the CASPaxos paper
+/// and Maelstrom's cas-register workload, whose ops don't name one at all. Broadcast
+/// and Counter keep the KV store, but answer reads from clients with the broadcast
+/// messages, or the counter. LinKvProxy runs no consensus for client ops, leaving
+/// them to Maelstrom's lin-kv service, to check our own results against.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum Workload {
+    #[default]
+    #[value(name = "kv")]
+    KeyValue,
+    Register,
+    Broadcast,
+    #[value(name = "g-counter", alias = "pn-counter")]
+    Counter,
+    LinKvProxy,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self::parse_from(["cas-paxos"])
+    }
+}
+
+impl Config {
+    /// Parses the flags in `args`, the program name left out. A clap error prints
+    /// what's wrong with them, along with the usage.
+    pub fn from_args(args: impl Iterator<Item = String>) -> Result<Self, clap::Error> {
+        let mut config = Self::try_parse_from(std::iter::once("cas-paxos".to_string()).chain(args))
+            .map_err(with_usage)?;
+
+        // the flag wins over the environment, as with any other setting.
+        if config.chaos.is_none() {
+            if let Ok(spec) = std::env::var("CHAOS") {
+                let chaos = spec
+                    .parse()
+                    .map_err(|e| invalid(format!("invalid CHAOS: {e:#}")))?;
+                config.chaos = Some(chaos);
+            }
+        }
+        config.validate().map_err(invalid)?;
+        Ok(config)
+    }
+
+    /// Checks the flags that only make sense together with others.
+    fn validate(&self) -> Result<(), String> {
+        if self.group_size.is_some() && !self.witnesses.is_empty() {
+            return Err("--witness needs the cluster to be a single group".into());
+        }
+        if self.retry_backoff_base > self.retry_backoff_cap {
+            return Err("--retry-backoff-base-ms can't be over --retry-backoff-cap-ms".into());
+        }
+        // a client still waiting on an op may go on from the version it read.
+        if self
+            .compact_after
+            .is_some_and(|compact_after| compact_after < self.client_deadline)
+        {
+            return Err("--compact-after-ms can't be under --client-deadline-ms".into());
+        }
+        // a client retrying an op within its deadline may look for the op's change.
+        if self
+            .changelog_max_age
+            .is_some_and(|max_age| max_age < self.client_deadline)
+        {
+            return Err("--changelog-max-age-ms can't be under --client-deadline-ms".into());
+        }
+        if self.prepare_quorum == Some(0) || self.accept_quorum == Some(0) {
+            return Err("--prepare-quorum and --accept-quorum can't be 0".into());
+        }
+        // without --group-size, the group is the whole cluster, whose size only Init tells.
+        if let Some(group_size) = self.group_size {
+            let quorum_sizes = QuorumSizes {
+                prepare: self.prepare_quorum,
+                accept: self.accept_quorum,
+            };
+            let (prepare_quorum, accept_quorum) = quorum_sizes.resolve(group_size, group_size);
+            if prepare_quorum.max(accept_quorum) > group_size {
+                return Err(format!(
+                    "quorums of {prepare_quorum} and {accept_quorum} nodes can't fit in \
+                     groups of {group_size}"
+                ));
+            }
+            if prepare_quorum + accept_quorum <= group_size {
+                return Err(format!(
+                    "prepare and accept quorums of {prepare_quorum} and {accept_quorum} nodes \
+                     should add up to more than the --group-size of {group_size}"
+                ));
+            }
+        }
+        // the LWW overlay holds values of keys, which the register has none of.
+        let takes_lww_path = self.crdt_fallback || !self.lww_key_prefixes.is_empty();
+        if self.workload == Workload::Register && takes_lww_path {
+            return Err(
+                "--workload register doesn't go with --crdt-fallback or --lww-key-prefix".into(),
+            );
+        }
+        Ok(())
+    }
+
+    pub fn is_lww_key(&self, key: &Key) -> bool {
+        if self.lww_key_prefixes.is_empty() {
+            return false;
+        }
+        let key = key.to_string();
+        self.lww_key_prefixes
+            .iter()
+            .any(|prefix| key.starts_with(prefix.as_str()))
+    }
+}
+
+/// Parses a flag given in milliseconds.
+fn millis(value: &str) -> Result<Duration, String> {
+    value
+        .parse()
+        .map(Duration::from_millis)
+        .map_err(|_| format!("{value} isn't a number of milliseconds"))
+}
+
+/// An error about flags that parsed on their own but don't go together.
+fn invalid(message: String) -> clap::Error {
+    with_usage(Config::command().error(ErrorKind::ArgumentConflict, message))
+}
+
+/// Adds the usage to `e`, which clap leaves out of some errors, such as bad values.
+fn with_usage(mut e: clap::Error) -> clap::Error {
+    if e.use_stderr() && e.get(ContextKind::Usage).is_none() {
+        let usage = Config::command().render_usage();
+        e.insert(ContextKind::Usage, ContextValue::StyledStr(usage));
+    }
+    e
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Config, clap::Error> {
+        Config::from_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn flags_take_aliases_and_inline_values() {
+        let config = parse(&[
+            "--retry-max=3",
+            "--timeout-ms",
+            "2000",
+            "--workload=pn-counter",
+            "--reads",
+            "lease",
+            "--witness",
+            "n2",
+            "--witness=n3",
+        ])
+        .unwrap();
+        assert_eq!(config.max_proposal_retries, 3);
+        assert_eq!(config.client_deadline, Duration::from_secs(2));
+        assert_eq!(config.workload, Workload::Counter);
+        assert_eq!(config.reads, ReadMode::Lease);
+        assert_eq!(config.witnesses, ["n2", "n3"]);
+        assert_eq!(config.log_level, LevelFilter::DEBUG);
+    }
+
+    #[test]
+    fn bad_flags_are_errors_with_the_usage() {
+        for args in [
+            &["--bogus"][..],
+            &["--reads", "sometimes"],
+            &["--timeout-ms", "soon"],
+            &["--compact-after-ms", "10"],
+            &["--group-size", "3", "--prepare-quorum", "1"],
+        ] {
+            let e = parse(args).unwrap_err();
+            assert!(e.use_stderr(), "{args:?}");
+            assert!(e.to_string().contains("Usage:"), "{args:?}: {e}");
+        }
+    }
+}
@@ -0,0 +1,26 @@
+//! Maelstrom's g-counter and pn-counter workloads: clients add deltas to a single
+//! counter and read it back. The counter is a key of the KV store like any other,
+//! and adds are CAS loops on it, see `CASPaxos::update_with_cas`.
+
+use serde_json::Value;
+
+use crate::{key::Key, message::ErrorCode};
+
+/// The key the counter is kept under. Counter clients don't name one.
+pub const COUNTER_KEY: Key = Key::Int(0);
+
+/// The counter's value, where a counter nobody added to yet is 0.
+pub fn value(current: Option<&Value>) -> Result<i64, ErrorCode> {
+    match current {
+        None => Ok(0),
+        Some(value) => value.as_i64().ok_or(ErrorCode::MalformedRequest),
+    }
+}
+
+/// The counter after adding `delta` to it.
+pub fn add(current: Option<&Value>, delta: i64) -> Result<Value, ErrorCode> {
+    let sum = value(current)?
+        .checked_add(delta)
+        .ok_or(ErrorCode::MalformedRequest)?;
+    Ok(Value::from(sum))
+}
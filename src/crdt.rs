@@ -0,0 +1,93 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value;
+
+use crate::{key::Key, node::NodeIndex};
+
+/// A last-writer-wins register: between two writes, the one with the higher
+/// (timestamp, node) pair wins, so every node merging the same writes agrees.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct LwwRegister {
+    pub value: Value,
+    timestamp: u64, // Lamport clock of the node that wrote it
+    node: NodeIndex,
+}
+
+impl LwwRegister {
+    fn stamp(&self) -> (u64, NodeIndex) {
+        (self.timestamp, self.node)
+    }
+}
+
+/// A state-based CRDT map of LWW registers, holding the writes made while the
+/// node couldn't reach a quorum. Merging two maps keeps the winner of each key.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct LwwMap {
+    #[serde(
+        serialize_with = "serialize_registers",
+        deserialize_with = "deserialize_registers"
+    )]
+    registers: BTreeMap<Key, LwwRegister>,
+    clock: u64, // highest timestamp seen, locally or in merged maps
+}
+
+impl LwwMap {
+    pub fn get(&self, key: &Key) -> Option<&Value> {
+        self.registers.get(key).map(|register| &register.value)
+    }
+
+    pub fn set(&mut self, key: Key, value: Value, node: NodeIndex) {
+        self.clock += 1;
+        let register = LwwRegister {
+            value,
+            timestamp: self.clock,
+            node,
+        };
+        self.registers.insert(key, register);
+    }
+
+    pub fn merge(&mut self, other: &LwwMap) {
+        self.clock = self.clock.max(other.clock);
+        for (key, theirs) in &other.registers {
+            let theirs_wins = self
+                .registers
+                .get(key)
+                .is_none_or(|ours| theirs.stamp() > ours.stamp());
+            if theirs_wins {
+                self.registers.insert(key.clone(), theirs.clone());
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.registers.is_empty()
+    }
+
+    /// Forgets the registers whose value `is_settled` says made it into the
+    /// linearizable store.
+    pub fn retain_unsettled(&mut self, mut is_settled: impl FnMut(&Key, &Value) -> bool) {
+        self.registers
+            .retain(|key, register| !is_settled(key, &register.value));
+    }
+}
+
+// Registers go over the wire as a list of (key, register) pairs, since keys aren't
+// all strings, which the keys of a JSON object have to be.
+fn serialize_registers<S>(
+    registers: &BTreeMap<Key, LwwRegister>,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.collect_seq(registers.iter())
+}
+
+fn deserialize_registers<'de, D>(deserializer: D) -> Result<BTreeMap<Key, LwwRegister>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let pairs: Vec<(Key, LwwRegister)> = Vec::deserialize(deserializer)?;
+    Ok(pairs.into_iter().collect())
+}
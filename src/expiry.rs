@@ -0,0 +1,77 @@
+//! Expiring keys. Replicas can't agree on what expired by their own clocks, so each
+//! CASPaxos instance with expiring keys carries a clock in its state, under a tagged
+//! key. The proposer of a round moves it to at least its own `LocalClock`, then drops
+//! the keys that expired by it, and the state it gets accepted is the one every
+//! replica ends up with.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use serde_json::Value;
+use tokio::time::Instant;
+
+use crate::{
+    key::{Key, Tag},
+    kv_store::KeyValueStore,
+};
+
+type InstanceState = KeyValueStore<Key, Value>;
+
+/// The key of the clock of `base_key`'s instance, in ms of `LocalClock`.
+pub fn clock_key(base_key: &Key) -> Key {
+    Key::Tagged(Tag::Clock, Box::new(base_key.clone()))
+}
+
+/// What a node moves the instance clocks of its rounds to: ms since the node started,
+/// by tokio's clock, which tests and simulations pause, so that their runs replay.
+/// Nodes don't start at quite the same time, but a node whose clock is behind an
+/// instance's only leaves the instance's where it is, see `now`.
+#[derive(Debug)]
+pub struct LocalClock {
+    started_at: Instant,
+    skipped_ms: AtomicU64, // ms the clock went forward by at once, see `fast_forward`
+}
+
+impl LocalClock {
+    pub fn new() -> Self {
+        Self {
+            started_at: Instant::now(),
+            skipped_ms: AtomicU64::new(0),
+        }
+    }
+
+    pub fn now(&self) -> u64 {
+        self.skipped_ms.load(Ordering::Relaxed) + self.started_at.elapsed().as_millis() as u64
+    }
+
+    /// Moves the clock to at least `ms`, e.g. to the clocks of a restored snapshot's
+    /// instances, which would otherwise stand still until it caught up with them.
+    pub fn fast_forward(&self, ms: u64) {
+        let elapsed = self.started_at.elapsed().as_millis() as u64;
+        self.skipped_ms
+            .fetch_max(ms.saturating_sub(elapsed), Ordering::Relaxed);
+    }
+}
+
+/// The latest clock of the instances in `state_machine`, 0 if none has one.
+pub fn latest(state_machine: &InstanceState) -> u64 {
+    state_machine
+        .iter()
+        .filter(|(key, _)| matches!(key, Key::Tagged(Tag::Clock, _)))
+        .filter_map(|(_, clock)| clock.as_u64())
+        .max()
+        .unwrap_or(0)
+}
+
+/// The time of an op on `base_key`'s instance: `local_clock`, unless the instance's
+/// clock is already past it, as the clocks of the nodes don't quite agree.
+pub fn now(state_machine: &InstanceState, base_key: &Key, local_clock: u64) -> u64 {
+    state_machine
+        .read(&clock_key(base_key))
+        .and_then(Value::as_u64)
+        .map_or(local_clock, |clock| clock.max(local_clock))
+}
+
+/// Sets the clock of `base_key`'s instance to `now`, for the ops that read it.
+pub fn advance(state_machine: &mut InstanceState, base_key: &Key, now: u64) {
+    state_machine.write(clock_key(base_key), Value::from(now));
+}
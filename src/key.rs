@@ -0,0 +1,58 @@
+//! Keys of the replicated store. Clients name keys with ints or strings, as lin-kv
+//! allows, and txns keep entries about a key under tagged keys derived from it.
+
+use std::{
+    fmt,
+    hash::{DefaultHasher, Hash, Hasher},
+};
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[serde(untagged)]
+pub enum Key {
+    Int(usize),
+    Str(String),
+    // `[tag, key]` on the wire, a shape no client key has.
+    Tagged(Tag, Box<Key>),
+}
+
+/// What a tagged key holds about the key it's derived from, see `txn`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Tag {
+    Lock,
+    Staged,
+    Decision,
+    Clock,
+}
+
+impl Key {
+    /// Spreads keys over groups and lanes. Int keys are their own number, so
+    /// consecutive keys land in consecutive groups, and tagged keys go with their key.
+    pub fn number(&self) -> usize {
+        match self {
+            Key::Int(key) => *key,
+            Key::Str(key) => {
+                let mut hasher = DefaultHasher::new();
+                key.hash(&mut hasher);
+                hasher.finish() as usize
+            }
+            Key::Tagged(_, key) => key.number(),
+        }
+    }
+
+    pub fn is_tagged(&self) -> bool {
+        matches!(self, Key::Tagged(..))
+    }
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Key::Int(key) => write!(f, "{key}"),
+            Key::Str(key) => write!(f, "{key}"),
+            Key::Tagged(tag, key) => write!(f, "{tag:?}({key})"),
+        }
+    }
+}
@@ -1,80 +1,471 @@
-use serde::{ser::SerializeMap, Deserialize, Deserializer, Serialize, Serializer};
-use std::str::FromStr;
-use std::{collections::HashMap, hash::Hash};
+use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize, Serializer};
+use std::{
+    collections::HashMap,
+    hash::{DefaultHasher, Hash, Hasher},
+    ops::RangeBounds,
+};
 
 use super::message::ErrorCode;
 
-#[derive(Default, Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub(super) struct KeyValueStore<K, V>
 where
     K: Hash + Eq + Send,
-    V: PartialEq + Send,
+    V: Hash + PartialEq + Send,
 {
-    map: HashMap<K, V>,
+    map: HashMap<K, Entry<V>>,
+    // wrapping sum of every entry's hash, kept up to date by each write so the
+    // store can be compared against another one without hashing all of it.
+    digest: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Hash)]
+pub(super) struct Entry<V> {
+    // None once the key is deleted or expired. Its entry stays on as a tombstone,
+    // so that the next write goes on from its version rather than starting over.
+    pub value: Option<V>,
+    pub version: u64, // bumped by every write to the key
+    // when the key expires, on the clock of whoever keeps the store, see `expire`.
+    pub expires_at: Option<u64>,
+    // when the tombstone was left, on the same clock, see `compact`.
+    pub buried_at: Option<u64>,
+}
+
+// not derived, which would only make stores of `Default` keys and values.
+impl<K, V> Default for KeyValueStore<K, V>
+where
+    K: Hash + Eq + Send,
+    V: Hash + PartialEq + Send,
+{
+    fn default() -> Self {
+        Self {
+            map: HashMap::new(),
+            digest: 0,
+        }
+    }
+}
+
+fn entry_digest<K: Hash, V: Hash>(key: &K, entry: &Entry<V>) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    entry.hash(&mut hasher);
+    hasher.finish()
 }
 
 impl<K, V> KeyValueStore<K, V>
 where
     K: Hash + Eq + Send,
-    V: PartialEq + Send,
+    V: Hash + PartialEq + Send,
 {
-    pub fn new_with_inner(inner: HashMap<K, V>) -> Self {
-        Self { map: inner }
+    pub fn new_with_inner(inner: HashMap<K, Entry<V>>) -> Self {
+        let digest = inner.iter().fold(0, |digest: u64, (key, entry)| {
+            digest.wrapping_add(entry_digest(key, entry))
+        });
+        Self { map: inner, digest }
+    }
+
+    /// Order-independent digest of the store's content, available in O(1).
+    pub fn digest(&self) -> u64 {
+        self.digest
     }
 
+    #[allow(dead_code)]
     pub fn is_empty(&self) -> bool {
         self.map.is_empty()
     }
 
+    /// How many keys exist, tombstones aside.
+    pub fn len(&self) -> usize {
+        self.map
+            .values()
+            .filter(|entry| entry.value.is_some())
+            .count()
+    }
+
+    /// Rough estimate of the heap memory held by the store, in bytes.
+    pub fn approximate_size_bytes(&self) -> usize {
+        self.map.capacity() * (size_of::<K>() + size_of::<Entry<V>>())
+    }
+
     pub fn read(&self, key: &K) -> Option<&V> {
+        self.map.get(key)?.value.as_ref()
+    }
+
+    /// The value of `key`, if it exists, with its version. Keys start at version 1,
+    /// and a deleted key keeps its version until `compact` drops its tombstone, so
+    /// that a version doesn't come back; 0 stands for a key never written.
+    pub fn read_versioned(&self, key: &K) -> (Option<&V>, u64) {
+        self.map
+            .get(key)
+            .map_or((None, 0), |entry| (entry.value.as_ref(), entry.version))
+    }
+
+    /// The entry of `key`, which is a tombstone if the key was deleted.
+    pub fn entry(&self, key: &K) -> Option<&Entry<V>> {
         self.map.get(key)
     }
 
+    /// Writes `value` to `key`, which then never expires.
     pub fn write(&mut self, key: K, value: V) {
-        self.map.insert(key, value);
+        self.write_expiring(key, value, None);
     }
 
-    pub fn cas(&mut self, key: K, from: V, to: V) -> anyhow::Result<()> {
-        let res = self.map.get_mut(&key);
+    /// Writes `value` to `key`, which `expire` drops once its clock reaches
+    /// `expires_at`, unless a later write replaces it first.
+    pub fn write_expiring(&mut self, key: K, value: V, expires_at: Option<u64>) {
+        let version = self.map.get(&key).map_or(0, |entry| entry.version) + 1;
+        let entry = Entry {
+            value: Some(value),
+            version,
+            expires_at,
+            buried_at: None,
+        };
+        self.put(key, entry);
+    }
 
-        match res {
-            Some(current) => {
-                if *current != from {
-                    return Err(anyhow::Error::new(ErrorCode::PreconditionFailed));
-                }
-                *current = to;
+    /// Stores `entry` as is, for copying entries between stores.
+    pub fn put(&mut self, key: K, entry: Entry<V>) {
+        let added = entry_digest(&key, &entry);
+        if let Some(removed) = self.map.get(&key).map(|old| entry_digest(&key, old)) {
+            self.digest = self.digest.wrapping_sub(removed);
+        }
+        self.digest = self.digest.wrapping_add(added);
+        self.map.insert(key, entry);
+    }
+
+    /// Drops `key`'s entry, tombstone and all, returning its value if it had one.
+    /// Unlike `delete`, the key's version starts over.
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let removed = self.map.remove(key)?;
+        self.digest = self.digest.wrapping_sub(entry_digest(key, &removed));
+        removed.value
+    }
+
+    /// Deletes the keys that expired by `now`, returning whether there were any.
+    pub fn expire(&mut self, now: u64) -> bool {
+        let mut expired = false;
+        for (key, entry) in &mut self.map {
+            if entry.expires_at.is_some_and(|expires_at| expires_at <= now) {
+                self.digest = self.digest.wrapping_sub(entry_digest(key, entry));
+                bury(entry, now);
+                self.digest = self.digest.wrapping_add(entry_digest(key, entry));
+                expired = true;
+            }
+        }
+        expired
+    }
+
+    /// Drops the tombstones left before `buried_before`, returning whether there were
+    /// any. Their keys' versions start over, so this is only done once whoever could
+    /// still hold one of those versions gave up on it.
+    pub fn compact(&mut self, buried_before: u64) -> bool {
+        let len = self.map.len();
+        let digest = &mut self.digest;
+        self.map.retain(|key, entry| {
+            let is_due = entry
+                .buried_at
+                .is_some_and(|buried_at| buried_at < buried_before);
+            if is_due {
+                *digest = digest.wrapping_sub(entry_digest(key, entry));
+            }
+            !is_due
+        });
+        self.map.len() < len
+    }
+
+    /// The keys left as tombstones, with when they were buried.
+    pub fn tombstones(&self) -> impl Iterator<Item = (&K, u64)> {
+        self.map
+            .iter()
+            .filter_map(|(key, entry)| Some((key, entry.buried_at?)))
+    }
+
+    /// Deletes `key` at `now`, leaving its tombstone. An instance's state travels
+    /// whole in Promise/Accept and is adopted whole, tombstones included, so whoever
+    /// builds on or accepts that state deletes the key too.
+    pub fn delete(&mut self, key: &K, now: u64) -> anyhow::Result<()> {
+        let Some(entry) = self.map.get_mut(key).filter(|entry| entry.value.is_some()) else {
+            return Err(anyhow::Error::new(ErrorCode::KeyDoesNotExist));
+        };
+        self.digest = self.digest.wrapping_sub(entry_digest(key, entry));
+        bury(entry, now);
+        self.digest = self.digest.wrapping_add(entry_digest(key, entry));
+        Ok(())
+    }
+
+    /// Deletes `key` at `now` if it holds `from`.
+    pub fn cas_delete(&mut self, key: &K, from: V, now: u64) -> anyhow::Result<()> {
+        match self.read(key) {
+            Some(current) if *current != from => {
+                Err(anyhow::Error::new(ErrorCode::PreconditionFailed))
+            }
+            Some(_) => self.delete(key, now),
+            None => Err(anyhow::Error::new(ErrorCode::KeyDoesNotExist)),
+        }
+    }
+
+    /// Writes `value` to `key` unless the key already exists.
+    pub fn write_if_absent(
+        &mut self,
+        key: K,
+        value: V,
+        expires_at: Option<u64>,
+    ) -> anyhow::Result<()> {
+        if self.read(&key).is_some() {
+            return Err(anyhow::Error::new(ErrorCode::KeyAlreadyExists));
+        }
+        self.write_expiring(key, value, expires_at);
+        Ok(())
+    }
+
+    /// The keys in `range` that exist, sorted.
+    pub fn keys_in(&self, range: impl RangeBounds<K>) -> Vec<K>
+    where
+        K: Ord + Clone,
+    {
+        let mut keys: Vec<K> = self
+            .iter()
+            .map(|(key, _)| key)
+            .filter(|key| range.contains(*key))
+            .cloned()
+            .collect();
+        keys.sort_unstable();
+        keys
+    }
+
+    /// The keys that exist, with their values.
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.map
+            .iter()
+            .filter_map(|(key, entry)| Some((key, entry.value.as_ref()?)))
+    }
+
+    pub fn cas(&mut self, key: K, from: V, to: V) -> anyhow::Result<()> {
+        match self.read(&key) {
+            Some(current) if *current != from => {
+                Err(anyhow::Error::new(ErrorCode::PreconditionFailed))
+            }
+            Some(_) => {
+                self.write(key, to);
                 Ok(())
             }
             None => Err(anyhow::Error::new(ErrorCode::KeyDoesNotExist)),
         }
     }
+
+    /// Writes `value` to `key` if the key is still at `expected_version`, as
+    /// `read_versioned` gives it, whether or not the key exists.
+    pub fn cas_by_version(
+        &mut self,
+        key: K,
+        expected_version: u64,
+        value: V,
+    ) -> anyhow::Result<()> {
+        match self.read_versioned(&key) {
+            (_, version) if version == expected_version => {
+                self.write(key, value);
+                Ok(())
+            }
+            (None, _) => Err(anyhow::Error::new(ErrorCode::KeyDoesNotExist)),
+            (Some(_), _) => Err(anyhow::Error::new(ErrorCode::PreconditionFailed)),
+        }
+    }
+}
+
+/// Makes `entry` the tombstone of its key, as of `now`.
+fn bury<V>(entry: &mut Entry<V>, now: u64) {
+    entry.value = None;
+    entry.expires_at = None;
+    entry.buried_at = Some(now);
+}
+
+// Entries come with their version and deadline, so that copies of a store keep them.
+impl<K, V> IntoIterator for KeyValueStore<K, V>
+where
+    K: Hash + Eq + Send,
+    V: Hash + PartialEq + Send,
+{
+    type Item = (K, Entry<V>);
+    type IntoIter = std::collections::hash_map::IntoIter<K, Entry<V>>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.map.into_iter()
+    }
+}
+
+impl<K, V> FromIterator<(K, Entry<V>)> for KeyValueStore<K, V>
+where
+    K: Hash + Eq + Send,
+    V: Hash + PartialEq + Send,
+{
+    fn from_iter<I: IntoIterator<Item = (K, Entry<V>)>>(iter: I) -> Self {
+        Self::new_with_inner(iter.into_iter().collect())
+    }
+}
+
+// Entries go over the wire as a list of (key, value, version, expires_at) tuples,
+// and tombstones as (key, version, buried_at) ones, since keys aren't all strings, which the
+// keys of a JSON object have to be. They're sorted by key, so that equal stores
+// serialize to the same bytes, whatever order the map holds them in.
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum WireEntry<K, V> {
+    Live(K, V, u64, Option<u64>),
+    Tombstone(K, u64, Option<u64>),
 }
 
-impl<'de> Deserialize<'de> for KeyValueStore<usize, usize> {
+impl<'de, K, V> Deserialize<'de> for KeyValueStore<K, V>
+where
+    K: DeserializeOwned + Hash + Eq + Send,
+    V: DeserializeOwned + Hash + PartialEq + Send,
+{
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
         D: Deserializer<'de>,
     {
-        let incoming_map: HashMap<String, usize> = HashMap::deserialize(deserializer)?;
-        let inner = incoming_map
+        let entries: Vec<WireEntry<K, V>> = Vec::deserialize(deserializer)?;
+        Ok(entries
             .into_iter()
-            // this unwrap is fine -- this is just a toy implementation where all the clients and the nodes always use usize :)
-            .map(|(k, v)| (usize::from_str(&k).unwrap(), v))
-            .collect::<HashMap<usize, usize>>();
-
-        Ok(KeyValueStore::new_with_inner(inner))
+            .map(|entry| match entry {
+                WireEntry::Live(key, value, version, expires_at) => {
+                    let entry = Entry {
+                        value: Some(value),
+                        version,
+                        expires_at,
+                        buried_at: None,
+                    };
+                    (key, entry)
+                }
+                WireEntry::Tombstone(key, version, buried_at) => {
+                    let entry = Entry {
+                        value: None,
+                        version,
+                        expires_at: None,
+                        buried_at,
+                    };
+                    (key, entry)
+                }
+            })
+            .collect())
     }
 }
 
-impl Serialize for KeyValueStore<usize, usize> {
+impl<K, V> Serialize for KeyValueStore<K, V>
+where
+    K: Serialize + Hash + Ord + Send,
+    V: Serialize + Hash + PartialEq + Send,
+{
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: Serializer,
     {
-        let mut map = serializer.serialize_map(Some(self.map.len()))?;
-        for (k, v) in &self.map {
-            map.serialize_entry(k, v)?;
+        let mut entries: Vec<_> = self
+            .map
+            .iter()
+            .map(|(key, entry)| match &entry.value {
+                Some(value) => WireEntry::Live(key, value, entry.version, entry.expires_at),
+                None => WireEntry::Tombstone(key, entry.version, entry.buried_at),
+            })
+            .collect();
+        entries.sort_unstable_by_key(
+            |(WireEntry::Live(key, ..) | WireEntry::Tombstone(key, ..))| *key,
+        );
+        serializer.collect_seq(entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    fn store() -> KeyValueStore<String, Value> {
+        KeyValueStore::new_with_inner(HashMap::new())
+    }
+
+    fn code(result: anyhow::Result<()>) -> ErrorCode {
+        result.unwrap_err().downcast().unwrap()
+    }
+
+    #[test]
+    fn versions_survive_deletes() {
+        let mut store = store();
+        let key = "a".to_string();
+        assert_eq!(store.read_versioned(&key), (None, 0));
+        store.write(key.clone(), json!(1));
+        store.write(key.clone(), json!(2));
+        assert_eq!(store.read_versioned(&key), (Some(&json!(2)), 2));
+
+        store.delete(&key, 0).unwrap();
+        assert_eq!(store.read_versioned(&key), (None, 2));
+        assert_eq!(store.len(), 0);
+        assert_eq!(store.iter().count(), 0);
+        assert_eq!(code(store.delete(&key, 0)), ErrorCode::KeyDoesNotExist);
+
+        // whoever read version 1 before the delete can't write over the new value.
+        store.write(key.clone(), json!(1));
+        assert_eq!(store.read_versioned(&key), (Some(&json!(1)), 3));
+        assert_eq!(
+            code(store.cas_by_version(key.clone(), 1, json!(5))),
+            ErrorCode::PreconditionFailed
+        );
+        store.cas_by_version(key.clone(), 3, json!(5)).unwrap();
+        assert_eq!(store.read_versioned(&key), (Some(&json!(5)), 4));
+    }
+
+    #[test]
+    fn expired_keys_keep_their_version() {
+        let mut store = store();
+        let key = "a".to_string();
+        store.write_expiring(key.clone(), json!(1), Some(10));
+        assert!(!store.expire(9));
+        assert!(store.expire(10));
+        assert_eq!(store.read_versioned(&key), (None, 1));
+        assert!(!store.expire(11));
+        assert_eq!(
+            code(store.cas_by_version(key.clone(), 0, json!(2))),
+            ErrorCode::KeyDoesNotExist
+        );
+        store.cas_by_version(key.clone(), 1, json!(2)).unwrap();
+        assert_eq!(store.read_versioned(&key), (Some(&json!(2)), 2));
+    }
+
+    #[test]
+    fn tombstones_go_over_the_wire() {
+        let mut store = store();
+        store.write_expiring("a".to_string(), json!(1), Some(10));
+        store.write("b".to_string(), Value::Null);
+        store.write("c".to_string(), json!(3));
+        store.delete(&"c".to_string(), 20).unwrap();
+
+        let wire = serde_json::to_value(&store).unwrap();
+        assert_eq!(
+            wire,
+            json!([["a", 1, 1, 10], ["b", null, 1, null], ["c", 1, 20]])
+        );
+        let decoded: KeyValueStore<String, Value> = serde_json::from_value(wire).unwrap();
+        assert_eq!(decoded, store);
+        assert_eq!(decoded.digest(), store.digest());
+    }
+
+    #[test]
+    fn compaction_drops_old_tombstones_only() {
+        let mut store = store();
+        for key in ["a", "b", "c"] {
+            store.write(key.to_string(), json!(1));
         }
-        map.end()
+        store.delete(&"a".to_string(), 10).unwrap();
+        store.delete(&"b".to_string(), 20).unwrap();
+        let before = store.clone();
+
+        assert!(!store.compact(10));
+        assert!(store.compact(15));
+        assert_eq!(store.read_versioned(&"a".to_string()), (None, 0));
+        assert_eq!(store.read_versioned(&"b".to_string()), (None, 1));
+        assert_eq!(store.read(&"c".to_string()), Some(&json!(1)));
+        assert_ne!(store.digest(), before.digest());
+        let copy: KeyValueStore<String, Value> = store.clone().into_iter().collect();
+        assert_eq!(copy.digest(), store.digest());
     }
 }
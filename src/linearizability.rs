@@ -0,0 +1,412 @@
+//! Checks a history of client ops on registers for linearizability, as Wing & Gong
+//! propose, with Lowe's memo of the states already searched, as porcupine does: it
+//! looks for an order of the ops that a register gives the same results in, with
+//! every op taking effect some time between its call and its return. Each key is a
+//! register of its own, so each key's ops are checked apart from the rest.
+//!
+//! Ops whose outcome the client never learned, e.g. as they timed out, never return:
+//! they may take effect any time after their call, or never, which is the same as
+//! taking effect after everything else. Every order of them would be searched, so the
+//! search only puts one where it changes what the register holds, and values nothing
+//! reads or cases from are all taken as one.
+
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashSet},
+    fmt::Write,
+};
+
+use anyhow::bail;
+
+use crate::key::Key;
+
+/// A client op on a register, with the result it got.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    // what the read returned, None if the key didn't exist.
+    Read(Option<u64>),
+    Write(u64),
+    Cas { from: u64, to: u64 },
+    // the key didn't exist, or held something other than `from`.
+    CasFailed { from: u64, existed: bool },
+}
+
+impl Op {
+    /// What the register holds after the op, if the op can have the result it got on
+    /// a register that held `state`. `returned` is false for an op of unknown
+    /// outcome, which can have any result.
+    fn step(self, state: Option<u64>, returned: bool) -> Option<Option<u64>> {
+        match self {
+            Op::Read(value) => (value == state).then_some(state),
+            Op::Write(value) => Some(Some(value)),
+            Op::Cas { from, to } if state == Some(from) => Some(Some(to)),
+            Op::Cas { .. } if !returned => Some(state),
+            Op::Cas { .. } => None,
+            Op::CasFailed { from, existed } => match state {
+                Some(value) if existed && value != from => Some(state),
+                None if !existed => Some(state),
+                _ => None,
+            },
+        }
+    }
+}
+
+/// One op as the client saw it. Times are logical: each call and return is one tick.
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub client: usize,
+    pub op: Op,
+    pub call: usize,
+    // None if the op's outcome is unknown.
+    pub ret: Option<usize>,
+}
+
+/// Every op clients called, with what they returned, in the order they returned.
+#[derive(Debug, Default)]
+pub struct History {
+    clock: RefCell<usize>,
+    entries: RefCell<BTreeMap<Key, Vec<Entry>>>,
+}
+
+impl History {
+    /// Ticks for a call, returning its time, for `record` once the op returns.
+    pub fn call(&self) -> usize {
+        self.tick()
+    }
+
+    /// Records an op called at `call` that returned just now, or that never will if
+    /// `returned` is false. Reads of unknown outcome leave nothing to check.
+    pub fn record(&self, client: usize, key: Key, call: usize, op: Op, returned: bool) {
+        if !returned && matches!(op, Op::Read(_)) {
+            return;
+        }
+        let ret = returned.then(|| self.tick());
+        self.entries
+            .borrow_mut()
+            .entry(key)
+            .or_default()
+            .push(Entry {
+                client,
+                op,
+                call,
+                ret,
+            });
+    }
+
+    /// How many ops there are to check.
+    pub fn ops(&self) -> usize {
+        self.entries.borrow().values().map(Vec::len).sum()
+    }
+
+    /// Fails with the ops of the first key no register could have given the
+    /// results of.
+    pub fn check(&self) -> anyhow::Result<()> {
+        for (key, entries) in self.entries.borrow().iter() {
+            if !is_linearizable(entries) {
+                let mut entries = entries.clone();
+                entries.sort_by_key(|entry| entry.call);
+                let mut ops = String::new();
+                for entry in entries {
+                    let ret = entry.ret.map_or("never".to_string(), |ret| ret.to_string());
+                    let _ = write!(
+                        ops,
+                        "\n  client {} {:?}, called at {}, returned {ret}",
+                        entry.client, entry.op, entry.call
+                    );
+                }
+                bail!("the ops on {key} aren't linearizable:{ops}");
+            }
+        }
+        Ok(())
+    }
+
+    fn tick(&self) -> usize {
+        let mut clock = self.clock.borrow_mut();
+        *clock += 1;
+        *clock
+    }
+}
+
+// What the values nothing observes are all taken as, never a value written.
+const UNOBSERVED: u64 = u64::MAX;
+
+/// Whether the ops on one register, starting out without a value, can be ordered.
+fn is_linearizable(entries: &[Entry]) -> bool {
+    // a value no op reads or cases from only makes the key exist, and hold something
+    // other than every value that is, so which value it is doesn't matter.
+    let observed: HashSet<u64> = entries
+        .iter()
+        .filter_map(|entry| match entry.op {
+            Op::Read(value) => value,
+            Op::Cas { from, .. } | Op::CasFailed { from, .. } => Some(from),
+            Op::Write(_) => None,
+        })
+        .collect();
+    let unless_observed = |value| match observed.contains(&value) {
+        true => value,
+        false => UNOBSERVED,
+    };
+    let entries: Vec<Entry> = entries
+        .iter()
+        .cloned()
+        .map(|mut entry| {
+            entry.op = match entry.op {
+                Op::Write(value) => Op::Write(unless_observed(value)),
+                Op::Cas { from, to } => Op::Cas {
+                    from,
+                    to: unless_observed(to),
+                },
+                op => op,
+            };
+            entry
+        })
+        .collect();
+
+    let mut search = Search {
+        entries: &entries,
+        done: vec![false; entries.len()],
+        left: entries.iter().filter(|entry| entry.ret.is_some()).count(),
+        seen: HashSet::new(),
+    };
+    search.from(None)
+}
+
+struct Search<'a> {
+    entries: &'a [Entry],
+    // the ops ordered so far.
+    done: Vec<bool>,
+    // returned ops not ordered yet. Once there are none, the ops that never returned
+    // can go after them all.
+    left: usize,
+    // the ops ordered and the state they left, of searches that found no order.
+    seen: HashSet<(Vec<bool>, Option<u64>)>,
+}
+
+impl Search<'_> {
+    fn from(&mut self, state: Option<u64>) -> bool {
+        if self.left == 0 {
+            return true;
+        }
+        if !self.seen.insert((self.done.clone(), state)) {
+            return false;
+        }
+        // an op can come next if no op left returned before it was called.
+        let first_ret = self
+            .entries
+            .iter()
+            .zip(&self.done)
+            .filter_map(|(entry, done)| entry.ret.filter(|_| !done))
+            .min()
+            .expect("returned ops are left");
+        for i in 0..self.entries.len() {
+            let entry = &self.entries[i];
+            if self.done[i] || entry.call > first_ret {
+                continue;
+            }
+            let Some(next) = entry.op.step(state, entry.ret.is_some()) else {
+                continue;
+            };
+            // an op that never returned can go any time later, so it only goes now if
+            // that changes the register, and before any like it called after it.
+            if entry.ret.is_none() && (next == state || self.is_preceded(i)) {
+                continue;
+            }
+            self.done[i] = true;
+            self.left -= usize::from(entry.ret.is_some());
+            if self.from(next) {
+                return true;
+            }
+            self.done[i] = false;
+            self.left += usize::from(entry.ret.is_some());
+        }
+        false
+    }
+
+    /// Whether an op left the same as `i`, that never returned either, was called first.
+    fn is_preceded(&self, i: usize) -> bool {
+        let entry = &self.entries[i];
+        self.entries.iter().zip(&self.done).any(|(other, done)| {
+            !done && other.ret.is_none() && other.op == entry.op && other.call < entry.call
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: Key = Key::Int(1);
+
+    #[derive(Clone, Copy, PartialEq)]
+    enum Step {
+        Call(usize),
+        Return(usize),
+    }
+    use Step::{Call, Return};
+
+    /// Records ops on KEY as `steps` go: `Call(i)` calls the i-th of `ops`, then
+    /// `Return(i)` returns it. Ops never returned are of unknown outcome.
+    fn history(ops: &[Op], steps: &[Step]) -> History {
+        let history = History::default();
+        let mut calls = vec![None; ops.len()];
+        for step in steps {
+            match *step {
+                Step::Call(i) => calls[i] = Some(history.call()),
+                Step::Return(i) => history.record(i, KEY, calls[i].unwrap(), ops[i], true),
+            }
+        }
+        for (i, call) in calls.into_iter().enumerate() {
+            match call {
+                Some(call) if !steps.contains(&Step::Return(i)) => {
+                    history.record(i, KEY, call, ops[i], false)
+                }
+                _ => (),
+            }
+        }
+        history
+    }
+
+    /// Each op called once the one before it returned.
+    fn sequential(ops: &[Op]) -> History {
+        let steps: Vec<Step> = (0..ops.len()).flat_map(|i| [Call(i), Return(i)]).collect();
+        history(ops, &steps)
+    }
+
+    #[test]
+    fn sequential_ops_a_register_gives_are_linearizable() {
+        let ops = [
+            Op::Read(None),
+            Op::CasFailed {
+                from: 1,
+                existed: false,
+            },
+            Op::Write(1),
+            Op::Cas { from: 1, to: 2 },
+            Op::CasFailed {
+                from: 1,
+                existed: true,
+            },
+            Op::Read(Some(2)),
+        ];
+        assert!(sequential(&ops).check().is_ok());
+    }
+
+    #[test]
+    fn stale_reads_arent_linearizable() {
+        let ops = [Op::Write(1), Op::Write(2), Op::Read(Some(1))];
+        assert!(sequential(&ops).check().is_err());
+        // unless the read overlaps the write it missed.
+        let steps = [Call(0), Return(0), Call(1), Call(2), Return(2), Return(1)];
+        assert!(history(&ops, &steps).check().is_ok());
+    }
+
+    #[test]
+    fn lost_cases_arent_linearizable() {
+        // both cases can't have found 1.
+        let ops = [
+            Op::Write(1),
+            Op::Cas { from: 1, to: 2 },
+            Op::Cas { from: 1, to: 3 },
+        ];
+        assert!(sequential(&ops).check().is_err());
+        let steps = [Call(0), Return(0), Call(1), Call(2), Return(1), Return(2)];
+        assert!(history(&ops, &steps).check().is_err());
+        // a read of the first case's value after the second one returned loses it too.
+        let ops = [
+            Op::Write(1),
+            Op::Cas { from: 1, to: 2 },
+            Op::Write(3),
+            Op::Read(Some(2)),
+        ];
+        let steps = [
+            Call(0),
+            Return(0),
+            Call(1),
+            Return(1),
+            Call(2),
+            Return(2),
+            Call(3),
+            Return(3),
+        ];
+        assert!(history(&ops, &steps).check().is_err());
+    }
+
+    #[test]
+    fn failed_cases_have_to_fail_for_the_reason_they_got() {
+        let ops = [
+            Op::Write(1),
+            Op::CasFailed {
+                from: 2,
+                existed: false,
+            },
+        ];
+        assert!(sequential(&ops).check().is_err());
+        let ops = [
+            Op::Write(1),
+            Op::CasFailed {
+                from: 1,
+                existed: true,
+            },
+        ];
+        assert!(sequential(&ops).check().is_err());
+    }
+
+    #[test]
+    fn ops_of_unknown_outcome_may_take_effect_any_time_after_their_call() {
+        // the write of 2 never returned, but took effect before the read.
+        let ops = [Op::Write(1), Op::Write(2), Op::Read(Some(2))];
+        let steps = [Call(0), Return(0), Call(1), Call(2), Return(2)];
+        assert!(history(&ops, &steps).check().is_ok());
+        // or never did.
+        let ops = [Op::Write(1), Op::Write(2), Op::Read(Some(1))];
+        assert!(history(&ops, &steps).check().is_ok());
+        // but not before it was called.
+        let steps = [Call(0), Return(0), Call(2), Return(2), Call(1)];
+        let ops = [Op::Write(1), Op::Write(2), Op::Read(Some(2))];
+        assert!(history(&ops, &steps).check().is_err());
+    }
+
+    #[test]
+    fn ops_of_unknown_outcome_take_effect_once() {
+        // once read, the unknown write of 2 stays, as nothing writes 1 again.
+        let ops = [
+            Op::Write(1),
+            Op::Write(2),
+            Op::Read(Some(2)),
+            Op::Read(Some(1)),
+        ];
+        let steps = [
+            Call(0),
+            Return(0),
+            Call(1),
+            Call(2),
+            Return(2),
+            Call(3),
+            Return(3),
+        ];
+        assert!(history(&ops, &steps).check().is_err());
+        // an unknown case can only have taken effect if it found its `from`.
+        let ops = [Op::Write(1), Op::Cas { from: 3, to: 2 }, Op::Read(Some(2))];
+        let steps = [Call(0), Return(0), Call(1), Call(2), Return(2)];
+        assert!(history(&ops, &steps).check().is_err());
+        let ops = [Op::Write(1), Op::Cas { from: 1, to: 2 }, Op::Read(Some(2))];
+        assert!(history(&ops, &steps).check().is_ok());
+    }
+
+    #[test]
+    fn keys_are_checked_apart() {
+        let history = History::default();
+        for (key, value) in [(Key::Int(1), 1), (Key::Int(2), 2)] {
+            let call = history.call();
+            history.record(0, key, call, Op::Write(value), true);
+        }
+        let call = history.call();
+        history.record(0, Key::Int(1), call, Op::Read(Some(2)), true);
+        let error = history.check().unwrap_err().to_string();
+        assert!(
+            error.starts_with("the ops on 1 aren't linearizable"),
+            "{error}"
+        );
+        assert_eq!(history.ops(), 3);
+    }
+}
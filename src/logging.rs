@@ -0,0 +1,37 @@
+use std::{str::FromStr, sync::OnceLock};
+
+use anyhow::{anyhow, Context};
+use tracing_subscriber::{
+    filter::LevelFilter, layer::SubscriberExt, reload, util::SubscriberInitExt, Registry,
+};
+
+// Set once logging is up, so the level can be changed while the node runs.
+static LEVEL_HANDLE: OnceLock<reload::Handle<LevelFilter, Registry>> = OnceLock::new();
+
+pub fn init(level: LevelFilter) {
+    let (level_filter, level_handle) = reload::Layer::new(level);
+    let fmt_layer = tracing_subscriber::fmt::layer()
+        .with_file(true)
+        .with_line_number(true)
+        .with_target(true)
+        .with_level(false)
+        .with_writer(std::io::stderr)
+        .with_thread_ids(true)
+        .with_ansi(false);
+
+    tracing_subscriber::registry()
+        .with(level_filter)
+        .with(fmt_layer)
+        .init();
+    LEVEL_HANDLE.set(level_handle).unwrap();
+}
+
+/// Swaps the max level of logged events, e.g. "trace" or "off".
+pub fn set_level(level: &str) -> anyhow::Result<()> {
+    let level = LevelFilter::from_str(level).context("unknown log level")?;
+    LEVEL_HANDLE
+        .get()
+        .ok_or_else(|| anyhow!("logging is disabled"))?
+        .reload(level)
+        .context("couldn't reload the log level")
+}
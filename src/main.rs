@@ -1,25 +1,69 @@
 use std::sync::Arc;
 
 use cas_paxos::CASPaxos;
+use config::Config;
+use snapshot::Snapshot;
+use transport::Stdio;
 
+mod ballot;
+mod broadcast;
 mod cas_paxos;
+mod changelog;
+mod chaos;
+mod config;
+mod counter;
+mod crdt;
+mod expiry;
+mod key;
 mod kv_store;
+mod linearizability;
+mod logging;
+mod membership;
 mod message;
 mod node;
+mod profiling;
+mod rate_limit;
+mod rng;
+mod sim;
+mod snapshot;
+mod state_machine;
+mod stats;
+mod transport;
+mod txn;
+
+fn main() {
+    let mut args = std::env::args().skip(1).peekable();
+    if args.next_if(|arg| arg == "simulate").is_some() {
+        if let Err(e) = sim::main(args) {
+            eprintln!("simulation failed: {e:#}");
+            std::process::exit(1);
+        }
+        return;
+    }
+    serve(Config::from_args(args).unwrap_or_else(|e| e.exit()));
+}
 
 #[tokio::main]
-async fn main() {
-    let subscriber = tracing_subscriber::fmt()
-        .with_file(true)
-        .with_line_number(true)
-        .with_target(true)
-        .with_level(false)
-        .with_writer(std::io::stderr)
-        .with_thread_ids(true)
-        .with_ansi(false)
-        .with_max_level(tracing::Level::DEBUG)
-        .finish();
-    tracing::subscriber::set_global_default(subscriber).unwrap();
+async fn serve(config: Config) {
+    if config.quiet_bench {
+        tracing::subscriber::set_global_default(tracing::subscriber::NoSubscriber::default())
+            .unwrap();
+        profiling::disable();
+    } else {
+        logging::init(config.log_level);
+    }
+
+    let snapshot = config
+        .restore
+        .as_deref()
+        .map(|path| Snapshot::read_from(path).unwrap());
+    let cas_paxos = CASPaxos::new(config, Arc::new(Stdio::default()));
+    if let Some(snapshot) = snapshot {
+        if let Err(e) = cas_paxos.restore(snapshot).await {
+            eprintln!("couldn't restore the snapshot: {e}");
+            std::process::exit(1);
+        }
+    }
 
-    Arc::new(CASPaxos::new()).run().await;
+    Arc::new(cas_paxos).run().await;
 }
@@ -0,0 +1,40 @@
+//! Runtime changes to the set of nodes taking part in consensus. The member set is a
+//! register in the replicated store like any other, so AddNode/RemoveNode run as
+//! CASPaxos ops on it, and every node that stores a new value of the register starts
+//! using it for its quorums.
+//!
+//! Members are stored as a bitmap over node numbers, i.e. the `k` of an "n<k>" node
+//! id. Unlike a NodeIndex, a node number means the same node to every node, whatever
+//! order they learnt about the members in.
+
+use crate::key::Key;
+
+/// The reserved key of the members register. Client ops on it are rejected.
+pub const MEMBERSHIP_KEY: Key = Key::Int(usize::MAX >> 2);
+
+// node numbers have to fit in the bitmap
+const MAX_NODE_NUMBER: u32 = usize::BITS;
+
+/// The `k` of an "n<k>" node id, if `node_id` has that shape and `k` fits the bitmap.
+pub fn node_number(node_id: &str) -> Option<u32> {
+    node_id
+        .strip_prefix('n')?
+        .parse()
+        .ok()
+        .filter(|number| *number < MAX_NODE_NUMBER)
+}
+
+/// The register value for `members`, or None if one of them has no node number.
+pub fn encode<'a>(members: impl IntoIterator<Item = &'a String>) -> Option<usize> {
+    members
+        .into_iter()
+        .try_fold(0, |bitmap, id| Some(bitmap | 1 << node_number(id)?))
+}
+
+/// The members in a register value, ordered by node number.
+pub fn decode(bitmap: usize) -> Vec<String> {
+    (0..MAX_NODE_NUMBER)
+        .filter(|number| bitmap & (1 << number) != 0)
+        .map(|number| format!("n{number}"))
+        .collect()
+}
@@ -1,7 +1,19 @@
+use std::collections::HashMap;
+
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use serde_repr::{Deserialize_repr, Serialize_repr};
 
-use crate::kv_store::KeyValueStore;
+use crate::{
+    ballot::BallotNumber,
+    changelog::Change,
+    crdt::LwwMap,
+    key::Key,
+    kv_store::KeyValueStore,
+    state_machine::register_key,
+    stats::{Health, StatsSnapshot},
+    txn::TxnOp,
+};
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Message {
@@ -13,7 +25,13 @@ pub struct Message {
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[serde(rename_all = "snake_case")]
 pub struct BodyWithMsgId {
+    // Maelstrom services such as lin-kv leave it out of their replies.
+    #[serde(default)]
     pub msg_id: usize,
+    // set on the copies of a msg sent again for want of a reply, see `Node::broadcast_to`,
+    // or duplicated by `Chaos`.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub resent: bool,
     #[serde(flatten)]
     pub inner: Body,
 }
@@ -29,70 +47,448 @@ pub enum Body {
         in_reply_to: usize,
     },
     Read {
-        key: usize, // technically it should be Any
+        // register clients don't name the key, see `state_machine::REGISTER_KEY`.
+        #[serde(default = "register_key")]
+        key: Key,
     },
     ReadOk {
         in_reply_to: usize,
-        value: usize,
+        value: Value,
+        // the ballot the read was decided at, only set with --debug-read-ballots.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ballot_number: Option<BallotNumber>,
     },
     Write {
-        key: usize, // technically it should be Any
-        value: usize,
+        #[serde(default = "register_key")]
+        key: Key,
+        value: Value,
+        // only write if the key doesn't exist yet, failing with error 21 otherwise.
+        // Left out when unset, for the lin-kv service, whose writes don't have it.
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        create_if_not_exists: bool,
+        // reads fail with error 20 this long after the write, until the next one.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expiry_ms: Option<u64>,
     },
     WriteOk {
         in_reply_to: usize,
     },
     Cas {
-        key: usize, // technically it should be Any
-        from: usize,
-        to: usize,
+        #[serde(default = "register_key")]
+        key: Key,
+        from: Value,
+        to: Value,
+        // write `to` if the key doesn't exist, rather than failing with error 20.
+        #[serde(default)]
+        create_if_not_exists: bool,
     },
     CasOk {
         in_reply_to: usize,
     },
+    Delete {
+        key: Key,
+    },
+    DeleteOk {
+        in_reply_to: usize,
+    },
+    // deletes the key if it holds `from`.
+    CasDelete {
+        key: Key,
+        from: Value,
+    },
+    CasDeleteOk {
+        in_reply_to: usize,
+    },
+    // reads `key` along with its version, which a key keeps even once deleted, so
+    // unlike Read this succeeds whether or not the key exists.
+    ReadVersion {
+        key: Key,
+    },
+    ReadVersionOk {
+        in_reply_to: usize,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        value: Option<Value>, // left out if the key doesn't exist
+        version: u64, // 0 if the key was never written
+    },
+    // writes `value` to `key` if the key is still at `version`, failing with error
+    // 22 otherwise, or with error 20 if the key doesn't exist.
+    CasVersion {
+        key: Key,
+        version: u64,
+        value: Value,
+    },
+    CasVersionOk {
+        in_reply_to: usize,
+    },
+    // reads every key in [from, to), each the way Read would, see `CASPaxos::scan`.
+    Scan {
+        from: Key,
+        to: Key,
+    },
+    ScanOk {
+        in_reply_to: usize,
+        values: Vec<(Key, Value)>, // the keys that exist, by key
+    },
+    // reads each of `keys` the way Read would: every value is linearizable on its
+    // own, though not as of the same point in time as the others.
+    MultiRead {
+        keys: Vec<Key>,
+    },
+    MultiReadOk {
+        in_reply_to: usize,
+        values: Vec<(Key, Value)>, // the keys that exist, in request order
+    },
     Proxy {
         proxied_msg: Box<Message>,
     },
+    // Each key is its own CASPaxos instance, so the consensus msgs name the key
+    // whose round they're part of, and carry only the entries of that instance.
     Propose {
-        ballot_number: usize,
+        key: Key,
+        ballot_number: BallotNumber,
     },
     Promise {
-        ballot_number: usize,
-        value: KeyValueStore<usize, usize>,
+        in_reply_to: usize, // the Propose, so that it reaches the round that sent it
+        key: Key,
+        ballot_number: BallotNumber,          // the one promised
+        accepted_ballot_number: BallotNumber, // the one `value` was accepted at
+        value: KeyValueStore<Key, Value>,
     },
     Accept {
-        ballot_number: usize,
-        value: KeyValueStore<usize, usize>,
+        key: Key,
+        ballot_number: BallotNumber,
+        value: KeyValueStore<Key, Value>,
     },
     Accepted {
-        ballot_number: usize,
+        in_reply_to: usize, // the Accept, so that it reaches the round that sent it
+        key: Key,
+        ballot_number: BallotNumber,
     },
     Error {
         in_reply_to: usize,
         code: ErrorCode,
         text: String,
+        // set along with TemporarilyUnavailable, when the client should wait before retrying.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        retry_after_ms: Option<u64>,
+        // set along with PreconditionFailed when a ballot is rejected: the greater
+        // ballot it lost to, which the proposer's next ballot should go past.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ballot_hint: Option<BallotNumber>,
+    },
+    // answered by the node itself, for smoke-testing the transport without consensus.
+    Echo {
+        echo: Value,
+    },
+    EchoOk {
+        in_reply_to: usize,
+        echo: Value,
+    },
+    // the broadcast workload, see `broadcast`. Its reads are Read with no key, whose
+    // read_ok lists the messages rather than holding a value.
+    Topology {
+        topology: HashMap<String, Vec<String>>,
+    },
+    TopologyOk {
+        in_reply_to: usize,
+    },
+    Broadcast {
+        message: u64,
+    },
+    BroadcastOk {
+        in_reply_to: usize,
+    },
+    #[serde(rename = "read_ok", skip_deserializing)]
+    BroadcastReadOk {
+        in_reply_to: usize,
+        messages: Vec<u64>,
+    },
+    // messages new to the sender, for its neighbors, which ack them with GossipOk.
+    Gossip {
+        messages: Vec<u64>,
+    },
+    GossipOk {
+        in_reply_to: usize,
+    },
+    // adds to the counter of the g-counter and pn-counter workloads, see `counter`.
+    Add {
+        delta: i64,
+    },
+    AddOk {
+        in_reply_to: usize,
+    },
+    // a cluster-wide unique id, see `CASPaxos::generate_id`.
+    Generate {},
+    GenerateOk {
+        in_reply_to: usize,
+        id: String,
+    },
+    Stats {},
+    SetLogLevel {
+        level: String,
+    },
+    SetLogLevelOk {
+        in_reply_to: usize,
+    },
+    Pause {
+        #[serde(default)]
+        mode: PauseMode,
+    },
+    PauseOk {
+        in_reply_to: usize,
+    },
+    Resume {},
+    ResumeOk {
+        in_reply_to: usize,
+    },
+    // Runs a proposal round for `key` that leaves the state machine untouched.
+    ForcePropose {
+        key: Key,
+    },
+    ForceProposeOk {
+        in_reply_to: usize,
+    },
+    // Delays msgs to `peer` by `ms` milliseconds, for the next `duration` milliseconds.
+    InjectLatency {
+        peer: String,
+        ms: u64,
+        duration: u64,
+    },
+    InjectLatencyOk {
+        in_reply_to: usize,
+    },
+    // Adds `node_id` to, or removes it from, the nodes taking part in consensus.
+    // The change is itself agreed on through a CASPaxos round, see `membership`.
+    AddNode {
+        node_id: String,
+    },
+    AddNodeOk {
+        in_reply_to: usize,
+    },
+    RemoveNode {
+        node_id: String,
+    },
+    RemoveNodeOk {
+        in_reply_to: usize,
+    },
+    Health {},
+    ChangesSince {
+        #[serde(default)]
+        cursor: u64,
+    },
+    ChangesSinceOk {
+        in_reply_to: usize,
+        changes: Vec<Change>,
+        cursor: u64,     // to pass to the next changes_since
+        truncated: bool, // some changes after the requested cursor were compacted
+    },
+    WriteSnapshot {
+        path: String,
+    },
+    WriteSnapshotOk {
+        in_reply_to: usize,
+    },
+    // peers send these to each other so that silence means a node is unreachable.
+    // They carry the digest of the sender's state, to catch diverging replicas.
+    Heartbeat {
+        #[serde(default)]
+        ballots_digest: u64, // of the ballot each of the sender's keys was accepted at
+        #[serde(default)]
+        state_digest: u64,
+    },
+    // Asks a peer for the ballot and state digest of each of its accepted keys,
+    // to tell which keys diverge once the heartbeat digests say some do.
+    InstanceDigests {},
+    InstanceDigestsOk {
+        in_reply_to: usize,
+        digests: Vec<(Key, BallotNumber, u64)>, // (key, accepted ballot, state digest), by key
+    },
+    // Pulls the accepted state of `keys` from a peer that accepted them at greater
+    // ballots than we did, see `CASPaxos::sync_loop`.
+    SyncState {
+        keys: Vec<Key>,
+    },
+    SyncStateOk {
+        in_reply_to: usize,
+        instances: Vec<(Key, BallotNumber, KeyValueStore<Key, Value>)>, // (key, accepted ballot, state)
+    },
+    // The client keys in [from, to) a peer stores, for a Scan to read.
+    ScanKeys {
+        from: Key,
+        to: Key,
+    },
+    ScanKeysOk {
+        in_reply_to: usize,
+        keys: Vec<Key>,
+    },
+    LwwMerge {
+        registers: LwwMap,
+    },
+    HealthOk {
+        in_reply_to: usize,
+        health: Health,
+    },
+    StatsOk {
+        in_reply_to: usize,
+        stats: StatsSnapshot,
+    },
+    Txn {
+        txn: Vec<TxnOp>,
+    },
+    TxnOk {
+        in_reply_to: usize,
+        txn: Vec<TxnOp>,
+    },
+    // the steps of a cross-group txn, each run as a CASPaxos op by the group it concerns.
+    TxnPrepare {
+        txn_id: usize,
+        txn: Vec<TxnOp>,
+    },
+    TxnPrepareOk {
+        in_reply_to: usize,
+        txn: Vec<TxnOp>,
+    },
+    TxnDecide {
+        txn_id: usize,
+        commit: bool,
+    },
+    TxnDecideOk {
+        in_reply_to: usize,
+        commit: bool, // the decision that stands, which may not be the one asked for
+    },
+    TxnFinish {
+        txn_id: usize,
+        commit: bool,
+        keys: Vec<Key>,
+    },
+    TxnFinishOk {
+        in_reply_to: usize,
+    },
+    TxnForget {
+        txn_id: usize,
+    },
+    TxnForgetOk {
+        in_reply_to: usize,
+    },
+    // Several node-to-node messages to the same peer, coalesced into one envelope.
+    // Node unpacks it on receipt, so it never reaches the protocol handlers.
+    Batch {
+        msgs: Vec<Message>,
     },
 }
 
 impl Body {
+    /// An error reply to msg `in_reply_to`, with no retry or ballot hint.
+    pub fn error(in_reply_to: usize, code: ErrorCode, text: impl Into<String>) -> Self {
+        Body::Error {
+            in_reply_to,
+            code,
+            text: text.into(),
+            retry_after_ms: None,
+            ballot_hint: None,
+        }
+    }
+
+    /// The key targeted by a client operation.
+    pub fn key(&self) -> Option<&Key> {
+        match self {
+            Body::Read { key }
+            | Body::Write { key, .. }
+            | Body::Cas { key, .. }
+            | Body::Delete { key }
+            | Body::CasDelete { key, .. }
+            | Body::ReadVersion { key }
+            | Body::CasVersion { key, .. }
+            | Body::ForcePropose { key } => Some(key),
+            _ => None,
+        }
+    }
+
     pub fn in_reply_to(&self) -> Option<usize> {
         match self {
             Body::ReadOk { in_reply_to, .. }
             | Body::WriteOk { in_reply_to, .. }
             | Body::CasOk { in_reply_to, .. }
+            | Body::DeleteOk { in_reply_to, .. }
+            | Body::CasDeleteOk { in_reply_to, .. }
+            | Body::ReadVersionOk { in_reply_to, .. }
+            | Body::CasVersionOk { in_reply_to, .. }
+            | Body::ScanOk { in_reply_to, .. }
+            | Body::MultiReadOk { in_reply_to, .. }
+            | Body::EchoOk { in_reply_to, .. }
+            | Body::GenerateOk { in_reply_to, .. }
+            | Body::AddOk { in_reply_to, .. }
+            | Body::TopologyOk { in_reply_to, .. }
+            | Body::BroadcastOk { in_reply_to, .. }
+            | Body::BroadcastReadOk { in_reply_to, .. }
+            | Body::GossipOk { in_reply_to, .. }
+            | Body::StatsOk { in_reply_to, .. }
+            | Body::SetLogLevelOk { in_reply_to, .. }
+            | Body::PauseOk { in_reply_to, .. }
+            | Body::ResumeOk { in_reply_to, .. }
+            | Body::ForceProposeOk { in_reply_to, .. }
+            | Body::InjectLatencyOk { in_reply_to, .. }
+            | Body::AddNodeOk { in_reply_to, .. }
+            | Body::InstanceDigestsOk { in_reply_to, .. }
+            | Body::SyncStateOk { in_reply_to, .. }
+            | Body::ScanKeysOk { in_reply_to, .. }
+            | Body::RemoveNodeOk { in_reply_to, .. }
+            | Body::HealthOk { in_reply_to, .. }
+            | Body::WriteSnapshotOk { in_reply_to, .. }
+            | Body::ChangesSinceOk { in_reply_to, .. }
+            | Body::TxnOk { in_reply_to, .. }
+            | Body::TxnPrepareOk { in_reply_to, .. }
+            | Body::TxnDecideOk { in_reply_to, .. }
+            | Body::TxnFinishOk { in_reply_to, .. }
+            | Body::TxnForgetOk { in_reply_to, .. }
+            | Body::Promise { in_reply_to, .. }
+            | Body::Accepted { in_reply_to, .. }
             | Body::Error { in_reply_to, .. } => Some(*in_reply_to),
             Body::Init { .. }
             | Body::InitOk { .. }
             | Body::Read { .. }
             | Body::Write { .. }
             | Body::Cas { .. }
+            | Body::Delete { .. }
+            | Body::CasDelete { .. }
+            | Body::ReadVersion { .. }
+            | Body::CasVersion { .. }
+            | Body::Scan { .. }
+            | Body::MultiRead { .. }
             | Body::Proxy { .. }
             | Body::Propose { .. }
-            | Body::Promise { .. }
             | Body::Accept { .. }
-            | Body::Accepted { .. } => None,
+            | Body::Echo { .. }
+            | Body::Generate { .. }
+            | Body::Add { .. }
+            | Body::Topology { .. }
+            | Body::Broadcast { .. }
+            | Body::Gossip { .. }
+            | Body::Stats { .. }
+            | Body::SetLogLevel { .. }
+            | Body::Pause { .. }
+            | Body::Resume { .. }
+            | Body::ForcePropose { .. }
+            | Body::InjectLatency { .. }
+            | Body::AddNode { .. }
+            | Body::RemoveNode { .. }
+            | Body::Health { .. }
+            | Body::Heartbeat { .. }
+            | Body::InstanceDigests { .. }
+            | Body::SyncState { .. }
+            | Body::ScanKeys { .. }
+            | Body::LwwMerge { .. }
+            | Body::WriteSnapshot { .. }
+            | Body::ChangesSince { .. }
+            | Body::Txn { .. }
+            | Body::TxnPrepare { .. }
+            | Body::TxnDecide { .. }
+            | Body::TxnFinish { .. }
+            | Body::TxnForget { .. }
+            | Body::Batch { .. } => None,
         }
     }
+
     pub fn set_in_reply_to(&mut self, new_in_reply_to: usize) {
         match self {
             Body::InitOk {
@@ -111,6 +507,142 @@ impl Body {
                 ref mut in_reply_to,
                 ..
             }
+            | Body::DeleteOk {
+                ref mut in_reply_to,
+                ..
+            }
+            | Body::CasDeleteOk {
+                ref mut in_reply_to,
+                ..
+            }
+            | Body::ReadVersionOk {
+                ref mut in_reply_to,
+                ..
+            }
+            | Body::CasVersionOk {
+                ref mut in_reply_to,
+                ..
+            }
+            | Body::ScanOk {
+                ref mut in_reply_to,
+                ..
+            }
+            | Body::MultiReadOk {
+                ref mut in_reply_to,
+                ..
+            }
+            | Body::EchoOk {
+                ref mut in_reply_to,
+                ..
+            }
+            | Body::GenerateOk {
+                ref mut in_reply_to,
+                ..
+            }
+            | Body::AddOk {
+                ref mut in_reply_to,
+                ..
+            }
+            | Body::TopologyOk {
+                ref mut in_reply_to,
+                ..
+            }
+            | Body::BroadcastOk {
+                ref mut in_reply_to,
+                ..
+            }
+            | Body::BroadcastReadOk {
+                ref mut in_reply_to,
+                ..
+            }
+            | Body::GossipOk {
+                ref mut in_reply_to,
+                ..
+            }
+            | Body::StatsOk {
+                ref mut in_reply_to,
+                ..
+            }
+            | Body::SetLogLevelOk {
+                ref mut in_reply_to,
+                ..
+            }
+            | Body::PauseOk {
+                ref mut in_reply_to,
+                ..
+            }
+            | Body::ResumeOk {
+                ref mut in_reply_to,
+                ..
+            }
+            | Body::ForceProposeOk {
+                ref mut in_reply_to,
+                ..
+            }
+            | Body::InjectLatencyOk {
+                ref mut in_reply_to,
+                ..
+            }
+            | Body::AddNodeOk {
+                ref mut in_reply_to,
+                ..
+            }
+            | Body::InstanceDigestsOk {
+                ref mut in_reply_to,
+                ..
+            }
+            | Body::SyncStateOk {
+                ref mut in_reply_to,
+                ..
+            }
+            | Body::ScanKeysOk {
+                ref mut in_reply_to,
+                ..
+            }
+            | Body::RemoveNodeOk {
+                ref mut in_reply_to,
+                ..
+            }
+            | Body::HealthOk {
+                ref mut in_reply_to,
+                ..
+            }
+            | Body::WriteSnapshotOk {
+                ref mut in_reply_to,
+                ..
+            }
+            | Body::ChangesSinceOk {
+                ref mut in_reply_to,
+                ..
+            }
+            | Body::TxnOk {
+                ref mut in_reply_to,
+                ..
+            }
+            | Body::TxnPrepareOk {
+                ref mut in_reply_to,
+                ..
+            }
+            | Body::TxnDecideOk {
+                ref mut in_reply_to,
+                ..
+            }
+            | Body::TxnFinishOk {
+                ref mut in_reply_to,
+                ..
+            }
+            | Body::TxnForgetOk {
+                ref mut in_reply_to,
+                ..
+            }
+            | Body::Promise {
+                ref mut in_reply_to,
+                ..
+            }
+            | Body::Accepted {
+                ref mut in_reply_to,
+                ..
+            }
             | Body::Error {
                 ref mut in_reply_to,
                 ..
@@ -121,28 +653,71 @@ impl Body {
             | Body::Read { .. }
             | Body::Write { .. }
             | Body::Cas { .. }
+            | Body::Delete { .. }
+            | Body::CasDelete { .. }
+            | Body::ReadVersion { .. }
+            | Body::CasVersion { .. }
+            | Body::Scan { .. }
+            | Body::MultiRead { .. }
             | Body::Proxy { .. }
             | Body::Propose { .. }
-            | Body::Promise { .. }
             | Body::Accept { .. }
-            | Body::Accepted { .. } => {
+            | Body::Echo { .. }
+            | Body::Generate { .. }
+            | Body::Add { .. }
+            | Body::Topology { .. }
+            | Body::Broadcast { .. }
+            | Body::Gossip { .. }
+            | Body::Stats { .. }
+            | Body::SetLogLevel { .. }
+            | Body::Pause { .. }
+            | Body::Resume { .. }
+            | Body::ForcePropose { .. }
+            | Body::InjectLatency { .. }
+            | Body::AddNode { .. }
+            | Body::RemoveNode { .. }
+            | Body::Health { .. }
+            | Body::Heartbeat { .. }
+            | Body::InstanceDigests { .. }
+            | Body::SyncState { .. }
+            | Body::ScanKeys { .. }
+            | Body::LwwMerge { .. }
+            | Body::WriteSnapshot { .. }
+            | Body::ChangesSince { .. }
+            | Body::Txn { .. }
+            | Body::TxnPrepare { .. }
+            | Body::TxnDecide { .. }
+            | Body::TxnFinish { .. }
+            | Body::TxnForget { .. }
+            | Body::Batch { .. } => {
                 panic!("trying to set in_reply_to on a body that doesnt have such field")
             }
         }
     }
 }
 
+/// What a paused node does with the msgs it receives until it's resumed.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum PauseMode {
+    #[default]
+    Buffer, // handle them once resumed
+    Drop,
+}
+
 // https://github.com/jepsen-io/maelstrom/blob/main/doc/protocol.md#errors
-#[derive(Serialize_repr, Deserialize_repr, PartialEq, Debug, Clone)]
+#[derive(Serialize_repr, Deserialize_repr, PartialEq, Debug, Clone, Copy)]
 #[repr(u8)]
 pub enum ErrorCode {
     Timeout = 0,
+    NodeNotFound = 1,
     NotSupported = 10,
     TemporarilyUnavailable = 11,
     MalformedRequest = 12,
     Crash = 13,
     Abort = 14,
     KeyDoesNotExist = 20,
+    KeyAlreadyExists = 21,
     PreconditionFailed = 22,
     TxnConflict = 30,
 }
@@ -151,12 +726,14 @@ impl std::fmt::Display for ErrorCode {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             ErrorCode::Timeout => write!(f, "timeout"),
+            ErrorCode::NodeNotFound => write!(f, "node not found"),
             ErrorCode::NotSupported => write!(f, "not supported"),
             ErrorCode::TemporarilyUnavailable => write!(f, "temporarily unavailable"),
             ErrorCode::MalformedRequest => write!(f, "malformed request"),
             ErrorCode::Crash => write!(f, "crash"),
             ErrorCode::Abort => write!(f, "abort"),
             ErrorCode::KeyDoesNotExist => write!(f, "key does not exist"),
+            ErrorCode::KeyAlreadyExists => write!(f, "key already exists"),
             ErrorCode::PreconditionFailed => write!(f, "precondition failed"),
             ErrorCode::TxnConflict => write!(f, "txn conflict"),
         }
@@ -164,3 +741,396 @@ impl std::fmt::Display for ErrorCode {
 }
 
 impl std::error::Error for ErrorCode {}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        key::Tag,
+        stats::{MemoryUsage, Stats},
+        txn::TxnOpKind,
+    };
+
+    #[track_caller]
+    fn round_trip(body: Body) {
+        let json = serde_json::to_value(&body).unwrap();
+        let decoded: Body = serde_json::from_value(json.clone())
+            .unwrap_or_else(|error| panic!("{json} doesn't decode: {error}"));
+        assert_eq!(decoded, body, "{json}");
+    }
+
+    fn store() -> KeyValueStore<Key, Value> {
+        let mut store = KeyValueStore::new_with_inner(HashMap::new());
+        store.write(Key::Int(1), json!(2));
+        store.write_expiring(Key::Str("a".into()), json!("b"), Some(3));
+        store.write(Key::Int(4), json!(5));
+        store.delete(&Key::Int(4), 6).unwrap();
+        store
+    }
+
+    fn message(body: Body) -> Message {
+        Message {
+            src: "n1".into(),
+            dest: "n2".into(),
+            body: BodyWithMsgId {
+                msg_id: 7,
+                resent: true,
+                inner: body,
+            },
+        }
+    }
+
+    #[test]
+    fn keys_round_trip() {
+        for (key, wire) in [
+            (Key::Int(3), json!(3)),
+            (Key::Str("x".into()), json!("x")),
+            // a string of digits stays a string key.
+            (Key::Str("3".into()), json!("3")),
+            (
+                Key::Tagged(Tag::Lock, Box::new(Key::Str("x".into()))),
+                json!(["lock", "x"]),
+            ),
+        ] {
+            assert_eq!(serde_json::to_value(&key).unwrap(), wire);
+            assert_eq!(serde_json::from_value::<Key>(wire).unwrap(), key);
+        }
+    }
+
+    #[test]
+    fn maelstrom_client_ops_decode() {
+        let msg: Message = serde_json::from_value(json!({
+            "src": "c1",
+            "dest": "n1",
+            "body": {"type": "cas", "msg_id": 1, "key": "x", "from": 1, "to": 2},
+        }))
+        .unwrap();
+        assert_eq!(
+            msg.body.inner,
+            Body::Cas {
+                key: Key::Str("x".into()),
+                from: json!(1),
+                to: json!(2),
+                create_if_not_exists: false,
+            }
+        );
+        // register clients name no key.
+        let body: BodyWithMsgId =
+            serde_json::from_value(json!({"type": "write", "msg_id": 2, "value": 5})).unwrap();
+        assert_eq!(
+            body.inner,
+            Body::Write {
+                key: register_key(),
+                value: json!(5),
+                create_if_not_exists: false,
+                expiry_ms: None,
+            }
+        );
+        // lin-kv leaves msg_id out of its replies.
+        let body: BodyWithMsgId = serde_json::from_value(
+            json!({"type": "error", "in_reply_to": 3, "code": 21, "text": ""}),
+        )
+        .unwrap();
+        assert!(matches!(
+            body.inner,
+            Body::Error {
+                code: ErrorCode::KeyAlreadyExists,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn broadcast_reads_reply_as_read_ok() {
+        let body = Body::BroadcastReadOk {
+            in_reply_to: 1,
+            messages: vec![1, 2],
+        };
+        assert_eq!(
+            serde_json::to_value(body).unwrap(),
+            json!({"type": "read_ok", "in_reply_to": 1, "messages": [1, 2]})
+        );
+    }
+
+    #[test]
+    fn bodies_round_trip() {
+        let ballot_number = BallotNumber(4, 1);
+        let key = Key::Str("k".into());
+        let txn = vec![
+            TxnOp(TxnOpKind::Read, Key::Int(1), None),
+            TxnOp(TxnOpKind::Write, Key::Int(2), Some(json!(3))),
+        ];
+        let mut registers = LwwMap::default();
+        registers.set(key.clone(), json!(1), 2);
+        let stats = Stats::new(true).snapshot(1, MemoryUsage::default(), 2);
+        let health = Health {
+            role: "acceptor".into(),
+            highest_known_ballot_number: ballot_number,
+            peers_last_heard_ms: [("n2".to_string(), Some(5)), ("n3".to_string(), None)].into(),
+            inbound_queue_depth: 1,
+            outbound_queue_depth: 2,
+            queued_client_ops: 3,
+            store_size: 4,
+        };
+
+        for body in [
+            Body::Init {
+                node_id: "n1".into(),
+                node_ids: vec!["n1".into(), "n2".into()],
+            },
+            Body::InitOk { in_reply_to: 1 },
+            Body::Read { key: key.clone() },
+            Body::ReadOk {
+                in_reply_to: 1,
+                value: json!({"a": [1]}),
+                ballot_number: None,
+            },
+            Body::ReadOk {
+                in_reply_to: 1,
+                value: json!(1),
+                ballot_number: Some(ballot_number),
+            },
+            Body::Write {
+                key: Key::Int(1),
+                value: json!(2),
+                create_if_not_exists: false,
+                expiry_ms: None,
+            },
+            Body::Write {
+                key: key.clone(),
+                value: json!(2),
+                create_if_not_exists: true,
+                expiry_ms: Some(10),
+            },
+            Body::WriteOk { in_reply_to: 1 },
+            Body::Cas {
+                key: key.clone(),
+                from: json!(1),
+                to: json!(2),
+                create_if_not_exists: true,
+            },
+            Body::CasOk { in_reply_to: 1 },
+            Body::Delete { key: key.clone() },
+            Body::DeleteOk { in_reply_to: 1 },
+            Body::CasDelete {
+                key: key.clone(),
+                from: json!(1),
+            },
+            Body::CasDeleteOk { in_reply_to: 1 },
+            Body::ReadVersion { key: key.clone() },
+            Body::ReadVersionOk {
+                in_reply_to: 1,
+                value: Some(json!(2)),
+                version: 3,
+            },
+            Body::ReadVersionOk {
+                in_reply_to: 1,
+                value: None,
+                version: 0,
+            },
+            Body::CasVersion {
+                key: key.clone(),
+                version: 3,
+                value: json!(4),
+            },
+            Body::CasVersionOk { in_reply_to: 1 },
+            Body::Scan {
+                from: Key::Int(1),
+                to: Key::Int(5),
+            },
+            Body::ScanOk {
+                in_reply_to: 1,
+                values: vec![(Key::Int(1), json!(2))],
+            },
+            Body::MultiRead {
+                keys: vec![Key::Int(1), key.clone()],
+            },
+            Body::MultiReadOk {
+                in_reply_to: 1,
+                values: vec![(key.clone(), json!(2))],
+            },
+            Body::Proxy {
+                proxied_msg: Box::new(message(Body::Read { key: key.clone() })),
+            },
+            Body::Propose {
+                key: key.clone(),
+                ballot_number,
+            },
+            Body::Promise {
+                in_reply_to: 1,
+                key: key.clone(),
+                ballot_number,
+                accepted_ballot_number: BallotNumber(3, 2),
+                value: store(),
+            },
+            Body::Accept {
+                key: key.clone(),
+                ballot_number,
+                value: store(),
+            },
+            Body::Accepted {
+                in_reply_to: 1,
+                key: Key::Tagged(Tag::Staged, Box::new(Key::Int(1))),
+                ballot_number,
+            },
+            Body::Error {
+                in_reply_to: 1,
+                code: ErrorCode::NodeNotFound,
+                text: "no such member to remove".into(),
+                retry_after_ms: None,
+                ballot_hint: None,
+            },
+            Body::Error {
+                in_reply_to: 1,
+                code: ErrorCode::PreconditionFailed,
+                text: String::new(),
+                retry_after_ms: Some(5),
+                ballot_hint: Some(ballot_number),
+            },
+            Body::Echo { echo: json!("hi") },
+            Body::EchoOk {
+                in_reply_to: 1,
+                echo: json!("hi"),
+            },
+            Body::Topology {
+                topology: [("n1".to_string(), vec!["n2".to_string()])].into(),
+            },
+            Body::TopologyOk { in_reply_to: 1 },
+            Body::Broadcast { message: 3 },
+            Body::BroadcastOk { in_reply_to: 1 },
+            Body::Gossip {
+                messages: vec![1, 2],
+            },
+            Body::GossipOk { in_reply_to: 1 },
+            Body::Add { delta: -2 },
+            Body::AddOk { in_reply_to: 1 },
+            Body::Generate {},
+            Body::GenerateOk {
+                in_reply_to: 1,
+                id: "n1-1".into(),
+            },
+            Body::Stats {},
+            Body::SetLogLevel {
+                level: "debug".into(),
+            },
+            Body::SetLogLevelOk { in_reply_to: 1 },
+            Body::Pause {
+                mode: PauseMode::Drop,
+            },
+            Body::PauseOk { in_reply_to: 1 },
+            Body::Resume {},
+            Body::ResumeOk { in_reply_to: 1 },
+            Body::ForcePropose { key: key.clone() },
+            Body::ForceProposeOk { in_reply_to: 1 },
+            Body::InjectLatency {
+                peer: "n2".into(),
+                ms: 10,
+                duration: 100,
+            },
+            Body::InjectLatencyOk { in_reply_to: 1 },
+            Body::AddNode {
+                node_id: "n4".into(),
+            },
+            Body::AddNodeOk { in_reply_to: 1 },
+            Body::RemoveNode {
+                node_id: "n4".into(),
+            },
+            Body::RemoveNodeOk { in_reply_to: 1 },
+            Body::Health {},
+            Body::ChangesSince { cursor: 3 },
+            Body::ChangesSinceOk {
+                in_reply_to: 1,
+                changes: vec![Change {
+                    cursor: 3,
+                    key: key.clone(),
+                    value: None,
+                    ballot_number,
+                }],
+                cursor: 4,
+                truncated: true,
+            },
+            Body::WriteSnapshot {
+                path: "/tmp/snapshot".into(),
+            },
+            Body::WriteSnapshotOk { in_reply_to: 1 },
+            Body::Heartbeat {
+                ballots_digest: 1,
+                state_digest: 2,
+            },
+            Body::InstanceDigests {},
+            Body::InstanceDigestsOk {
+                in_reply_to: 1,
+                digests: vec![(key.clone(), ballot_number, 3)],
+            },
+            Body::SyncState {
+                keys: vec![key.clone()],
+            },
+            Body::SyncStateOk {
+                in_reply_to: 1,
+                instances: vec![(key.clone(), ballot_number, store())],
+            },
+            Body::ScanKeys {
+                from: Key::Int(1),
+                to: Key::Int(5),
+            },
+            Body::ScanKeysOk {
+                in_reply_to: 1,
+                keys: vec![Key::Int(2)],
+            },
+            Body::LwwMerge { registers },
+            Body::HealthOk {
+                in_reply_to: 1,
+                health,
+            },
+            Body::StatsOk {
+                in_reply_to: 1,
+                stats,
+            },
+            Body::Txn { txn: txn.clone() },
+            Body::TxnOk {
+                in_reply_to: 1,
+                txn: txn.clone(),
+            },
+            Body::TxnPrepare {
+                txn_id: 2,
+                txn: txn.clone(),
+            },
+            Body::TxnPrepareOk {
+                in_reply_to: 1,
+                txn,
+            },
+            Body::TxnDecide {
+                txn_id: 2,
+                commit: true,
+            },
+            Body::TxnDecideOk {
+                in_reply_to: 1,
+                commit: false,
+            },
+            Body::TxnFinish {
+                txn_id: 2,
+                commit: true,
+                keys: vec![key.clone()],
+            },
+            Body::TxnFinishOk { in_reply_to: 1 },
+            Body::TxnForget { txn_id: 2 },
+            Body::TxnForgetOk { in_reply_to: 1 },
+            Body::Batch {
+                msgs: vec![
+                    message(Body::Heartbeat {
+                        ballots_digest: 1,
+                        state_digest: 2,
+                    }),
+                    message(Body::Propose {
+                        key: key.clone(),
+                        ballot_number,
+                    }),
+                ],
+            },
+        ] {
+            round_trip(body);
+        }
+    }
+}
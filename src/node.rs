@@ -1,90 +1,572 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, VecDeque},
+    ops::Range,
     sync::{
         atomic::{AtomicUsize, Ordering},
-        Arc, Mutex, OnceLock,
+        Arc, Mutex, OnceLock, RwLock,
     },
+    time::Duration,
 };
 
+use futures::{FutureExt, StreamExt};
 use rand::Rng;
-use tokio::task::yield_now;
+use smallvec::SmallVec;
+use tokio::{task::yield_now, time::Instant};
 
-use crate::message::{Body, BodyWithMsgId, Message};
+use crate::{
+    chaos::{Chaos, Fate},
+    key::Key,
+    message::{Body, BodyWithMsgId, ErrorCode, Message},
+    rng,
+    transport::{Received, Transport},
+};
+
+/// Compact stand-in for a node id, used instead of the id string in internal state.
+/// Node ids only appear as strings at the wire boundary.
+pub type NodeIndex = u8;
+
+const CHANNEL_CAPACITY: usize = 32;
+// Per-peer collections up to this size live on the stack. Maelstrom clusters rarely
+// go beyond ~25 nodes.
+const INLINE_PEERS: usize = 24;
+
+// Upper bound on how many queued outbound messages get coalesced in one go.
+const MAX_COALESCED_MESSAGES: usize = 64;
+// How long the stdout task waits for more outbound messages to coalesce when the
+// inbound queue is full. The wait shrinks with the inbound queue, down to none when idle.
+const MAX_COALESCING_WINDOW: Duration = Duration::from_millis(2);
+
+// With reliable broadcasts, how long a broadcast msg waits for the peer's reply before
+// it's sent again, and how many of the latest requests from peers are remembered,
+// with our replies to them, to answer copies of them without handling them twice.
+const RESEND_INTERVAL: Duration = Duration::from_millis(100);
+const RECENT_REQUESTS_CAPACITY: usize = 4096;
+
+// A peer that's been silent for this long is considered cut off from us, so hearing
+// from it again means connectivity just came back.
+const PEER_SILENCE_BEFORE_SUSPECTED: Duration = Duration::from_secs(1);
 
-pub struct MessageWithResponder {
-    msg: Message,
-    responder: Option<tokio::sync::oneshot::Sender<Message>>,
+/// What the Init msg told us about the cluster, worked out once when it arrives and
+/// again on every membership change.
+/// The cluster may be split into consensus groups of `group_size` consecutive nodes,
+/// in which case `other_node_ids` and the quorums only cover our own group.
+#[derive(Debug, Clone)]
+pub struct ClusterInfo {
+    pub my_id: String,
+    pub my_index: NodeIndex,
+    pub node_ids: Vec<String>, // all node ids (including ours), in Init order
+    pub other_node_ids: Vec<String>, // the rest of our group
+    pub prepare_quorum: usize, // promises a round's Propose phase waits for
+    pub accept_quorum: usize,  // acceptances a round's Accept phase waits for
+    voters: usize,             // nodes of our group, to draw the quorums from
+    group_size: usize,
+    quorum_sizes: QuorumSizes,
+    witnesses: Vec<String>,
+}
+
+/// Quorum sizes to use in place of a majority of the group. Per Flexible Paxos,
+/// rounds stay safe with any sizes as long as every prepare quorum overlaps every
+/// accept quorum, see `ClusterInfo::quorums_intersect`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct QuorumSizes {
+    pub prepare: Option<usize>,
+    pub accept: Option<usize>,
+}
+
+impl QuorumSizes {
+    /// The (prepare, accept) quorums of a group of `voters` nodes, `replicas` of them
+    /// full replicas. Unless configured, Accept phases wait for a majority of the full
+    /// replicas, and Propose phases for (at least a majority of) enough nodes to
+    /// overlap them.
+    pub fn resolve(&self, voters: usize, replicas: usize) -> (usize, usize) {
+        let accept_quorum = self.accept.unwrap_or(replicas / 2 + 1);
+        let prepare_quorum = self
+            .prepare
+            .unwrap_or((voters / 2 + 1).max((voters + 1).saturating_sub(accept_quorum)));
+        (prepare_quorum, accept_quorum)
+    }
+}
+
+impl ClusterInfo {
+    pub fn new(
+        my_id: &str,
+        node_ids: &[String],
+        group_size: Option<usize>,
+        quorum_sizes: QuorumSizes,
+        witnesses: &[String],
+    ) -> Self {
+        let my_index = node_ids
+            .iter()
+            .position(|id| id == my_id)
+            .expect("Init's node_ids should include our own id");
+        let group_size = group_size
+            .unwrap_or(node_ids.len())
+            .clamp(1, node_ids.len());
+        let my_group =
+            &node_ids[Self::group_range(my_index / group_size, group_size, node_ids.len())];
+        let (prepare_quorum, accept_quorum) = Self::quorums(quorum_sizes, my_group, witnesses);
+
+        Self {
+            my_id: my_id.to_string(),
+            my_index: my_index as NodeIndex,
+            node_ids: node_ids.to_vec(),
+            other_node_ids: my_group.iter().filter(|id| *id != my_id).cloned().collect(),
+            prepare_quorum,
+            accept_quorum,
+            voters: my_group.len(),
+            group_size,
+            quorum_sizes,
+            witnesses: witnesses.to_vec(),
+        }
+    }
+
+    fn quorums(sizes: QuorumSizes, voters: &[String], witnesses: &[String]) -> (usize, usize) {
+        let replicas = voters.iter().filter(|id| !witnesses.contains(id)).count();
+        sizes.resolve(voters.len(), replicas)
+    }
+
+    /// Whether `node_id` is a witness, which only takes part in Propose phases.
+    pub fn is_witness(&self, node_id: &str) -> bool {
+        self.witnesses.iter().any(|id| id == node_id)
+    }
+
+    /// The rest of our group, except for witnesses.
+    pub fn other_replica_ids(&self) -> Vec<String> {
+        self.other_node_ids
+            .iter()
+            .filter(|id| !self.is_witness(id))
+            .cloned()
+            .collect()
+    }
+
+    /// Whether any prepare quorum shares a node with any accept quorum, without which
+    /// two rounds could each decide a value without either seeing the other's. Only
+    /// full replicas are in accept quorums, so a prepare quorum may take witnesses
+    /// and still overlap them in a full replica.
+    pub fn quorums_intersect(&self) -> bool {
+        self.prepare_quorum + self.accept_quorum > self.voters
+    }
+
+    fn group_range(group: usize, group_size: usize, cluster_size: usize) -> Range<usize> {
+        group * group_size..((group + 1) * group_size).min(cluster_size)
+    }
+
+    pub fn group_count(&self) -> usize {
+        self.size().div_ceil(self.group_size)
+    }
+
+    pub fn my_group(&self) -> usize {
+        self.my_index as usize / self.group_size
+    }
+
+    /// The group whose CASPaxos instance stores `key`.
+    pub fn group_of_key(&self, key: &Key) -> usize {
+        key.number() % self.group_count()
+    }
+
+    /// How many of `group`'s full replicas to ask for the keys they store, so that
+    /// one of them at least took part in every Accept phase the group decided.
+    pub fn key_holders_needed(&self, group: usize) -> usize {
+        let members = self.group_members(group);
+        let replicas = members.iter().filter(|id| !self.is_witness(id)).count();
+        let (_, accept_quorum) = Self::quorums(self.quorum_sizes, members, &self.witnesses);
+        replicas + 1 - accept_quorum.min(replicas)
+    }
+
+    pub fn group_members(&self, group: usize) -> &[String] {
+        &self.node_ids[Self::group_range(group, self.group_size, self.size())]
+    }
+
+    pub fn size(&self) -> usize {
+        self.node_ids.len()
+    }
+
+    /// Interned index of `node_id`, i.e. its position in the node list given by Init.
+    pub fn node_index(&self, node_id: &str) -> Option<NodeIndex> {
+        self.node_ids
+            .iter()
+            .position(|id| id == node_id)
+            .map(|index| index as NodeIndex)
+    }
+
+    pub fn node_id(&self, index: NodeIndex) -> &str {
+        &self.node_ids[index as usize]
+    }
+
+    /// The cluster once consensus runs among `members` alone, as a single group.
+    /// Nodes new to us are appended to `node_ids` and none are ever dropped from it,
+    /// so that NodeIndexes stay valid.
+    pub fn with_members(&self, members: &[String]) -> Self {
+        let mut node_ids = self.node_ids.clone();
+        node_ids.extend(
+            members
+                .iter()
+                .filter(|id| !self.node_ids.contains(id))
+                .cloned(),
+        );
+        let (prepare_quorum, accept_quorum) =
+            Self::quorums(self.quorum_sizes, members, &self.witnesses);
+
+        Self {
+            my_id: self.my_id.clone(),
+            my_index: self.my_index,
+            group_size: node_ids.len(),
+            node_ids,
+            other_node_ids: members
+                .iter()
+                .filter(|id| **id != self.my_id)
+                .cloned()
+                .collect(),
+            prepare_quorum,
+            accept_quorum,
+            voters: members.len(),
+            quorum_sizes: self.quorum_sizes,
+            witnesses: self.witnesses.clone(),
+        }
+    }
 }
 
 pub struct Node {
-    pub my_id: OnceLock<String>,
-    pub other_node_ids: OnceLock<Vec<String>>,
+    cluster: RwLock<Option<Arc<ClusterInfo>>>, // None until Init
     unacked: Arc<Mutex<HashMap<usize, tokio::sync::oneshot::Sender<Message>>>>,
-    pub stdout_tx: OnceLock<tokio::sync::mpsc::Sender<MessageWithResponder>>,
+    pub outbound_tx: OnceLock<tokio::sync::mpsc::Sender<Message>>,
+    inbound_tx: OnceLock<tokio::sync::mpsc::Sender<Message>>,
     pub next_msg_id: AtomicUsize,
+    broadcast_concurrency: usize, // max number of peers a broadcast sends to at once
+    injected_latencies: Mutex<HashMap<String, InjectedLatency>>, // keyed by peer
+    last_heard_from: Mutex<HashMap<String, Instant>>, // keyed by peer
+    reconnected_at: Mutex<Option<Instant>>, // last time a suspected peer was heard from
+    group_size: Option<usize>,    // None when the whole cluster is a single group
+    quorum_sizes: QuorumSizes,
+    witnesses: Vec<String>,
+    // how long broadcast msgs are sent again for, None when they're sent once.
+    resend_for: Option<Duration>,
+    recent_requests: Mutex<RecentRequests>, // from peers, see `keeps_recent_requests`
+    chaos: Option<Chaos>,                   // faults dealt to msgs to peers
+    transport: Arc<dyn Transport>,
+}
+
+/// The latest requests from peers, by (peer, msg_id), with our reply to each once
+/// it's sent. The oldest are forgotten past RECENT_REQUESTS_CAPACITY.
+#[derive(Debug, Default)]
+struct RecentRequests {
+    replies: HashMap<(String, usize), Option<Message>>,
+    order: VecDeque<(String, usize)>,
+}
+
+impl RecentRequests {
+    fn record_request(&mut self, msg: &Message) {
+        let id = (msg.src.clone(), msg.body.msg_id);
+        if self.replies.insert(id.clone(), None).is_none() {
+            self.order.push_back(id);
+        }
+        if self.order.len() > RECENT_REQUESTS_CAPACITY {
+            let oldest = self.order.pop_front().unwrap();
+            self.replies.remove(&oldest);
+        }
+    }
+
+    fn record_reply(&mut self, reply: &Message, in_reply_to: usize) {
+        if let Some(slot) = self.replies.get_mut(&(reply.dest.clone(), in_reply_to)) {
+            *slot = Some(reply.clone());
+        }
+    }
+
+    /// None if `msg` isn't a copy of a recent request, otherwise our reply to the
+    /// request, if we sent one yet.
+    fn reply_to_copy(&self, msg: &Message) -> Option<Option<Message>> {
+        self.replies
+            .get(&(msg.src.clone(), msg.body.msg_id))
+            .cloned()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct InjectedLatency {
+    delay: Duration,
+    until: Instant,
 }
 
 impl Node {
-    pub fn new() -> Self {
+    pub fn new(
+        broadcast_concurrency: usize,
+        group_size: Option<usize>,
+        quorum_sizes: QuorumSizes,
+        witnesses: Vec<String>,
+        resend_for: Option<Duration>,
+        chaos: Option<Chaos>,
+        transport: Arc<dyn Transport>,
+    ) -> Self {
         Self {
+            transport,
+            chaos,
+            group_size,
+            quorum_sizes,
+            witnesses,
+            resend_for,
+            recent_requests: Default::default(),
+            broadcast_concurrency: broadcast_concurrency.max(1),
+            injected_latencies: Default::default(),
+            last_heard_from: Default::default(),
+            reconnected_at: Default::default(),
             unacked: Default::default(),
-            stdout_tx: OnceLock::new(),
+            outbound_tx: OnceLock::new(),
+            inbound_tx: OnceLock::new(),
             next_msg_id: AtomicUsize::new(0),
-            my_id: OnceLock::new(),
-            other_node_ids: OnceLock::new(),
+            cluster: Default::default(),
         }
     }
 
+    /// Sends `body` to `dest` under a fresh msg_id, returning the msg sent. The reply
+    /// to it, if any, goes to `responder` instead of the inbound channel; `rpc` is the
+    /// way to wait for it.
     pub async fn send(
         self: Arc<Self>,
         dest: &str,
         body: Body,
         responder: Option<tokio::sync::oneshot::Sender<Message>>,
     ) -> Message {
-        let stdout_tx = self.stdout_tx.get().unwrap();
-
         let msg = Message {
-            src: self.my_id.get().unwrap().into(),
+            src: self.cluster().my_id.clone(),
             dest: dest.to_string(),
             body: BodyWithMsgId {
                 msg_id: self.reserve_next_msg_id(),
+                resent: false,
                 inner: body,
             },
         };
+        if let Some(responder) = responder {
+            self.unacked
+                .lock()
+                .unwrap()
+                .insert(msg.body.msg_id, responder);
+        }
+        if self.keeps_recent_requests() && self.is_peer(dest) {
+            if let Some(in_reply_to) = msg.body.inner.in_reply_to() {
+                self.recent_requests
+                    .lock()
+                    .unwrap()
+                    .record_reply(&msg, in_reply_to);
+            }
+        }
 
-        stdout_tx
-            .send(MessageWithResponder {
-                msg: msg.clone(),
-                responder,
-            })
-            .await
-            .unwrap();
+        self.write(msg.clone()).await;
         msg
     }
 
+    async fn write(&self, msg: Message) {
+        let outbound_tx = self.outbound_tx.get().unwrap();
+        let mut delay = self.injected_latency(&msg.dest);
+        let mut copy = None;
+        match self.chaos_fate(&msg) {
+            Fate::Deliver => (),
+            Fate::Drop => {
+                tracing::debug!("chaos dropped {msg:?}");
+                return;
+            }
+            // the copy goes after the msg, for the peer to tell it's a copy.
+            Fate::Duplicate => {
+                let mut duplicate = msg.clone();
+                duplicate.body.resent = true;
+                copy = Some(duplicate);
+            }
+            Fate::Delay(extra) => delay = Some(delay.unwrap_or_default() + extra),
+        }
+        let msgs = std::iter::once(msg).chain(copy);
+        match delay {
+            // delayed msgs are sent from their own task, so the caller isn't held up.
+            Some(delay) => {
+                let outbound_tx = outbound_tx.clone();
+                tokio::spawn(async move {
+                    tokio::time::sleep(delay).await;
+                    for msg in msgs {
+                        outbound_tx.send(msg).await.unwrap();
+                    }
+                });
+            }
+            None => {
+                for msg in msgs {
+                    outbound_tx.send(msg).await.unwrap();
+                }
+            }
+        }
+    }
+
+    /// Sends `msg` again every RESEND_INTERVAL until its reply comes, or `resend_for`
+    /// passes, when the reply stops being waited for.
+    async fn resend_until_acked(self: Arc<Self>, msg: Message, resend_for: Duration) {
+        let deadline = Instant::now() + resend_for;
+        let mut copy = msg;
+        copy.body.resent = true;
+        loop {
+            tokio::time::sleep(RESEND_INTERVAL).await;
+            if !self.unacked.lock().unwrap().contains_key(&copy.body.msg_id) {
+                return;
+            }
+            if Instant::now() >= deadline {
+                self.unacked.lock().unwrap().remove(&copy.body.msg_id);
+                return;
+            }
+            self.write(copy.clone()).await;
+        }
+    }
+
+    /// Sends `body` to whoever sent `original`, as the reply to it: its in_reply_to is
+    /// set to `original`'s msg_id, whatever it was set to before.
+    pub async fn reply(self: Arc<Self>, original: &Message, mut body: Body) {
+        body.set_in_reply_to(original.body.msg_id);
+        self.send(&original.src, body, None).await;
+    }
+
+    /// Sends `body` to `dest` and waits for the reply to it, or None if none comes
+    /// within `timeout`, after which a late reply goes to the inbound channel.
+    pub async fn rpc(self: Arc<Self>, dest: &str, body: Body, timeout: Duration) -> Option<Body> {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let msg_id = self.clone().send(dest, body, Some(tx)).await.body.msg_id;
+        match tokio::time::timeout(timeout, rx).await {
+            Ok(Ok(reply)) => Some(reply.body.inner),
+            _ => {
+                self.unacked.lock().unwrap().remove(&msg_id);
+                None
+            }
+        }
+    }
+
+    /// Delays every msg sent to `peer` by `delay` for the next `duration`.
+    pub fn inject_latency(&self, peer: &str, delay: Duration, duration: Duration) {
+        self.injected_latencies.lock().unwrap().insert(
+            peer.to_string(),
+            InjectedLatency {
+                delay,
+                until: Instant::now() + duration,
+            },
+        );
+    }
+
+    /// How long ago each peer was last heard from, if ever.
+    pub fn peers_last_heard(&self) -> Vec<(String, Option<Duration>)> {
+        let last_heard_from = self.last_heard_from.lock().unwrap();
+        self.cluster()
+            .other_node_ids
+            .iter()
+            .map(|peer| {
+                let last_heard = last_heard_from.get(peer).map(|at| at.elapsed());
+                (peer.clone(), last_heard)
+            })
+            .collect()
+    }
+
+    /// Whether enough peers were heard from recently to make up both quorums with us.
+    pub fn has_quorum(&self) -> bool {
+        let reachable_peers = self
+            .last_heard_from
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|at| at.elapsed() < PEER_SILENCE_BEFORE_SUSPECTED)
+            .count();
+        let cluster = self.cluster();
+        reachable_peers + 1 >= cluster.prepare_quorum.max(cluster.accept_quorum)
+    }
+
+    /// Whether `peer` went silent for long enough to be suspected of being down or
+    /// cut off. Every peer heartbeats, so silence doesn't last long otherwise.
+    pub fn is_suspected(&self, peer: &str) -> bool {
+        self.last_heard_from
+            .lock()
+            .unwrap()
+            .get(peer)
+            .is_none_or(|at| at.elapsed() >= PEER_SILENCE_BEFORE_SUSPECTED)
+    }
+
+    /// How long ago a peer that had gone silent was heard from again, if one ever was.
+    pub fn since_reconnect(&self) -> Option<Duration> {
+        self.reconnected_at.lock().unwrap().map(|at| at.elapsed())
+    }
+
+    fn record_heard_from(&self, peer: &str) {
+        let now = Instant::now();
+        let previous = self
+            .last_heard_from
+            .lock()
+            .unwrap()
+            .insert(peer.to_string(), now);
+        let was_suspected = previous
+            .is_some_and(|previous| now.duration_since(previous) >= PEER_SILENCE_BEFORE_SUSPECTED);
+        if was_suspected {
+            tracing::info!("heard from {peer} again after a silence, connectivity is back");
+            *self.reconnected_at.lock().unwrap() = Some(now);
+        }
+    }
+
+    fn chaos_fate(&self, msg: &Message) -> Fate {
+        match &self.chaos {
+            Some(chaos) if self.is_peer(&msg.dest) => chaos.fate(),
+            _ => Fate::Deliver,
+        }
+    }
+
+    fn injected_latency(&self, dest: &str) -> Option<Duration> {
+        let mut injected_latencies = self.injected_latencies.lock().unwrap();
+        let injected = *injected_latencies.get(dest)?;
+        if Instant::now() >= injected.until {
+            injected_latencies.remove(dest);
+            return None;
+        }
+        Some(injected.delay)
+    }
+
     pub async fn broadcast(
         self: Arc<Self>,
         body: Body,
         responder: Option<tokio::sync::mpsc::Sender<Message>>,
     ) {
-        let mut receiver_tasks = tokio::task::JoinSet::<Message>::new();
+        let cluster = self.cluster();
+        self.broadcast_to(&cluster.other_node_ids, body, responder)
+            .await;
+    }
 
-        for destination in self.other_node_ids.get().unwrap().clone() {
-            let (tx, rx) = tokio::sync::oneshot::channel::<Message>();
-            receiver_tasks.spawn(async move {
-                rx.await
-                    .expect("should be able to recv on one of the broadcast responses")
-            });
+    /// Like `broadcast`, but to `destinations` only.
+    pub async fn broadcast_to(
+        self: Arc<Self>,
+        destinations: &[String],
+        body: Body,
+        responder: Option<tokio::sync::mpsc::Sender<Message>>,
+    ) {
+        // with no reply, a reliable broadcast's responder is dropped once it stops
+        // sending the msg again, hence the Option.
+        let mut receiver_tasks = tokio::task::JoinSet::<Option<Message>>::new();
+        let mut sends: SmallVec<[(&str, _); INLINE_PEERS]> = SmallVec::new();
 
-            self.clone()
-                .send(&destination, body.clone(), Some(tx))
-                .await;
+        for destination in destinations {
+            // only wait for responses if the caller wants them
+            let tx = responder.as_ref().map(|_| {
+                let (tx, rx) = tokio::sync::oneshot::channel::<Message>();
+                receiver_tasks.spawn(async move { rx.await.ok() });
+                tx
+            });
+            sends.push((destination.as_str(), tx));
         }
 
+        // send to up to broadcast_concurrency peers at a time
+        let resend_for = self.resend_for.filter(|_| responder.is_some());
+        futures::stream::iter(sends)
+            .for_each_concurrent(self.broadcast_concurrency, |(destination, tx)| {
+                self.clone().send(destination, body.clone(), tx).map(|msg| {
+                    if let Some(resend_for) = resend_for {
+                        tokio::spawn(self.clone().resend_until_acked(msg, resend_for));
+                    }
+                })
+            })
+            .await;
+
         tokio::spawn(async move {
             while let Some(response_result) = receiver_tasks.join_next().await {
-                let response_message =
+                let response_result =
                     response_result.expect("should be able to recv response during broadcast");
+                let Some(response_message) = response_result else {
+                    continue;
+                };
                 if let Some(ref responder) = responder {
                     // The caller of broadcast might not care for all responses.
                     // In such case, some receivers might be dropped already
@@ -98,22 +580,69 @@ impl Node {
         });
     }
 
+    pub fn cluster(&self) -> Arc<ClusterInfo> {
+        self.cluster
+            .read()
+            .unwrap()
+            .clone()
+            .expect("cluster info is only known after Init")
+    }
+
+    fn try_cluster(&self) -> Option<Arc<ClusterInfo>> {
+        self.cluster.read().unwrap().clone()
+    }
+
+    /// Runs consensus among `members` from now on, returning whether they changed.
+    /// Peers new to us count as reachable until they've been silent for a while,
+    /// like the ones Init told us about.
+    pub fn set_members(&self, members: &[String]) -> bool {
+        let current = self.cluster();
+        let cluster = current.with_members(members);
+        if cluster.other_node_ids == current.other_node_ids {
+            return false;
+        }
+
+        let now = Instant::now();
+        let mut last_heard_from = self.last_heard_from.lock().unwrap();
+        last_heard_from.retain(|peer, _| cluster.other_node_ids.contains(peer));
+        for peer in &cluster.other_node_ids {
+            last_heard_from.entry(peer.clone()).or_insert(now);
+        }
+        *self.cluster.write().unwrap() = Some(Arc::new(cluster));
+        true
+    }
+
+    /// None for anything that isn't a cluster member (e.g. clients), or before Init.
+    pub fn node_index(&self, node_id: &str) -> Option<NodeIndex> {
+        self.try_cluster()?.node_index(node_id)
+    }
+
+    pub fn node_id(&self, index: NodeIndex) -> String {
+        self.cluster().node_id(index).to_string()
+    }
+
+    #[allow(dead_code)]
     pub fn get_random_peer(&self) -> String {
-        let other_node_ids = self.other_node_ids.get().unwrap();
+        let other_node_ids = &self.cluster().other_node_ids;
         other_node_ids
-            .get(rand::rng().random_range(0..other_node_ids.len()))
+            .get(rng::with_rng(|rng| {
+                rng.random_range(0..other_node_ids.len())
+            }))
             .cloned()
             .unwrap()
     }
 
     pub async fn run(self: Arc<Self>) -> tokio::sync::mpsc::Receiver<Message> {
-        let mut stdin_rx = self.clone().spawn_stdin_task().await;
-        self.clone().spawn_stdout_task().await;
+        let mut inbound_rx = self.clone().spawn_inbound_task().await;
+        self.clone().spawn_outbound_task().await;
 
-        let (tx, rx) = tokio::sync::mpsc::channel(32);
+        let (tx, rx) = tokio::sync::mpsc::channel(CHANNEL_CAPACITY);
 
         tokio::spawn(async move {
-            while let Some(msg) = stdin_rx.recv().await {
+            while let Some(msg) = inbound_rx.recv().await {
+                if self.keeps_recent_requests() && self.is_copy_of_request(&msg).await {
+                    continue;
+                }
                 let mut responder: Option<tokio::sync::oneshot::Sender<Message>> = None;
                 if let Some(in_reply_to) = msg.body.inner.in_reply_to() {
                     let mut unacked = self.unacked.lock().unwrap();
@@ -133,71 +662,224 @@ impl Node {
         rx
     }
 
-    async fn spawn_stdout_task(self: Arc<Self>) {
-        let (stdout_tx, mut stdout_rx) = tokio::sync::mpsc::channel::<MessageWithResponder>(32);
+    async fn spawn_outbound_task(self: Arc<Self>) {
+        let (outbound_tx, mut outbound_rx) =
+            tokio::sync::mpsc::channel::<Message>(CHANNEL_CAPACITY);
 
-        self.stdout_tx.set(stdout_tx).unwrap();
+        self.outbound_tx.set(outbound_tx).unwrap();
 
         tokio::spawn(async move {
-            while let Some(MessageWithResponder { msg, responder }) = stdout_rx.recv().await {
-                println!(
-                    "{}",
-                    serde_json::to_string(&msg)
-                        .expect("msg being sent to STDOUT should be serializable to JSON")
-                );
-                tracing::debug!("{:?} sent {:?}", self.my_id.get(), &msg);
+            while let Some(first) = outbound_rx.recv().await {
+                // whatever queued up while we were writing gets coalesced per destination.
+                let mut queued = vec![first];
+                while queued.len() < MAX_COALESCED_MESSAGES {
+                    match outbound_rx.try_recv() {
+                        Ok(msg) => queued.push(msg),
+                        Err(_) => break,
+                    }
+                }
 
-                if let Some(responder) = responder {
-                    self.unacked
-                        .lock()
-                        .unwrap()
-                        .insert(msg.body.msg_id, responder);
+                let window = self.coalescing_window();
+                if !window.is_zero() {
+                    let deadline = Instant::now() + window;
+                    while queued.len() < MAX_COALESCED_MESSAGES {
+                        match tokio::time::timeout_at(deadline, outbound_rx.recv()).await {
+                            Ok(Some(msg)) => queued.push(msg),
+                            Ok(None) | Err(_) => break,
+                        }
+                    }
+                }
+
+                let mut per_destination: Vec<(String, Vec<Message>)> = Vec::new();
+                for msg in queued {
+                    match per_destination
+                        .iter_mut()
+                        .find(|(dest, _)| *dest == msg.dest)
+                    {
+                        Some((_, msgs)) => msgs.push(msg),
+                        None => per_destination.push((msg.dest.clone(), vec![msg])),
+                    }
+                }
+
+                for (dest, msgs) in per_destination {
+                    if msgs.len() > 1 && self.is_peer(&dest) {
+                        let batch = Message {
+                            src: self.cluster().my_id.clone(),
+                            dest,
+                            body: BodyWithMsgId {
+                                msg_id: self.reserve_next_msg_id(),
+                                resent: false,
+                                inner: Body::Batch { msgs },
+                            },
+                        };
+                        self.transmit(&batch).await;
+                    } else {
+                        for msg in &msgs {
+                            self.transmit(msg).await;
+                        }
+                    }
                 }
             }
         });
     }
 
-    async fn spawn_stdin_task(self: Arc<Self>) -> tokio::sync::mpsc::Receiver<Message> {
-        let (stdin_tx, stdin_rx) = tokio::sync::mpsc::channel::<Message>(32);
+    async fn transmit(&self, msg: &Message) {
+        self.transport.send(msg).await;
+        tracing::debug!("{:?} sent {:?}", self.my_id(), msg);
+    }
+
+    /// Scales the coalescing window with the inbound queue depth: under load it pays
+    /// to wait for more outbound messages, while an idle node should flush right away.
+    fn coalescing_window(&self) -> Duration {
+        MAX_COALESCING_WINDOW.mul_f64(self.inbound_load())
+    }
+
+    /// How full the inbound queue is, from 0 when idle to 1 when it's full.
+    pub fn inbound_load(&self) -> f64 {
+        self.inbound_depth() as f64 / CHANNEL_CAPACITY as f64
+    }
+
+    /// Number of inbound messages read from STDIN but not yet dispatched.
+    pub fn inbound_depth(&self) -> usize {
+        self.inbound_tx.get().map_or(0, |inbound_tx| {
+            inbound_tx.max_capacity() - inbound_tx.capacity()
+        })
+    }
+
+    /// Number of outbound messages waiting to be written to STDOUT.
+    pub fn outbound_depth(&self) -> usize {
+        self.outbound_tx.get().map_or(0, |outbound_tx| {
+            outbound_tx.max_capacity() - outbound_tx.capacity()
+        })
+    }
+
+    /// Only copies of msgs, which there are none of unless we resend broadcast msgs
+    /// or chaos duplicates msgs, need recent requests to be told apart from new ones.
+    fn keeps_recent_requests(&self) -> bool {
+        self.resend_for.is_some()
+            || self
+                .chaos
+                .as_ref()
+                .is_some_and(|chaos| chaos.duplicate_rate > 0.0)
+    }
+
+    /// Records `msg` if it's a request from a peer, telling whether it's a copy of a
+    /// recent one, which is answered with our reply to that one, if we sent it yet,
+    /// rather than being handled again.
+    async fn is_copy_of_request(&self, msg: &Message) -> bool {
+        if !self.is_peer(&msg.src) || msg.body.inner.in_reply_to().is_some() {
+            return false;
+        }
+        let reply = {
+            let mut recent_requests = self.recent_requests.lock().unwrap();
+            match recent_requests.reply_to_copy(msg) {
+                Some(reply) if msg.body.resent => reply,
+                _ => {
+                    recent_requests.record_request(msg);
+                    return false;
+                }
+            }
+        };
+        if let Some(reply) = reply {
+            self.write(reply).await;
+        }
+        true
+    }
+
+    /// Whether `node_id` is another member of our group.
+    pub fn is_peer(&self, node_id: &str) -> bool {
+        self.try_cluster()
+            .is_some_and(|cluster| cluster.other_node_ids.iter().any(|id| id == node_id))
+    }
+
+    async fn spawn_inbound_task(self: Arc<Self>) -> tokio::sync::mpsc::Receiver<Message> {
+        let (inbound_tx, inbound_rx) = tokio::sync::mpsc::channel::<Message>(CHANNEL_CAPACITY);
+        self.inbound_tx.set(inbound_tx.clone()).unwrap();
         tokio::spawn(async move {
-            let mut input = String::new();
-            let mut is_reading_stdin = true;
-            while is_reading_stdin {
-                if let Err(e) = std::io::stdin().read_line(&mut input) {
-                    println!("readline error: {e}");
-                    is_reading_stdin = false;
+            while let Some(received) = self.transport.recv().await {
+                let json_msg = match received {
+                    Received::Msg(json_msg) => json_msg,
+                    Received::Malformed(input, e) => {
+                        tracing::warn!(
+                            "dropping unparseable msg {:?}: {e}",
+                            String::from_utf8_lossy(&input)
+                        );
+                        self.clone().reject_malformed(&input, e).await;
+                        continue;
+                    }
+                };
+                tracing::debug!("{:?} recv {:?}", self.my_id(), json_msg);
+
+                if self.is_peer(&json_msg.src) {
+                    self.record_heard_from(&json_msg.src);
                 }
 
-                let json_msg: Message = serde_json::from_str(&input)
-                    .expect(&format!("should take a JSON message. Got {:?}", input));
-                tracing::debug!("{:?} recv {:?}", self.my_id.get(), json_msg);
+                if let Body::Batch { msgs } = json_msg.body.inner {
+                    for msg in msgs {
+                        inbound_tx.send(msg).await.unwrap();
+                    }
+                    yield_now().await;
+                    continue;
+                }
 
                 if let Body::Init {
                     node_id, node_ids, ..
                 } = &json_msg.body.inner
                 {
-                    self.my_id.set(node_id.into()).unwrap();
-
-                    self.other_node_ids
-                        .set(
-                            node_ids
-                                .iter()
-                                .filter(|id| *id != node_id)
-                                .cloned()
-                                .collect(),
-                        )
-                        .unwrap();
+                    let cluster = ClusterInfo::new(
+                        node_id,
+                        node_ids,
+                        self.group_size,
+                        self.quorum_sizes,
+                        &self.witnesses,
+                    );
+                    *self.cluster.write().unwrap() = Some(Arc::new(cluster));
+                    // every peer counts as reachable until it's been silent for a while.
+                    let now = Instant::now();
+                    self.last_heard_from.lock().unwrap().extend(
+                        self.cluster()
+                            .other_node_ids
+                            .iter()
+                            .map(|peer| (peer.clone(), now)),
+                    );
                 }
 
-                stdin_tx.send(json_msg).await.unwrap();
+                inbound_tx.send(json_msg).await.unwrap();
                 yield_now().await;
-                input.clear();
             }
         });
-        stdin_rx
+        inbound_rx
+    }
+
+    /// Answers a msg that couldn't be parsed with a malformed request error, if enough
+    /// of it makes sense to tell who sent it, and which msg it was.
+    async fn reject_malformed(self: Arc<Self>, input: &[u8], error: serde_json::Error) {
+        let Ok(value) = serde_json::from_slice::<serde_json::Value>(input) else {
+            return;
+        };
+        // replies don't get replies, so that two nodes can't keep bouncing errors.
+        let is_reply = value["body"].get("in_reply_to").is_some();
+        let (Some(src), Some(msg_id)) = (value["src"].as_str(), value["body"]["msg_id"].as_u64())
+        else {
+            return;
+        };
+        if is_reply || self.try_cluster().is_none() {
+            return;
+        }
+
+        let body = Body::error(
+            msg_id as usize,
+            ErrorCode::MalformedRequest,
+            error.to_string(),
+        );
+        self.send(src, body, None).await;
+    }
+
+    fn my_id(&self) -> Option<String> {
+        self.try_cluster().map(|cluster| cluster.my_id.clone())
     }
 
-    fn reserve_next_msg_id(&self) -> usize {
+    pub fn reserve_next_msg_id(&self) -> usize {
         self.next_msg_id.fetch_add(1, Ordering::SeqCst)
     }
 }
@@ -0,0 +1,101 @@
+//! Timing counters around the hot path: parsing, waiting for the consensus task,
+//! applying ops to the state machine, and serializing. Only compiled in with the `profiling` feature,
+//! otherwise `time` just runs its closure.
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, Debug)]
+pub enum Stage {
+    Parse,
+    Lock,
+    Apply,
+    Serialize,
+}
+
+#[cfg(feature = "profiling")]
+const STAGES: [Stage; 4] = [Stage::Parse, Stage::Lock, Stage::Apply, Stage::Serialize];
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct StageTiming {
+    pub stage: String,
+    pub count: u64,
+    pub total_nanos: u64,
+}
+
+#[cfg(feature = "profiling")]
+mod counters {
+    use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+    use super::Stage;
+
+    pub static ENABLED: AtomicBool = AtomicBool::new(true);
+    static COUNTS: [AtomicU64; 4] = [const { AtomicU64::new(0) }; 4];
+    static TOTAL_NANOS: [AtomicU64; 4] = [const { AtomicU64::new(0) }; 4];
+
+    pub fn record(stage: Stage, nanos: u64) {
+        COUNTS[stage as usize].fetch_add(1, Ordering::Relaxed);
+        TOTAL_NANOS[stage as usize].fetch_add(nanos, Ordering::Relaxed);
+    }
+
+    pub fn load(stage: Stage) -> (u64, u64) {
+        (
+            COUNTS[stage as usize].load(Ordering::Relaxed),
+            TOTAL_NANOS[stage as usize].load(Ordering::Relaxed),
+        )
+    }
+}
+
+/// Stops timing for the rest of the run, e.g. for benchmarks.
+pub fn disable() {
+    #[cfg(feature = "profiling")]
+    counters::ENABLED.store(false, std::sync::atomic::Ordering::Relaxed);
+}
+
+#[cfg(feature = "profiling")]
+pub fn time<R>(stage: Stage, f: impl FnOnce() -> R) -> R {
+    if !counters::ENABLED.load(std::sync::atomic::Ordering::Relaxed) {
+        return f();
+    }
+    let started_at = std::time::Instant::now();
+    let result = f();
+    counters::record(stage, started_at.elapsed().as_nanos() as u64);
+    result
+}
+
+#[cfg(not(feature = "profiling"))]
+#[inline(always)]
+pub fn time<R>(_stage: Stage, f: impl FnOnce() -> R) -> R {
+    f()
+}
+
+/// Records the time from `started_at` until now, for stages that don't end in the
+/// closure they started in.
+#[cfg(feature = "profiling")]
+pub fn record_since(stage: Stage, started_at: std::time::Instant) {
+    if counters::ENABLED.load(std::sync::atomic::Ordering::Relaxed) {
+        counters::record(stage, started_at.elapsed().as_nanos() as u64);
+    }
+}
+
+#[cfg(not(feature = "profiling"))]
+#[inline(always)]
+pub fn record_since(_stage: Stage, _started_at: std::time::Instant) {}
+
+/// Timings gathered so far, empty unless built with the `profiling` feature.
+pub fn report() -> Vec<StageTiming> {
+    #[cfg(feature = "profiling")]
+    return STAGES
+        .iter()
+        .map(|stage| {
+            let (count, total_nanos) = counters::load(*stage);
+            StageTiming {
+                stage: format!("{stage:?}").to_lowercase(),
+                count,
+                total_nanos,
+            }
+        })
+        .collect();
+
+    #[cfg(not(feature = "profiling"))]
+    Vec::new()
+}
@@ -0,0 +1,53 @@
+use std::{collections::HashMap, sync::Mutex, time::Duration};
+
+use tokio::time::Instant;
+
+/// Token buckets keyed by client, each refilling at `ops_per_sec` and holding
+/// at most a second's worth of tokens.
+#[derive(Debug)]
+pub struct ClientRateLimiter {
+    ops_per_sec: f64,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl ClientRateLimiter {
+    pub fn new(ops_per_sec: u32) -> Self {
+        Self {
+            ops_per_sec: ops_per_sec.max(1) as f64,
+            buckets: Mutex::default(),
+        }
+    }
+
+    /// Takes a token from `client`'s bucket. When the bucket is empty, returns
+    /// how long until it holds a token again.
+    pub fn try_acquire(&self, client: &str) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = match buckets.get_mut(client) {
+            Some(bucket) => bucket,
+            None => buckets.entry(client.to_string()).or_insert(Bucket {
+                tokens: self.ops_per_sec,
+                refilled_at: now,
+            }),
+        };
+
+        let refill = now.duration_since(bucket.refilled_at).as_secs_f64() * self.ops_per_sec;
+        bucket.tokens = (bucket.tokens + refill).min(self.ops_per_sec);
+        bucket.refilled_at = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - bucket.tokens) / self.ops_per_sec,
+            ))
+        }
+    }
+}
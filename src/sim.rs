@@ -0,0 +1,1013 @@
+//! An in-process cluster, to exercise consensus without Maelstrom. `cas-paxos
+//! simulate` starts N `CASPaxos` nodes whose transports are channels into a virtual
+//! `Network`, which delays, drops, reorders and partitions the msgs between them,
+//! then runs client ops against the nodes, checks every result they get back, and
+//! checks the history of them all is linearizable, see `linearizability`.
+//!
+//! A simulation is deterministic: it runs on one thread, on a clock that only moves
+//! when every task waits on a timer, and every random choice, the network's and the
+//! nodes' alike, comes from one RNG seeded from `SIM_SEED`, or a random seed it
+//! prints along with a failure, for the run to be replayed exactly.
+
+use std::{
+    cell::{Cell, RefCell},
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    iter::Peekable,
+    slice,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, Once,
+    },
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Context};
+use futures::future::BoxFuture;
+use rand::{seq::SliceRandom, Rng};
+use serde_json::Value;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::{
+    cas_paxos::CASPaxos,
+    config::{Config, ReadMode},
+    key::Key,
+    linearizability::{History, Op},
+    logging,
+    message::{Body, BodyWithMsgId, ErrorCode, Message},
+    rng,
+    transport::{Received, Transport},
+};
+
+// The client every op is sent from.
+const CLIENT_ID: &str = "c1";
+// How long past the client deadline the client waits for a reply before giving up.
+const REPLY_GRACE: Duration = Duration::from_millis(500);
+// How long a reordered msg is held back, for the msgs sent after it to overtake it.
+const REORDER_HOLD: Duration = Duration::from_millis(20);
+// Keys the client ops are spread over.
+const KEYS: usize = 5;
+// Reads of each key from every node at the end of a run, before it gives up on one.
+const FINAL_READ_ATTEMPTS: usize = 10;
+
+/// What to simulate, set from the command line. Arguments that aren't the
+/// simulation's own are the nodes', as in a normal run.
+#[derive(Debug, Clone)]
+pub struct SimConfig {
+    pub nodes: usize,
+    pub ops: usize,
+    // how many clients send the ops, each waiting for one op's reply before the next.
+    pub clients: usize,
+    pub faults: Faults,
+    // Partitions and heals, each at the logical time it happens at: the number of ops
+    // sent before it.
+    pub schedule: Vec<(usize, Event)>,
+    // What every random choice of the run comes from, see `rng`.
+    pub seed: u64,
+}
+
+impl Default for SimConfig {
+    fn default() -> Self {
+        Self {
+            nodes: 3,
+            ops: 200,
+            clients: 1,
+            faults: Faults::default(),
+            schedule: Vec::new(),
+            seed: rand::random(),
+        }
+    }
+}
+
+impl SimConfig {
+    pub fn from_args(args: impl Iterator<Item = String>) -> anyhow::Result<(Self, Config)> {
+        let mut sim = Self::default();
+        let mut random_partition = false;
+        // nodes only log warnings unless told otherwise, left to the nodes' arguments.
+        let mut node_args = vec!["--log-level".to_string(), "warn".to_string()];
+        let mut args = args.flat_map(|arg| match arg.split_once('=') {
+            Some((flag, value)) if flag.starts_with("--") => {
+                vec![flag.to_string(), value.to_string()]
+            }
+            _ => vec![arg],
+        });
+
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .ok_or_else(|| anyhow!("missing value for {arg}"))
+            };
+            match arg.as_str() {
+                "--nodes" => {
+                    sim.nodes = value()?
+                        .parse()
+                        .context("--nodes should be a number of nodes")?;
+                }
+                "--ops" => {
+                    sim.ops = value()?
+                        .parse()
+                        .context("--ops should be a number of client ops")?;
+                }
+                "--clients" => {
+                    sim.clients = value()?
+                        .parse()
+                        .context("--clients should be a number of clients")?;
+                }
+                "--max-delay-ms" => {
+                    sim.faults.max_delay = Duration::from_millis(
+                        value()?
+                            .parse()
+                            .context("--max-delay-ms should be a number of milliseconds")?,
+                    );
+                }
+                "--drop-rate" => sim.faults.drop_rate = rate(&arg, &value()?)?,
+                "--reorder-rate" => sim.faults.reorder_rate = rate(&arg, &value()?)?,
+                "--partition" => random_partition = true,
+                "--partition-at" => {
+                    let value = value()?;
+                    let (at, sides) = value
+                        .split_once(':')
+                        .and_then(|(at, sides)| Some((at.parse().ok()?, sides.split_once('/')?)))
+                        .ok_or_else(|| {
+                            anyhow!("--partition-at should be like 100:n1,n2/n3,n4,n5, not {value}")
+                        })?;
+                    let side = |nodes: &str| nodes.split(',').map(String::from).collect();
+                    let event = Event::Partition(side(sides.0), side(sides.1));
+                    sim.schedule.push((at, event));
+                }
+                "--heal-at" => {
+                    let at = value()?
+                        .parse()
+                        .context("--heal-at should be a number of ops")?;
+                    sim.schedule.push((at, Event::Heal));
+                }
+                _ => node_args.push(arg),
+            }
+        }
+
+        // a random minority is cut off for the middle third of the ops.
+        if random_partition {
+            sim.schedule.push((sim.ops / 3, Event::PartitionMinority));
+            sim.schedule.push((sim.ops * 2 / 3, Event::Heal));
+        }
+        sim.schedule.sort_by_key(|(at, _)| *at);
+
+        if let Ok(seed) = std::env::var("SIM_SEED") {
+            sim.seed = seed
+                .parse()
+                .context("SIM_SEED should be a number, as printed by a failed run")?;
+        }
+        if sim.nodes == 0 {
+            return Err(anyhow!("--nodes should be at least 1"));
+        }
+        if sim.clients == 0 {
+            return Err(anyhow!("--clients should be at least 1"));
+        }
+        if random_partition && sim.nodes < 3 {
+            return Err(anyhow!("--partition needs at least 3 nodes"));
+        }
+        for (_, event) in &sim.schedule {
+            if let Event::Partition(a, b) = event {
+                if let Some(unknown) = a.iter().chain(b).find(|node| !sim.is_node(node)) {
+                    return Err(anyhow!(
+                        "no node {unknown} to partition, nodes are n1 to n{}",
+                        sim.nodes
+                    ));
+                }
+            }
+        }
+        let config = Config::from_args(node_args.into_iter())?;
+        Ok((sim, config))
+    }
+}
+
+impl SimConfig {
+    fn is_node(&self, id: &str) -> bool {
+        id.strip_prefix('n')
+            .and_then(|i| i.parse::<usize>().ok())
+            .is_some_and(|i| (1..=self.nodes).contains(&i))
+    }
+}
+
+fn rate(flag: &str, value: &str) -> anyhow::Result<f64> {
+    match value.parse() {
+        Ok(rate) if (0.0..=1.0).contains(&rate) => Ok(rate),
+        _ => Err(anyhow!("{flag} should be a probability from 0 to 1")),
+    }
+}
+
+/// A change to the network.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    // cuts every node of the first side off from every node of the second.
+    Partition(Vec<String>, Vec<String>),
+    // cuts a random minority off from the rest of the cluster.
+    PartitionMinority,
+    Heal,
+}
+
+/// Faults the network deals the msgs between nodes. Msgs to and from clients go
+/// straight through, as Maelstrom doesn't lose or partition those either.
+#[derive(Debug, Clone, Default)]
+pub struct Faults {
+    pub max_delay: Duration,
+    pub drop_rate: f64,
+    pub reorder_rate: f64,
+}
+
+/// Routes msgs between everything connected to it.
+pub struct Network {
+    faults: Mutex<Faults>,
+    node_ids: Vec<String>,
+    inboxes: Mutex<HashMap<String, mpsc::UnboundedSender<Message>>>,
+    // pairs of nodes that can't reach each other, both ways round.
+    severed: Mutex<HashSet<(String, String)>>,
+}
+
+impl Network {
+    pub fn new(node_ids: Vec<String>, faults: Faults) -> Arc<Self> {
+        Arc::new(Self {
+            faults: Mutex::new(faults),
+            node_ids,
+            inboxes: Mutex::default(),
+            severed: Mutex::default(),
+        })
+    }
+
+    /// A transport for the node or client `id`.
+    pub fn connect(self: &Arc<Self>, id: &str) -> Channel {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.inboxes.lock().unwrap().insert(id.to_string(), tx);
+        Channel {
+            network: self.clone(),
+            inbox: tokio::sync::Mutex::new(rx),
+        }
+    }
+
+    /// Cuts every node in `a` off from every node in `b`, until `heal`.
+    pub fn partition(&self, a: &[&str], b: &[&str]) {
+        let mut severed = self.severed.lock().unwrap();
+        for x in a {
+            for y in b {
+                severed.insert((x.to_string(), y.to_string()));
+                severed.insert((y.to_string(), x.to_string()));
+            }
+        }
+    }
+
+    pub fn heal(&self) {
+        self.severed.lock().unwrap().clear();
+    }
+
+    pub fn is_partitioned(&self) -> bool {
+        !self.severed.lock().unwrap().is_empty()
+    }
+
+    /// How many nodes `node` gets msgs to, itself included, directly or through others.
+    pub fn reachable(&self, node: &str) -> usize {
+        let severed = self.severed.lock().unwrap();
+        let mut reached = vec![node];
+        let mut i = 0;
+        while let Some(from) = reached.get(i).copied() {
+            for to in &self.node_ids {
+                let link = (from.to_string(), to.clone());
+                if !reached.contains(&to.as_str()) && !severed.contains(&link) {
+                    reached.push(to);
+                }
+            }
+            i += 1;
+        }
+        reached.len()
+    }
+
+    pub fn set_faults(&self, faults: Faults) {
+        *self.faults.lock().unwrap() = faults;
+    }
+
+    fn is_node(&self, id: &str) -> bool {
+        self.node_ids.iter().any(|node_id| node_id == id)
+    }
+
+    fn deliver(&self, msg: Message) {
+        let Some(inbox) = self.inboxes.lock().unwrap().get(&msg.dest).cloned() else {
+            tracing::warn!("dropping msg to unknown {:?}: {msg:?}", msg.dest);
+            return;
+        };
+        if !self.is_node(&msg.src) || !self.is_node(&msg.dest) {
+            let _ = inbox.send(msg);
+            return;
+        }
+        let link = (msg.src.clone(), msg.dest.clone());
+        if self.severed.lock().unwrap().contains(&link) {
+            return;
+        }
+        let faults = self.faults.lock().unwrap().clone();
+        let delay = rng::with_rng(|rng| {
+            if rng.random_bool(faults.drop_rate) {
+                return None;
+            }
+            let delay = faults.max_delay.mul_f64(rng.random());
+            match rng.random_bool(faults.reorder_rate) {
+                true => Some(delay + REORDER_HOLD),
+                false => Some(delay),
+            }
+        });
+        let Some(delay) = delay else {
+            return;
+        };
+
+        if delay.is_zero() {
+            let _ = inbox.send(msg);
+            return;
+        }
+        tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            let _ = inbox.send(msg);
+        });
+    }
+}
+
+/// One end of the network, in place of STDIN/STDOUT.
+pub struct Channel {
+    network: Arc<Network>,
+    inbox: tokio::sync::Mutex<mpsc::UnboundedReceiver<Message>>,
+}
+
+impl Transport for Channel {
+    fn recv(&self) -> BoxFuture<'_, Option<Received>> {
+        Box::pin(async move { self.inbox.lock().await.recv().await.map(Received::Msg) })
+    }
+
+    fn send<'a>(&'a self, msg: &'a Message) -> BoxFuture<'a, ()> {
+        Box::pin(async move { self.network.deliver(msg.clone()) })
+    }
+}
+
+/// Sends client ops to nodes, and waits for their replies.
+pub struct Client {
+    channel: Channel,
+    next_msg_id: AtomicUsize,
+    awaiting_reply: Mutex<HashMap<usize, oneshot::Sender<Body>>>,
+    timeout: Duration,
+}
+
+impl Client {
+    pub fn start(network: &Arc<Network>, timeout: Duration) -> Arc<Self> {
+        let client = Arc::new(Self {
+            channel: network.connect(CLIENT_ID),
+            next_msg_id: AtomicUsize::new(0),
+            awaiting_reply: Mutex::default(),
+            timeout,
+        });
+        tokio::spawn(client.clone().receive_replies());
+        client
+    }
+
+    async fn receive_replies(self: Arc<Self>) {
+        while let Some(Received::Msg(msg)) = self.channel.recv().await {
+            // nodes never wait for init_ok, so `in_reply_to` leaves it out.
+            let in_reply_to = match msg.body.inner {
+                Body::InitOk { in_reply_to } => in_reply_to,
+                ref body => match body.in_reply_to() {
+                    Some(in_reply_to) => in_reply_to,
+                    None => continue,
+                },
+            };
+            // replies to ops we gave up on have nothing waiting for them.
+            if let Some(tx) = self.awaiting_reply.lock().unwrap().remove(&in_reply_to) {
+                let _ = tx.send(msg.body.inner);
+            }
+        }
+    }
+
+    /// The reply to `body` from `dest`, or None if none came in time.
+    pub async fn call(&self, dest: &str, body: Body) -> Option<Body> {
+        let (msg_id, rx) = self.send(dest, body);
+        self.reply(msg_id, rx).await
+    }
+
+    fn send(&self, dest: &str, body: Body) -> (usize, oneshot::Receiver<Body>) {
+        let msg_id = self.next_msg_id.fetch_add(1, Ordering::SeqCst);
+        let (tx, rx) = oneshot::channel();
+        self.awaiting_reply.lock().unwrap().insert(msg_id, tx);
+        self.channel.network.deliver(Message {
+            src: CLIENT_ID.to_string(),
+            dest: dest.to_string(),
+            body: BodyWithMsgId {
+                msg_id,
+                resent: false,
+                inner: body,
+            },
+        });
+        (msg_id, rx)
+    }
+
+    async fn reply(&self, msg_id: usize, rx: oneshot::Receiver<Body>) -> Option<Body> {
+        let reply = tokio::time::timeout(self.timeout, rx).await;
+        self.awaiting_reply.lock().unwrap().remove(&msg_id);
+        reply.ok()?.ok()
+    }
+}
+
+/// N nodes n1..nN on one network, initialized and ready for client ops.
+pub struct Cluster {
+    pub network: Arc<Network>,
+    pub node_ids: Vec<String>,
+    pub client: Arc<Client>,
+    // the fewest nodes that decide a round, see `smallest_quorum`.
+    smallest_quorum: Option<usize>,
+}
+
+impl Cluster {
+    pub async fn start(size: usize, config: &Config, faults: Faults) -> anyhow::Result<Self> {
+        let node_ids: Vec<String> = (1..=size).map(|i| format!("n{i}")).collect();
+        let network = Network::new(node_ids.clone(), faults);
+        let client = Client::start(&network, config.client_deadline + REPLY_GRACE);
+        let nodes: Vec<CASPaxos> = node_ids
+            .iter()
+            .map(|node_id| CASPaxos::new(config.clone(), Arc::new(network.connect(node_id))))
+            .collect();
+
+        // every Init is waiting for its node before any node runs, so that no node
+        // hears from a peer before it knows the cluster, as no Maelstrom node does.
+        let inits: Vec<_> = node_ids
+            .iter()
+            .map(|node_id| {
+                let init = Body::Init {
+                    node_id: node_id.clone(),
+                    node_ids: node_ids.clone(),
+                };
+                client.send(node_id, init)
+            })
+            .collect();
+        for cas_paxos in nodes {
+            tokio::spawn(Arc::new(cas_paxos).run());
+        }
+        for (node_id, (msg_id, rx)) in node_ids.iter().zip(inits) {
+            match client.reply(msg_id, rx).await {
+                Some(Body::InitOk { .. }) => (),
+                reply => bail!("{node_id} didn't init, got {reply:?}"),
+            }
+        }
+
+        Ok(Self {
+            network,
+            smallest_quorum: smallest_quorum(&node_ids, config),
+            node_ids,
+            client,
+        })
+    }
+
+    pub fn apply(&self, event: &Event) {
+        let (a, b) = match event {
+            Event::Partition(a, b) => (
+                a.iter().map(String::as_str).collect(),
+                b.iter().map(String::as_str).collect(),
+            ),
+            Event::PartitionMinority => self.random_split(),
+            Event::Heal => {
+                tracing::warn!("healing the partition");
+                self.network.heal();
+                return;
+            }
+        };
+        tracing::warn!("partitioning {a:?} from {b:?}");
+        self.network.partition(&a, &b);
+    }
+
+    /// Whether `node` reaches too few nodes for any round to be decided, so that
+    /// every client op sent to it has to go undecided.
+    pub fn is_cut_off(&self, node: &str) -> bool {
+        self.smallest_quorum
+            .is_some_and(|quorum| self.network.reachable(node) < quorum)
+    }
+
+    pub fn random_node(&self) -> &str {
+        &self.node_ids[rng::with_rng(|rng| rng.random_range(0..self.node_ids.len()))]
+    }
+
+    /// Splits the nodes into a random minority and the majority left.
+    pub fn random_split(&self) -> (Vec<&str>, Vec<&str>) {
+        let mut node_ids: Vec<&str> = self.node_ids.iter().map(String::as_str).collect();
+        rng::with_rng(|rng| node_ids.shuffle(rng));
+        let majority = node_ids.split_off((node_ids.len() - 1) / 2);
+        (node_ids, majority)
+    }
+}
+
+/// The fewest nodes a round can be decided by, with the same default quorums as
+/// `Node`'s. None if a cut off node may answer client ops all the same: from the
+/// LWW map, or from a group that's only part of the cluster.
+fn smallest_quorum(node_ids: &[String], config: &Config) -> Option<usize> {
+    let takes_lww_path = config.crdt_fallback || !config.lww_key_prefixes.is_empty();
+    if takes_lww_path || config.group_size.is_some() {
+        return None;
+    }
+    let voters = node_ids.len();
+    let replicas = voters
+        - node_ids
+            .iter()
+            .filter(|id| config.witnesses.contains(id))
+            .count();
+    let accept_quorum = config.accept_quorum.unwrap_or(replicas / 2 + 1);
+    let prepare_quorum = config
+        .prepare_quorum
+        .unwrap_or((voters / 2 + 1).max(voters + 1 - accept_quorum));
+    Some(prepare_quorum.min(accept_quorum))
+}
+
+/// What became of a client op.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Ok,
+    // it failed without taking effect, e.g. a Cas whose `from` didn't match.
+    Failed,
+    // it may or may not have taken effect, e.g. it timed out.
+    Unknown,
+}
+
+fn outcome(reply: &Option<Body>) -> Outcome {
+    match reply {
+        Some(Body::ReadOk { .. } | Body::WriteOk { .. } | Body::CasOk { .. }) => Outcome::Ok,
+        Some(Body::Error {
+            code: ErrorCode::KeyDoesNotExist | ErrorCode::PreconditionFailed,
+            ..
+        }) => Outcome::Failed,
+        _ => Outcome::Unknown,
+    }
+}
+
+/// What a key may hold, given the results the client got. Every value is written
+/// once, so a read tells which write it saw. Ops with unknown outcomes may take
+/// effect any time after they were sent, so their values stay possible until read.
+#[derive(Debug, Default)]
+struct KeyModel {
+    current: Option<u64>,
+    maybe: BTreeSet<u64>,
+}
+
+impl KeyModel {
+    fn is_settled(&self) -> bool {
+        self.maybe.is_empty()
+    }
+
+    /// Takes `value` as the key's value from now on, if it's one it could hold.
+    fn observe(&mut self, value: Option<u64>) -> anyhow::Result<()> {
+        if value == self.current {
+            return Ok(());
+        }
+        match value {
+            Some(value) if self.maybe.remove(&value) => {
+                self.current = Some(value);
+                Ok(())
+            }
+            _ => bail!(
+                "{value:?} isn't {:?} or any of {:?}",
+                self.current,
+                self.maybe
+            ),
+        }
+    }
+
+    /// Checks `reply` to `op` against what the key may hold, and updates it.
+    fn apply(&mut self, op: &Body, reply: &Option<Body>) -> anyhow::Result<()> {
+        match (op, outcome(reply)) {
+            (Body::Write { value, .. } | Body::Cas { to: value, .. }, Outcome::Unknown) => {
+                self.maybe.insert(as_u64(value));
+            }
+            (_, Outcome::Unknown) => (),
+            (Body::Read { .. }, Outcome::Ok) => match reply {
+                Some(Body::ReadOk { value, .. }) => self.observe(Some(as_u64(value)))?,
+                _ => unreachable!("read_ok is a read's only ok reply"),
+            },
+            (Body::Read { .. }, Outcome::Failed) => self.observe(None)?,
+            (Body::Write { value, .. }, Outcome::Ok) => self.current = Some(as_u64(value)),
+            (Body::Cas { from, to, .. }, Outcome::Ok) => {
+                self.observe(Some(as_u64(from)))?;
+                self.current = Some(as_u64(to));
+            }
+            (Body::Cas { from, .. }, Outcome::Failed) if self.is_settled() => {
+                // with nothing else the key could hold, the cas failed against `current`.
+                match (reply, self.current) {
+                    (
+                        Some(Body::Error {
+                            code: ErrorCode::KeyDoesNotExist,
+                            ..
+                        }),
+                        Some(current),
+                    ) => bail!("cas failed as the key didn't exist, but it held {current}"),
+                    (
+                        Some(Body::Error {
+                            code: ErrorCode::PreconditionFailed,
+                            ..
+                        }),
+                        Some(current),
+                    ) if current == as_u64(from) => {
+                        bail!("cas from {from} failed, but the key held {current}")
+                    }
+                    _ => (),
+                }
+            }
+            (Body::Cas { .. }, Outcome::Failed) => (),
+            _ => unreachable!("the simulation only sends reads, writes and cases"),
+        }
+        Ok(())
+    }
+}
+
+// every value the simulation writes is a number.
+fn as_u64(value: &Value) -> u64 {
+    value
+        .as_u64()
+        .expect("the simulation only writes unsigned numbers")
+}
+
+/// How many of each `Outcome` the client ops had.
+#[derive(Debug, Default)]
+pub struct Report {
+    pub ok: usize,
+    pub failed: usize,
+    pub unknown: usize,
+    // ops sent to nodes cut off from every quorum, all of which went undecided.
+    pub cut_off: usize,
+    // ops sent during a partition to nodes that still reach a quorum, and how many
+    // of them were decided, which some have to be.
+    pub beside_partition: usize,
+    pub decided_beside_partition: usize,
+    // ops in the history found linearizable, the final reads included.
+    pub checked: usize,
+}
+
+impl Report {
+    fn count(&mut self, outcome: Outcome) {
+        match outcome {
+            Outcome::Ok => self.ok += 1,
+            Outcome::Failed => self.failed += 1,
+            Outcome::Unknown => self.unknown += 1,
+        }
+    }
+}
+
+/// Runs `sim.ops` random reads, writes and cases on random nodes from `sim.clients`
+/// clients at once, partitioning the network along `sim.schedule`, then reads every
+/// key from every node without faults, and checks the history of it all is
+/// linearizable. Fails on a result that a node cut off from every quorum decided, on a
+/// partition none of the ops to the nodes still reaching a quorum were decided in,
+/// and, with one client, on the first result that doesn't fit what was written before it.
+pub async fn run(sim: &SimConfig, config: &Config) -> anyhow::Result<Report> {
+    let run = Run {
+        sim,
+        config,
+        cluster: Cluster::start(sim.nodes, config, sim.faults.clone()).await?,
+        next_op: Cell::new(0),
+        schedule: RefCell::new(sim.schedule.iter().peekable()),
+        events: Cell::new(0),
+        keys: RefCell::default(),
+        seen: RefCell::default(),
+        report: RefCell::default(),
+        history: History::default(),
+    };
+    futures::future::try_join_all((0..sim.clients).map(|client| run.client(client))).await?;
+    let beside_partition = run.report.borrow().beside_partition;
+    if beside_partition > 0 && run.report.borrow().decided_beside_partition == 0 {
+        bail!("none of the {beside_partition} ops sent to nodes that reach a quorum during a partition were decided");
+    }
+
+    run.cluster.network.heal();
+    run.cluster.network.set_faults(Faults::default());
+    for i in 0..KEYS {
+        run.assert_agree(&Key::Int(i)).await?;
+    }
+    run.history.check()?;
+    let mut report = run.report.into_inner();
+    report.checked = run.history.ops();
+    Ok(report)
+}
+
+/// What the clients of a run share.
+struct Run<'a> {
+    sim: &'a SimConfig,
+    config: &'a Config,
+    cluster: Cluster,
+    // the next op any client sends.
+    next_op: Cell<usize>,
+    schedule: RefCell<Peekable<slice::Iter<'a, (usize, Event)>>>,
+    // how many events of the schedule were applied, to tell if any was during an op.
+    events: Cell<usize>,
+    // what each key may hold, only checked with one client, as it takes ops one at a time.
+    keys: RefCell<BTreeMap<Key, KeyModel>>,
+    // the last value each key was seen to hold, for cases to go from.
+    seen: RefCell<BTreeMap<Key, u64>>,
+    report: RefCell<Report>,
+    history: History,
+}
+
+impl Run<'_> {
+    async fn client(&self, client: usize) -> anyhow::Result<()> {
+        loop {
+            let i = self.next_op.get();
+            if i == self.sim.ops {
+                return Ok(());
+            }
+            self.next_op.set(i + 1);
+            while let Some((_, event)) = self.schedule.borrow_mut().next_if(|(at, _)| *at <= i) {
+                self.cluster.apply(event);
+                self.events.set(self.events.get() + 1);
+            }
+
+            let key = Key::Int(rng::with_rng(|rng| rng.random_range(0..KEYS)));
+            let from = self.seen.borrow().get(&key).copied();
+            let op = random_op(key.clone(), from, i as u64 + 1);
+            let node = self.cluster.random_node();
+            // reads under a lease are answered from the lease holder's own state.
+            let is_lease_read =
+                self.config.reads == ReadMode::Lease && matches!(op, Body::Read { .. });
+            let is_cut_off = self.cluster.is_cut_off(node) && !is_lease_read;
+            let is_partitioned = self.cluster.network.is_partitioned();
+            let events = self.events.get();
+            let reply = self.call(client, &key, node, &op).await;
+            self.report.borrow_mut().count(outcome(&reply));
+            // a heal during the op may have let it through.
+            if is_cut_off && self.events.get() == events {
+                self.report.borrow_mut().cut_off += 1;
+                if outcome(&reply) != Outcome::Unknown {
+                    bail!("op {i}, {op:?} on {node}, was decided while {node} was cut off from every quorum, got {reply:?}");
+                }
+            } else if is_partitioned && self.events.get() == events {
+                let mut report = self.report.borrow_mut();
+                report.beside_partition += 1;
+                if outcome(&reply) != Outcome::Unknown {
+                    report.decided_beside_partition += 1;
+                }
+            }
+            if self.sim.clients == 1 {
+                self.keys
+                    .borrow_mut()
+                    .entry(key)
+                    .or_default()
+                    .apply(&op, &reply)
+                    .with_context(|| format!("op {i}, {op:?} on {node}, got {reply:?}"))?;
+            }
+        }
+    }
+
+    /// Sends `op` on `key` to `node`, recording it and its reply in the history.
+    async fn call(&self, client: usize, key: &Key, node: &str, op: &Body) -> Option<Body> {
+        let call = self.history.call();
+        let reply = self.cluster.client.call(node, op.clone()).await;
+        let returned = outcome(&reply) != Outcome::Unknown;
+        let op = as_op(op, &reply);
+        self.history.record(client, key.clone(), call, op, returned);
+        let value = match op {
+            Op::Read(Some(value)) | Op::Write(value) | Op::Cas { to: value, .. } => Some(value),
+            _ => None,
+        };
+        if let Some(value) = value.filter(|_| returned) {
+            self.seen.borrow_mut().insert(key.clone(), value);
+        }
+        reply
+    }
+
+    /// Checks that every node can read `key` once the network is healed and
+    /// faultless, and, with one client, reads a value the key could hold.
+    async fn assert_agree(&self, key: &Key) -> anyhow::Result<()> {
+        for node in &self.cluster.node_ids {
+            let read = Body::Read { key: key.clone() };
+            let mut reply = None;
+            for _ in 0..FINAL_READ_ATTEMPTS {
+                reply = self.call(0, key, node, &read).await;
+                if outcome(&reply) != Outcome::Unknown {
+                    break;
+                }
+            }
+            if outcome(&reply) == Outcome::Unknown {
+                bail!("{node} couldn't read {key} after the run, got {reply:?}");
+            }
+            if self.sim.clients == 1 {
+                self.keys
+                    .borrow_mut()
+                    .entry(key.clone())
+                    .or_default()
+                    .apply(&read, &reply)
+                    .with_context(|| format!("final read of {key} on {node}, got {reply:?}"))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn random_op(key: Key, from: Option<u64>, value: u64) -> Body {
+    rng::with_rng(|rng| match rng.random_range(0..10) {
+        0..5 => Body::Read { key },
+        5..8 => Body::Write {
+            key,
+            value: value.into(),
+            create_if_not_exists: false,
+            expiry_ms: None,
+        },
+        _ => Body::Cas {
+            key,
+            // mostly the value the key was last seen to hold, so that some cases succeed.
+            from: match from {
+                Some(from) if rng.random_bool(0.8) => from.into(),
+                _ => (value - 1).into(),
+            },
+            to: value.into(),
+            create_if_not_exists: false,
+        },
+    })
+}
+
+/// `op` as the history holds it, with the result `reply` gave it.
+fn as_op(op: &Body, reply: &Option<Body>) -> Op {
+    match (op, reply) {
+        (Body::Read { .. }, Some(Body::ReadOk { value, .. })) => Op::Read(Some(as_u64(value))),
+        (Body::Read { .. }, _) => Op::Read(None),
+        (Body::Write { value, .. }, _) => Op::Write(as_u64(value)),
+        (Body::Cas { from, .. }, Some(Body::Error { code, .. }))
+            if outcome(reply) == Outcome::Failed =>
+        {
+            Op::CasFailed {
+                from: as_u64(from),
+                existed: *code == ErrorCode::PreconditionFailed,
+            }
+        }
+        (Body::Cas { from, to, .. }, _) => Op::Cas {
+            from: as_u64(from),
+            to: as_u64(to),
+        },
+        _ => unreachable!("the simulation only sends reads, writes and cases"),
+    }
+}
+
+/// Runs the simulation `sim` of nodes started with `config`, deterministically from
+/// `sim.seed`, on this thread, as `cas-paxos simulate` does. A node panicking fails
+/// the run as much as a wrong result does.
+pub fn simulate(sim: &SimConfig, config: &Config) -> anyhow::Result<Report> {
+    record_panics();
+    PANIC.with(RefCell::take);
+    rng::seed(sim.seed);
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .start_paused(true)
+        .build()?;
+    let report = runtime.block_on(run(sim, config));
+    drop(runtime);
+    let report = match PANIC.with(RefCell::take) {
+        Some(panic) => Err(anyhow!("a node panicked: {panic}")),
+        None => report,
+    };
+    report.with_context(|| format!("replay with SIM_SEED={}", sim.seed))
+}
+
+thread_local! {
+    // the first panic on this thread since `simulate` started, which runs every node
+    // on its own thread.
+    static PANIC: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Keeps the first panic of each thread in PANIC, on top of reporting it as usual,
+/// since tokio catches the panics of tasks, which only their JoinHandles learn of.
+fn record_panics() {
+    static HOOK: Once = Once::new();
+    HOOK.call_once(|| {
+        let hook = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            let _ = PANIC.try_with(|panic| {
+                panic.borrow_mut().get_or_insert_with(|| info.to_string());
+            });
+            hook(info);
+        }));
+    });
+}
+
+/// `cas-paxos simulate [--nodes N] [--ops N] [--clients N] [--max-delay-ms N] [--drop-rate P]
+/// [--reorder-rate P] [--partition] [--partition-at OP:n1,n2/n3,n4,n5] [--heal-at OP]
+/// [node arguments]`, with `SIM_SEED=N` to replay the run of seed N. `--partition`
+/// cuts a random minority off at a third of the ops and heals it at two thirds.
+pub fn main(args: impl Iterator<Item = String>) -> anyhow::Result<()> {
+    let (sim, config) = SimConfig::from_args(args)?;
+    logging::init(config.log_level);
+
+    // whatever panics, the seed to replay it from goes along with it.
+    let seed = sim.seed;
+    let panic_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        panic_hook(info);
+        eprintln!("replay with SIM_SEED={seed}");
+    }));
+
+    let report = simulate(&sim, &config)?;
+    println!(
+        "{} ops on {} nodes: {} ok, {} failed, {} unknown ({} cut off), {} of {} decided beside a partition, {} linearizable, SIM_SEED={seed}",
+        sim.ops,
+        sim.nodes,
+        report.ok,
+        report.failed,
+        report.unknown,
+        report.cut_off,
+        report.decided_beside_partition,
+        report.beside_partition,
+        report.checked
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Simulates what `args` of `cas-paxos simulate` set, from `seed`.
+    fn simulate_with(args: &[&str], seed: u64) -> anyhow::Result<Report> {
+        let (mut sim, config) = SimConfig::from_args(args.iter().map(|arg| arg.to_string()))?;
+        sim.seed = seed;
+        simulate(&sim, &config)
+    }
+
+    #[test]
+    fn faultless_runs_decide_every_op() {
+        let report = simulate_with(&["--nodes", "3", "--ops", "100"], 1).unwrap();
+        assert_eq!(report.ok + report.failed, 100);
+        assert_eq!(report.unknown, 0);
+    }
+
+    // n1 and n2 can't commit while cut off, which `run` fails on, while ops sent to
+    // the other three are decided.
+    #[test]
+    fn minority_side_of_a_partition_decides_nothing() {
+        let args = [
+            "--nodes",
+            "5",
+            "--ops",
+            "200",
+            "--clients",
+            "3",
+            "--partition-at",
+            "50:n1,n2/n3,n4,n5",
+            "--heal-at",
+            "150",
+        ];
+        for seed in [1, 2, 3] {
+            let report = simulate_with(&args, seed).unwrap();
+            assert!(report.cut_off > 0, "seed {seed}: {report:?}");
+            assert!(
+                report.decided_beside_partition > 0,
+                "seed {seed}: {report:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn majority_side_of_a_partition_stays_linearizable() {
+        let args = [
+            "--nodes",
+            "5",
+            "--ops",
+            "300",
+            "--clients",
+            "4",
+            "--partition",
+            "--drop-rate",
+            "0.05",
+            "--reorder-rate",
+            "0.1",
+        ];
+        for seed in [4, 5, 6] {
+            let report = simulate_with(&args, seed).unwrap();
+            assert!(
+                report.decided_beside_partition > 0,
+                "seed {seed}: {report:?}"
+            );
+            assert_eq!(report.ok + report.failed + report.unknown, 300);
+        }
+    }
+
+    // every op clients got a result for is in the history checked, final reads too.
+    #[test]
+    fn concurrent_clients_histories_are_checked() {
+        let args = [
+            "--nodes",
+            "5",
+            "--ops",
+            "300",
+            "--clients",
+            "8",
+            "--drop-rate",
+            "0.05",
+            "--reorder-rate",
+            "0.1",
+        ];
+        let report = simulate_with(&args, 11).unwrap();
+        assert!(report.checked >= report.ok + report.failed, "{report:?}");
+    }
+
+    #[test]
+    fn runs_replay_from_their_seed() {
+        let args = ["--nodes", "3", "--ops", "60", "--drop-rate", "0.1"];
+        let first = simulate_with(&args, 7).unwrap();
+        let second = simulate_with(&args, 7).unwrap();
+        assert_eq!(
+            (first.ok, first.failed, first.unknown),
+            (second.ok, second.failed, second.unknown)
+        );
+    }
+}
@@ -0,0 +1,31 @@
+use std::{fs::File, io::BufWriter, path::Path};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{ballot::BallotNumber, key::Key, kv_store::KeyValueStore};
+
+/// A node's state machine and the ballot each key's instance was accepted at,
+/// as written to disk by `write_snapshot` and loaded back with `--restore`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    pub state_machine: KeyValueStore<Key, Value>,
+    pub ballot_numbers: Vec<(Key, BallotNumber)>, // by key
+}
+
+impl Snapshot {
+    pub fn read_from(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let file = File::open(path).with_context(|| format!("couldn't open {path:?}"))?;
+        serde_json::from_reader(std::io::BufReader::new(file))
+            .with_context(|| format!("{path:?} isn't a valid snapshot"))
+    }
+
+    pub fn write_to(&self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        let path = path.as_ref();
+        let file = File::create(path).with_context(|| format!("couldn't create {path:?}"))?;
+        serde_json::to_writer(BufWriter::new(file), self)
+            .with_context(|| format!("couldn't write the snapshot to {path:?}"))
+    }
+}
@@ -0,0 +1,277 @@
+//! What client ops do. The consensus layer leaves what Read/Write/Cas/Delete/CasDelete
+//! and the versioned ops do to a `StateMachine`: the KV store, or a single register
+//! for the classic single-register CASPaxos setup, see `--workload`. It still stores
+//! and replicates the state of each instance as a KV store, so the register lives
+//! in it as a snapshot, under REGISTER_KEY, that each op is applied to.
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{key::Key, kv_store::KeyValueStore, message::Body, message::ErrorCode, txn};
+
+pub trait StateMachine: Serialize + DeserializeOwned {
+    /// Applies the client op `op`, at `now` on its instance's clock (see `expiry`),
+    /// returning the reply to it, or the error it fails with.
+    fn apply(&mut self, op: &Body, in_reply_to: usize, now: u64) -> Result<Body, ErrorCode>;
+
+    /// The state as a value, to be kept in the state of a CASPaxos instance.
+    fn snapshot(&self) -> Value {
+        serde_json::to_value(self).expect("state machines should serialize to JSON")
+    }
+
+    fn restore(snapshot: &Value) -> anyhow::Result<Self> {
+        Ok(Self::deserialize(snapshot)?)
+    }
+}
+
+// the store methods only fail with the error codes clients get.
+fn error_code(error: anyhow::Error) -> ErrorCode {
+    match error.downcast::<ErrorCode>() {
+        Ok(code) => code,
+        Err(error) => panic!("unexpected error while applying a client op: {error}"),
+    }
+}
+
+impl StateMachine for KeyValueStore<Key, Value> {
+    fn apply(&mut self, op: &Body, in_reply_to: usize, now: u64) -> Result<Body, ErrorCode> {
+        if op.key().is_some_and(|key| txn::is_locked(self, key)) {
+            return Err(ErrorCode::TxnConflict);
+        }
+        match op {
+            Body::Read { key } => match self.read(key) {
+                Some(value) => Ok(Body::ReadOk {
+                    in_reply_to,
+                    value: value.clone(),
+                    ballot_number: None,
+                }),
+                None => Err(ErrorCode::KeyDoesNotExist),
+            },
+            Body::Write {
+                key,
+                value,
+                create_if_not_exists: false,
+                expiry_ms,
+            } => {
+                let expires_at = expiry_ms.map(|expiry_ms| now + expiry_ms);
+                self.write_expiring(key.clone(), value.clone(), expires_at);
+                Ok(Body::WriteOk { in_reply_to })
+            }
+            Body::Write {
+                key,
+                value,
+                expiry_ms,
+                ..
+            } => {
+                let expires_at = expiry_ms.map(|expiry_ms| now + expiry_ms);
+                self.write_if_absent(key.clone(), value.clone(), expires_at)
+                    .map_err(error_code)?;
+                Ok(Body::WriteOk { in_reply_to })
+            }
+            Body::Cas {
+                key,
+                to,
+                create_if_not_exists: true,
+                ..
+            } if self.read(key).is_none() => {
+                self.write_if_absent(key.clone(), to.clone(), None)
+                    .map_err(error_code)?;
+                Ok(Body::CasOk { in_reply_to })
+            }
+            Body::Cas { key, from, to, .. } => {
+                self.cas(key.clone(), from.clone(), to.clone())
+                    .map_err(error_code)?;
+                Ok(Body::CasOk { in_reply_to })
+            }
+            Body::Delete { key } => {
+                self.delete(key, now).map_err(error_code)?;
+                Ok(Body::DeleteOk { in_reply_to })
+            }
+            Body::CasDelete { key, from } => {
+                self.cas_delete(key, from.clone(), now)
+                    .map_err(error_code)?;
+                Ok(Body::CasDeleteOk { in_reply_to })
+            }
+            Body::ReadVersion { key } => {
+                let (value, version) = self.read_versioned(key);
+                Ok(Body::ReadVersionOk {
+                    in_reply_to,
+                    value: value.cloned(),
+                    version,
+                })
+            }
+            Body::CasVersion {
+                key,
+                version,
+                value,
+            } => {
+                self.cas_by_version(key.clone(), *version, value.clone())
+                    .map_err(error_code)?;
+                Ok(Body::CasVersionOk { in_reply_to })
+            }
+            _ => unreachable!("only client ops are applied to the state machine"),
+        }
+    }
+}
+
+/// The key the register is kept under. In register mode every client op is made
+/// an op on it as it comes in, whatever key it names. Ops that name no key, as
+/// register clients send them, name it too.
+pub const REGISTER_KEY: Key = Key::Int(0);
+
+pub fn register_key() -> Key {
+    REGISTER_KEY
+}
+
+/// A single register, with the same ops as a key of the KV store.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Register {
+    value: Option<Value>,
+    expires_at: Option<u64>,
+}
+
+impl StateMachine for Register {
+    fn apply(&mut self, op: &Body, in_reply_to: usize, now: u64) -> Result<Body, ErrorCode> {
+        if self.expires_at.is_some_and(|expires_at| expires_at <= now) {
+            *self = Register::default();
+        }
+        let current = self.value.as_ref();
+        match op {
+            Body::Read { .. } => Ok(Body::ReadOk {
+                in_reply_to,
+                value: current.ok_or(ErrorCode::KeyDoesNotExist)?.clone(),
+                ballot_number: None,
+            }),
+            Body::Write {
+                create_if_not_exists: true,
+                ..
+            } if current.is_some() => Err(ErrorCode::KeyAlreadyExists),
+            Body::Write {
+                value, expiry_ms, ..
+            } => {
+                *self = Register {
+                    value: Some(value.clone()),
+                    expires_at: expiry_ms.map(|expiry_ms| now + expiry_ms),
+                };
+                Ok(Body::WriteOk { in_reply_to })
+            }
+            Body::Cas {
+                from,
+                to,
+                create_if_not_exists,
+                ..
+            } => match current {
+                Some(current) if current != from => Err(ErrorCode::PreconditionFailed),
+                None if !create_if_not_exists => Err(ErrorCode::KeyDoesNotExist),
+                _ => {
+                    *self = Register {
+                        value: Some(to.clone()),
+                        expires_at: None,
+                    };
+                    Ok(Body::CasOk { in_reply_to })
+                }
+            },
+            Body::Delete { .. } if current.is_none() => Err(ErrorCode::KeyDoesNotExist),
+            Body::Delete { .. } => {
+                *self = Register::default();
+                Ok(Body::DeleteOk { in_reply_to })
+            }
+            Body::CasDelete { from, .. } => match current {
+                Some(current) if current != from => Err(ErrorCode::PreconditionFailed),
+                None => Err(ErrorCode::KeyDoesNotExist),
+                Some(_) => {
+                    *self = Register::default();
+                    Ok(Body::CasDeleteOk { in_reply_to })
+                }
+            },
+            // the register keeps no versions.
+            Body::ReadVersion { .. } | Body::CasVersion { .. } => Err(ErrorCode::NotSupported),
+            _ => unreachable!("only client ops are applied to the state machine"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::txn::{TxnOp, TxnOpKind};
+
+    fn write_if_absent(value: u64) -> Body {
+        Body::Write {
+            key: REGISTER_KEY,
+            value: value.into(),
+            create_if_not_exists: true,
+            expiry_ms: None,
+        }
+    }
+
+    fn cas_delete(from: u64) -> Body {
+        Body::CasDelete {
+            key: REGISTER_KEY,
+            from: from.into(),
+        }
+    }
+
+    // both state machines fail the same ops with the same codes.
+    fn check_error_codes(mut state: impl StateMachine) {
+        assert_eq!(
+            state.apply(&write_if_absent(1), 1, 0),
+            Ok(Body::WriteOk { in_reply_to: 1 })
+        );
+        assert_eq!(
+            state.apply(&write_if_absent(2), 2, 0),
+            Err(ErrorCode::KeyAlreadyExists)
+        );
+        assert_eq!(
+            state.apply(&cas_delete(2), 3, 0),
+            Err(ErrorCode::PreconditionFailed)
+        );
+        assert_eq!(
+            state.apply(&cas_delete(1), 4, 0),
+            Ok(Body::CasDeleteOk { in_reply_to: 4 })
+        );
+        assert_eq!(
+            state.apply(&cas_delete(1), 5, 0),
+            Err(ErrorCode::KeyDoesNotExist)
+        );
+    }
+
+    #[test]
+    fn kv_store_error_codes() {
+        check_error_codes(KeyValueStore::<Key, Value>::new_with_inner(
+            Default::default(),
+        ));
+    }
+
+    #[test]
+    fn register_error_codes() {
+        check_error_codes(Register::default());
+    }
+
+    fn write(key: usize, value: u64) -> Body {
+        Body::Write {
+            key: Key::Int(key),
+            value: value.into(),
+            create_if_not_exists: false,
+            expiry_ms: None,
+        }
+    }
+
+    #[test]
+    fn kv_store_fails_ops_on_keys_locked_by_a_txn() {
+        let mut state = KeyValueStore::<Key, Value>::new_with_inner(Default::default());
+        assert!(state.apply(&write(1, 1), 1, 0).is_ok());
+        let txn = [TxnOp(TxnOpKind::Write, Key::Int(1), Some(2.into()))];
+        txn::prepare(&mut state, 7, &txn).unwrap();
+
+        // the write would be lost to the txn's staged one, and the read would miss it.
+        assert_eq!(state.apply(&write(1, 3), 2, 0), Err(ErrorCode::TxnConflict));
+        let read = Body::Read { key: Key::Int(1) };
+        assert_eq!(state.apply(&read, 3, 0), Err(ErrorCode::TxnConflict));
+        assert!(state.apply(&write(2, 3), 4, 0).is_ok());
+
+        txn::finish(&mut state, 7, true, &[Key::Int(1)]);
+        assert_eq!(state.read(&Key::Int(1)), Some(&Value::from(2)));
+        assert!(state.apply(&write(1, 3), 5, 0).is_ok());
+        assert_eq!(state.read(&Key::Int(1)), Some(&Value::from(3)));
+    }
+}
@@ -0,0 +1,189 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    ballot::BallotNumber,
+    profiling::{self, StageTiming},
+};
+
+/// Counters describing how the node has been doing, reported in reply to `stats`.
+#[derive(Debug)]
+pub struct Stats {
+    enabled: bool, // when false, nothing gets recorded
+    shed_client_ops: AtomicU64,
+    late_promises: AtomicU64,
+    quorum_reads: AtomicU64,
+    fast_rounds: AtomicU64,
+    promise_only_reads: AtomicU64,
+    lease_reads: AtomicU64,
+    client_waits: Mutex<HashMap<String, ClientWait>>, // keyed by client
+}
+
+impl Stats {
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            shed_client_ops: AtomicU64::new(0),
+            late_promises: AtomicU64::new(0),
+            quorum_reads: AtomicU64::new(0),
+            fast_rounds: AtomicU64::new(0),
+            promise_only_reads: AtomicU64::new(0),
+            lease_reads: AtomicU64::new(0),
+            client_waits: Mutex::default(),
+        }
+    }
+
+    pub fn record_shed_client_op(&self) {
+        if !self.enabled {
+            return;
+        }
+        self.shed_client_ops.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_late_promise(&self) {
+        if !self.enabled {
+            return;
+        }
+        self.late_promises.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a read served by a CASPaxos round.
+    pub fn record_quorum_read(&self) {
+        if !self.enabled {
+            return;
+        }
+        self.quorum_reads.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a round that skipped its Propose phase, on the lease of a won ballot.
+    pub fn record_fast_round(&self) {
+        if !self.enabled {
+            return;
+        }
+        self.fast_rounds.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a read answered from its round's promises, without an Accept phase.
+    pub fn record_promise_only_read(&self) {
+        if !self.enabled {
+            return;
+        }
+        self.promise_only_reads.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a read answered from the local state, under the key's lease.
+    pub fn record_lease_read(&self) {
+        if !self.enabled {
+            return;
+        }
+        self.lease_reads.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records how long a client op was queued before it got its turn.
+    pub fn record_client_wait(&self, client: &str, waited: Duration) {
+        if !self.enabled {
+            return;
+        }
+        let waited_nanos = waited.as_nanos() as u64;
+        let mut client_waits = self.client_waits.lock().unwrap();
+        let client_wait = match client_waits.get_mut(client) {
+            Some(client_wait) => client_wait,
+            None => client_waits.entry(client.to_string()).or_default(),
+        };
+        client_wait.count += 1;
+        client_wait.total_nanos += waited_nanos;
+        client_wait.max_nanos = client_wait.max_nanos.max(waited_nanos);
+    }
+
+    pub fn snapshot(
+        &self,
+        in_flight_proposals: usize,
+        memory: MemoryUsage,
+        state_digest: u64,
+    ) -> StatsSnapshot {
+        StatsSnapshot {
+            shed_client_ops: self.shed_client_ops.load(Ordering::Relaxed),
+            late_promises: self.late_promises.load(Ordering::Relaxed),
+            quorum_reads: self.quorum_reads.load(Ordering::Relaxed),
+            fast_rounds: self.fast_rounds.load(Ordering::Relaxed),
+            promise_only_reads: self.promise_only_reads.load(Ordering::Relaxed),
+            lease_reads: self.lease_reads.load(Ordering::Relaxed),
+            in_flight_proposals,
+            memory,
+            state_digest,
+            client_waits: self
+                .client_waits
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(client, client_wait)| (client.clone(), *client_wait))
+                .collect(),
+            profile: profiling::report(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct StatsSnapshot {
+    pub shed_client_ops: u64,
+    // promises that arrived after their round had already broadcast Accept
+    pub late_promises: u64,
+    // reads served through a round rather than under a lease
+    pub quorum_reads: u64,
+    // rounds that went straight to their Accept phase
+    pub fast_rounds: u64,
+    // reads whose round ended with its Propose phase, see `PromisesInbox::highest_is_chosen`
+    pub promise_only_reads: u64,
+    // reads answered locally, with `--reads=lease`
+    pub lease_reads: u64,
+    pub in_flight_proposals: usize,
+    pub memory: MemoryUsage,
+    pub state_digest: u64,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub client_waits: BTreeMap<String, ClientWait>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub profile: Vec<StageTiming>,
+}
+
+/// Time a client's ops spent queued behind other ops on the same key lane.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub struct ClientWait {
+    pub count: u64,
+    pub total_nanos: u64,
+    pub max_nanos: u64,
+}
+
+/// Rough estimate of the memory held by the node's main data structures, in bytes.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub struct MemoryUsage {
+    pub state_machine: usize,
+    pub in_flight_proposals: usize,
+    pub queues: usize,
+}
+
+impl MemoryUsage {
+    pub fn total(&self) -> usize {
+        self.state_machine + self.in_flight_proposals + self.queues
+    }
+}
+
+/// A cheap overview of the node's state, reported in reply to `health`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Health {
+    pub role: String,
+    pub highest_known_ballot_number: BallotNumber,
+    // milliseconds since each peer was last heard from, None if it never was.
+    pub peers_last_heard_ms: BTreeMap<String, Option<u64>>,
+    pub inbound_queue_depth: usize,
+    pub outbound_queue_depth: usize,
+    pub queued_client_ops: usize,
+    pub store_size: usize,
+}
@@ -0,0 +1,83 @@
+//! How msgs get to and from a node. Maelstrom talks to nodes over STDIN/STDOUT, one
+//! JSON msg per line, which is what `Stdio` does; `Node` only sees the `Transport`.
+
+use std::{
+    io::{BufRead, Write},
+    sync::Mutex,
+};
+
+use futures::future::BoxFuture;
+
+use crate::{
+    message::Message,
+    profiling::{self, Stage},
+};
+
+// Capacity the STDIN line buffer keeps between msgs.
+const INPUT_BUFFER_CAPACITY: usize = 64 * 1024;
+
+pub enum Received {
+    Msg(Message),
+    // what came in, and why it isn't a msg, for the node to reject it if it can.
+    Malformed(Vec<u8>, serde_json::Error),
+}
+
+pub trait Transport: Send + Sync {
+    /// The next inbound msg, or None once no more can come.
+    fn recv(&self) -> BoxFuture<'_, Option<Received>>;
+
+    fn send<'a>(&'a self, msg: &'a Message) -> BoxFuture<'a, ()>;
+}
+
+/// JSON lines over STDIN/STDOUT.
+pub struct Stdio {
+    // one line buffer is reused for every msg, and parsed in place as bytes
+    // rather than being copied into a fresh String first.
+    input: Mutex<Vec<u8>>,
+}
+
+impl Default for Stdio {
+    fn default() -> Self {
+        Self {
+            input: Mutex::new(Vec::with_capacity(INPUT_BUFFER_CAPACITY)),
+        }
+    }
+}
+
+impl Transport for Stdio {
+    fn recv(&self) -> BoxFuture<'_, Option<Received>> {
+        Box::pin(async move {
+            let mut input = self.input.lock().unwrap();
+            // Keeps the buffer's allocation for the next msg, unless an unusually
+            // large msg grew it, in which case it gives the extra memory back.
+            input.clear();
+            input.shrink_to(INPUT_BUFFER_CAPACITY);
+            match std::io::stdin().lock().read_until(b'\n', &mut input) {
+                Ok(0) => return None, // EOF
+                Ok(_) => (),
+                Err(e) => {
+                    println!("readline error: {e}");
+                    return None;
+                }
+            }
+
+            let parsed = profiling::time(Stage::Parse, || serde_json::from_slice(&input));
+            Some(match parsed {
+                Ok(msg) => Received::Msg(msg),
+                Err(e) => Received::Malformed(input.clone(), e),
+            })
+        })
+    }
+
+    fn send<'a>(&'a self, msg: &'a Message) -> BoxFuture<'a, ()> {
+        Box::pin(async move {
+            // serialize straight into STDOUT's buffered writer, so large messages
+            // (e.g. full state machines in Promise/Accept) never exist as one String.
+            let mut stdout = std::io::stdout().lock();
+            profiling::time(Stage::Serialize, || serde_json::to_writer(&mut stdout, msg))
+                .expect("msg being sent to STDOUT should be serializable to JSON");
+            writeln!(stdout).expect("should be able to write to STDOUT");
+            stdout.flush().expect("should be able to flush STDOUT");
+        })
+    }
+}
@@ -0,0 +1,174 @@
+//! Two-phase commit for txns spanning several consensus groups. Every step is a
+//! CASPaxos op on the keys it concerns, applied by the functions below:
+//!
+//! 1. `prepare` locks a txn's keys, reads them and stages its writes.
+//! 2. `decide` records in the txn's decision register whether it commits. The first
+//!    decision sticks, so a coordinator and a recovering node can't disagree on it.
+//! 3. `finish` applies (or drops) the staged writes and releases the locks.
+//! 4. With compaction on, `forget` drops the decision once every group finished the
+//!    txn. No lock of the txn is left by then for recovery to decide it again.
+//!
+//! Locks, staged writes and decisions are kept in the replicated store itself, under
+//! tagged keys, which clients can't write to. They share the CASPaxos instance of the
+//! key they're derived from, see `instance_keys`. Client ops on a locked key fail
+//! until the lock is released, see `is_locked`, so that none of them lands between
+//! a txn's prepare and its finish.
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    expiry,
+    key::{Key, Tag},
+    kv_store::KeyValueStore,
+    message::ErrorCode,
+};
+
+type InstanceState = KeyValueStore<Key, Value>;
+
+// a lock holds the id of the txn that locked the key, a staged key the value a txn
+// writes once committed, and a decision COMMITTED or ABORTED.
+const COMMITTED: u64 = 1;
+const ABORTED: u64 = 0;
+
+fn lock_key(key: &Key) -> Key {
+    Key::Tagged(Tag::Lock, Box::new(key.clone()))
+}
+
+fn staged_key(key: &Key) -> Key {
+    Key::Tagged(Tag::Staged, Box::new(key.clone()))
+}
+
+/// The key of the register holding `txn_id`'s decision.
+pub fn decision_key(txn_id: usize) -> Key {
+    Key::Tagged(Tag::Decision, Box::new(Key::Int(txn_id)))
+}
+
+/// The key a tagged key was derived from, or `key` itself for client keys.
+pub fn base_key(key: &Key) -> &Key {
+    match key {
+        Key::Tagged(_, key) => key,
+        key => key,
+    }
+}
+
+/// Every key stored in the CASPaxos instance of `base_key`: the key itself, its
+/// lock and staged write, the decision of the txn with the same id, and the clock
+/// its expiry goes by.
+pub fn instance_keys(base_key: &Key) -> [Key; 5] {
+    [
+        base_key.clone(),
+        lock_key(base_key),
+        staged_key(base_key),
+        Key::Tagged(Tag::Decision, Box::new(base_key.clone())),
+        expiry::clock_key(base_key),
+    ]
+}
+
+/// One micro-op of a txn, `["r", key, value]` or `["w", key, value]` on the wire.
+/// Reads come in with no value and go out with the value read, if any.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TxnOp(pub TxnOpKind, pub Key, pub Option<Value>);
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum TxnOpKind {
+    #[serde(rename = "r")]
+    Read,
+    #[serde(rename = "w")]
+    Write,
+}
+
+impl TxnOp {
+    pub fn key(&self) -> &Key {
+        &self.1
+    }
+}
+
+/// Whether a txn holds `key`, which client ops then fail on: a write would be lost
+/// to the txn's staged one, and a read could see some of a committed txn's writes
+/// and not others, as groups finish it one at a time.
+pub fn is_locked(state_machine: &InstanceState, key: &Key) -> bool {
+    state_machine.read(&lock_key(key)).is_some()
+}
+
+/// Locks every key of `txn`, then runs its reads and stages its writes. Fails
+/// without changing anything if another txn holds one of the keys.
+pub fn prepare(
+    state_machine: &mut InstanceState,
+    txn_id: usize,
+    txn: &[TxnOp],
+) -> Result<Vec<TxnOp>, ErrorCode> {
+    let is_locked_by_another_txn = txn.iter().any(|op| {
+        state_machine
+            .read(&lock_key(op.key()))
+            .is_some_and(|holder| holder.as_u64() != Some(txn_id as u64))
+    });
+    if is_locked_by_another_txn {
+        return Err(ErrorCode::TxnConflict);
+    }
+
+    let mut completed = Vec::with_capacity(txn.len());
+    for TxnOp(kind, key, value) in txn {
+        state_machine.write(lock_key(key), Value::from(txn_id));
+        match kind {
+            TxnOpKind::Read => {
+                // the txn reads its own writes, which are only staged so far.
+                let read = state_machine
+                    .read(&staged_key(key))
+                    .or_else(|| state_machine.read(key))
+                    .cloned();
+                completed.push(TxnOp(TxnOpKind::Read, key.clone(), read));
+            }
+            TxnOpKind::Write => {
+                let value = value.clone().ok_or(ErrorCode::MalformedRequest)?;
+                state_machine.write(staged_key(key), value.clone());
+                completed.push(TxnOp(TxnOpKind::Write, key.clone(), Some(value)));
+            }
+        }
+    }
+
+    Ok(completed)
+}
+
+/// Records whether `txn_id` commits, unless it was decided already.
+/// Returns the decision that stands.
+pub fn decide(state_machine: &mut InstanceState, txn_id: usize, commit: bool) -> bool {
+    match state_machine.read(&decision_key(txn_id)) {
+        Some(decision) => decision.as_u64() == Some(COMMITTED),
+        None => {
+            let decision = if commit { COMMITTED } else { ABORTED };
+            state_machine.write(decision_key(txn_id), Value::from(decision));
+            commit
+        }
+    }
+}
+
+/// Drops the decision of `txn_id`, which every group finished.
+pub fn forget(state_machine: &mut InstanceState, txn_id: usize) {
+    state_machine.remove(&decision_key(txn_id));
+}
+
+/// Releases the locks `txn_id` holds on `keys`, writing its staged values if it committed.
+pub fn finish(state_machine: &mut InstanceState, txn_id: usize, commit: bool, keys: &[Key]) {
+    for key in keys {
+        if state_machine.read(&lock_key(key)) != Some(&Value::from(txn_id)) {
+            continue; // finished already
+        }
+        state_machine.remove(&lock_key(key));
+        let staged = state_machine.remove(&staged_key(key));
+        if let (true, Some(value)) = (commit, staged) {
+            state_machine.write(key.clone(), value);
+        }
+    }
+}
+
+/// Every (txn id, key) pair of the locks held in `state_machine`.
+pub fn locks(state_machine: &InstanceState) -> Vec<(usize, Key)> {
+    state_machine
+        .iter()
+        .filter_map(|(key, txn_id)| match key {
+            Key::Tagged(Tag::Lock, key) => Some((txn_id.as_u64()? as usize, (**key).clone())),
+            _ => None,
+        })
+        .collect()
+}